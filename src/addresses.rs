@@ -0,0 +1,355 @@
+//! Address normalization.
+//!
+//! [`normalize()`] maps the different spellings of a mailbox to a single form, so that
+//! `John.Doe+newsletter@GMail.com` and `johndoe@gmail.com` are treated as the same address:
+//!
+//! - the address is lowercased,
+//! - a `+tag` suffix of the local part is removed,
+//! - dots in the local part are removed for domains which ignore them (`gmail.com` and
+//!   `googlemail.com` by default, see [`Normalizer`]),
+//! - internationalized domain names are converted to their ASCII form (`xn--...`),
+//! - a trailing dot of the domain is removed.
+//!
+//! Address lists are compared with normalized addresses by [`contains()`],
+//! [`recipient::static_list()`](crate::recipient::static_list) and the lookup of per-user
+//! Sieve scripts.
+//!
+//! [`lookalike()`] finds addresses which differ from a protected address by a typo or two,
+//! as registered by attackers to intercept or hijack conversations with local users.
+//!
+//! [`keyed_hash()`] pseudonymizes addresses for persistent storage.
+//!
+//! # Example
+//!
+//! ```ignore
+//! if addresses::contains(&ctx.blocklist, mail_info.get_from_address()) {
+//!     return mail_info.reject("sender in blocklist");
+//! }
+//! ```
+
+use hmac::{Hmac, Mac as _};
+use sha2::Sha256;
+use std::sync::OnceLock;
+
+/// Normalization rules, configurable per domain.
+///
+/// # Example
+///
+/// ```ignore
+/// let normalizer = Normalizer::new().dot_insensitive_domain("example.com");
+/// assert_eq!(normalizer.normalize("J.Doe+x@example.com"), "jdoe@example.com");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Normalizer {
+    dot_insensitive_domains: Vec<String>,
+}
+
+impl Default for Normalizer {
+    fn default() -> Self {
+        Normalizer {
+            dot_insensitive_domains: vec!["gmail.com".into(), "googlemail.com".into()],
+        }
+    }
+}
+
+impl Normalizer {
+    /// Creates a normalizer with the default rules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Treats dots in the local part of addresses in `domain` as insignificant.
+    pub fn dot_insensitive_domain(mut self, domain: &str) -> Self {
+        self.dot_insensitive_domains.push(normalize_domain(domain));
+        self
+    }
+    /// Returns the normalized form of `address`.
+    ///
+    /// Strings without `@` are only lowercased. A leading `@domain` (as used for domain
+    /// entries in lists) keeps its empty local part.
+    pub fn normalize(&self, address: &str) -> String {
+        let Some((local, domain)) = address.rsplit_once('@') else {
+            return address.to_lowercase();
+        };
+        let mut local = local.to_lowercase();
+        if let Some(plus) = local.find('+')
+            && plus > 0
+        {
+            local.truncate(plus);
+        }
+        let domain = normalize_domain(domain);
+        if self.dot_insensitive_domains.contains(&domain) {
+            local.retain(|c| c != '.');
+        }
+        format!("{local}@{domain}")
+    }
+}
+
+// Maximum length of a DNS label in octets (RFC 1035).
+const LABEL_MAX: usize = 63;
+
+// Labels which can not be encoded, e.g. because the result would be too long for DNS, are
+// kept as they are.
+fn normalize_domain(domain: &str) -> String {
+    let domain = domain.strip_suffix('.').unwrap_or(domain).to_lowercase();
+    if domain.is_ascii() {
+        return domain;
+    }
+    domain
+        .split('.')
+        .map(|label| {
+            // the encoding has at least one character per code point, so longer labels can
+            // not fit; checking this first also bounds the quadratic encoding time
+            if label.is_ascii() || label.chars().count() > LABEL_MAX - 4 {
+                return label.to_string();
+            }
+            match punycode(label) {
+                Some(encoded) if encoded.len() <= LABEL_MAX - 4 => format!("xn--{encoded}"),
+                _ => label.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+// Punycode encoding of a single label (RFC 3492). Returns `None` on integer overflow.
+fn punycode(label: &str) -> Option<String> {
+    const BASE: u32 = 36;
+    const TMIN: u32 = 1;
+    const TMAX: u32 = 26;
+    fn digit(d: u32) -> char {
+        if d < 26 {
+            (b'a' + d as u8) as char
+        } else {
+            (b'0' + (d - 26) as u8) as char
+        }
+    }
+    fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+        let mut delta = if first { delta / 700 } else { delta / 2 };
+        delta += delta / points;
+        let mut k = 0;
+        while delta > ((BASE - TMIN) * TMAX) / 2 {
+            delta /= BASE - TMIN;
+            k += BASE;
+        }
+        k + (BASE - TMIN + 1) * delta / (delta + 38)
+    }
+
+    let input: Vec<u32> = label.chars().map(u32::from).collect();
+    let mut output: String = label.chars().filter(char::is_ascii).collect();
+    let basic = output.len() as u32;
+    if basic > 0 {
+        output.push('-');
+    }
+    let (mut n, mut delta, mut bias, mut h) = (128u32, 0u32, 72u32, basic);
+    while (h as usize) < input.len() {
+        let m = input.iter().copied().filter(|&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(h + 1)?)?;
+        n = m;
+        for &c in &input {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            } else if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = k.saturating_sub(bias).clamp(TMIN, TMAX);
+                    if q < t {
+                        break;
+                    }
+                    output.push(digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(digit(q));
+                bias = adapt(delta, h + 1, h == basic);
+                delta = 0;
+                h += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        n = n.checked_add(1)?;
+    }
+    Some(output)
+}
+
+fn default_normalizer() -> &'static Normalizer {
+    static NORMALIZER: OnceLock<Normalizer> = OnceLock::new();
+    NORMALIZER.get_or_init(Normalizer::default)
+}
+
+/// Returns the normalized form of `address` with the default rules, see [`Normalizer`].
+pub fn normalize(address: &str) -> String {
+    default_normalizer().normalize(address)
+}
+
+/// Checks if the normalized `address` is in `list`, which is normalized for the comparison.
+pub fn contains(list: &[String], address: &str) -> bool {
+    let address = normalize(address);
+    list.iter().any(|entry| normalize(entry) == address)
+}
+
+/// Returns a keyed hash of the normalized `address` as lowercase hex.
+///
+/// The hash is an HMAC-SHA256 with a site `key`, e.g. from
+/// [`secrets::resolve()`](crate::secrets::resolve). Applications which keep per-address
+/// data on disk (reputation, correspondents, audit logs) can store the hash instead of the
+/// address: lookups work as before, but the raw addresses can not be recovered or checked
+/// against a list of known addresses without the key.
+pub fn keyed_hash(key: &[u8], address: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(normalize(address).as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Returns the entry of `protected` which `address` resembles without being equal to it.
+///
+/// Both sides are normalized. An address resembles a protected address if the edit
+/// distance (insertions, deletions, substitutions and transpositions of adjacent
+/// characters) is 1, or 2 for addresses of at least 12 characters. `@domain` entries are
+/// ignored. An address which is itself protected resembles nothing.
+pub fn lookalike<'a>(protected: &'a [String], address: &str) -> Option<&'a String> {
+    let normalized = normalize(address);
+    if protected.iter().any(|entry| normalize(entry) == normalized) {
+        return None;
+    }
+    let address: Vec<char> = normalized.chars().collect();
+    protected.iter().find(|entry| {
+        if entry.starts_with('@') {
+            return false;
+        }
+        let entry: Vec<char> = normalize(entry).chars().collect();
+        let max = if entry.len() >= 12 { 2 } else { 1 };
+        (1..=max).contains(&edit_distance(&entry, &address))
+    })
+}
+
+// Optimal string alignment distance.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    if a.len().abs_diff(b.len()) > 2 {
+        return a.len().abs_diff(b.len());
+    }
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>(); 3];
+    for i in 1..=a.len() {
+        let (prev2, prev, cur) = ((i + 1) % 3, (i + 2) % 3, i % 3);
+        rows[cur][0] = i;
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut d = (rows[prev][j] + 1)
+                .min(rows[cur][j - 1] + 1)
+                .min(rows[prev][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = d.min(rows[prev2][j - 2] + 1);
+            }
+            rows[cur][j] = d;
+        }
+    }
+    rows[a.len() % 3][b.len()]
+}
+
+#[test]
+fn test_normalize() {
+    assert_eq!(normalize("John.Doe+news@GMail.com"), "johndoe@gmail.com");
+    assert_eq!(
+        normalize("John.Doe+news@example.com"),
+        "john.doe@example.com"
+    );
+    assert_eq!(normalize("+tag@example.com"), "+tag@example.com");
+    assert_eq!(normalize("user@Example.COM."), "user@example.com");
+    assert_eq!(
+        normalize("info@Bücher.example"),
+        "info@xn--bcher-kva.example"
+    );
+    assert_eq!(normalize("info@münchen.de"), "info@xn--mnchen-3ya.de");
+    assert_eq!(normalize("@Example.org"), "@example.org");
+    assert_eq!(normalize("MAILER-DAEMON"), "mailer-daemon");
+    let normalizer = Normalizer::new().dot_insensitive_domain("Example.com");
+    assert_eq!(
+        normalizer.normalize("J.Doe+x@example.com"),
+        "jdoe@example.com"
+    );
+    assert!(contains(
+        &["johndoe@gmail.com".to_string()],
+        "john.doe+x@gmail.com"
+    ));
+    assert!(!contains(
+        &["john@example.com".to_string()],
+        "jo.hn@example.com"
+    ));
+}
+
+#[test]
+fn test_punycode() {
+    // sample strings of RFC 3492, section 7.1
+    for (label, encoded) in [
+        ("ليهمابتكلموشعربي؟", "egbpdaj6bu4bxfgehfvwxn"),
+        ("他们为什么不说中文", "ihqwcrb4cv8a8dqg056pqjye"),
+        ("Pročprostěnemluvíčesky", "Proprostnemluvesky-uyb24dma41a"),
+        ("למההםפשוטלאמדבריםעברית", "4dbcagdahymbxekheh6e0a7fei0b"),
+        (
+            "なぜみんな日本語を話してくれないのか",
+            "n8jok5ay5dzabd5bym9f0cm5685rrjetr6pdxa",
+        ),
+        ("3年B組金八先生", "3B-ww4c5e180e575a65lsy2b"),
+        (
+            "安室奈美恵-with-SUPER-MONKEYS",
+            "-with-SUPER-MONKEYS-pc58ag80a8qai00g7n9n",
+        ),
+        ("MajiでKoiする5秒前", "MajiKoi5-783gue6qz075azm5e"),
+        ("そのスピードで", "d9juau41awczczp"),
+    ] {
+        assert_eq!(punycode(label).as_deref(), Some(encoded), "{label}");
+    }
+    // (m - n) * (h + 1) exceeds u32
+    let label = format!("{}\u{10ffff}", "a".repeat(5000));
+    assert_eq!(punycode(&label), None);
+    // too long for a DNS label
+    let long = format!("info@{}.example", "ü".repeat(60));
+    assert_eq!(normalize(&long), long);
+    let label: String = (0..30)
+        .map(|i| char::from_u32(0x4e00 + i * 97).unwrap())
+        .collect();
+    assert!(punycode(&label).unwrap().len() > LABEL_MAX - 4);
+    let long = format!("info@{label}.example");
+    assert_eq!(normalize(&long), long);
+}
+
+#[test]
+fn test_keyed_hash() {
+    let hash = keyed_hash(b"site key", "John.Doe+x@Example.com");
+    assert_eq!(hash.len(), 64);
+    assert_eq!(hash, keyed_hash(b"site key", "john.doe@example.com"));
+    assert_ne!(hash, keyed_hash(b"other key", "john.doe@example.com"));
+    assert_ne!(hash, keyed_hash(b"site key", "jane.doe@example.com"));
+}
+
+#[test]
+fn test_lookalike() {
+    let protected = [
+        "ceo@example.com".to_string(),
+        "accounting@example.com".to_string(),
+        "@example.org".to_string(),
+    ];
+    assert_eq!(lookalike(&protected, "ceo@example.com"), None);
+    assert_eq!(lookalike(&protected, "CEO+x@example.com"), None);
+    assert_eq!(
+        lookalike(&protected, "ceo@exmaple.com"),
+        Some(&protected[0])
+    );
+    assert_eq!(lookalike(&protected, "ceo@example.co"), Some(&protected[0]));
+    assert_eq!(lookalike(&protected, "bob@example.com"), None);
+    assert_eq!(
+        lookalike(&protected, "acounting@examp1e.com"),
+        Some(&protected[1])
+    );
+    assert_eq!(lookalike(&protected, "user@example.org"), None);
+    assert_eq!(lookalike(&protected, "someone@else.net"), None);
+    // protected addresses resembling each other
+    let protected = ["bob@example.com".to_string(), "rob@example.com".to_string()];
+    assert_eq!(lookalike(&protected, "rob@example.com"), None);
+    assert_eq!(lookalike(&protected, "Bob@example.com"), None);
+    assert_eq!(lookalike(&protected, "bob@example.co"), Some(&protected[0]));
+}
@@ -0,0 +1,172 @@
+//! Abuse Reporting Format (ARF, RFC 5965) feedback report parsing.
+//!
+//! Feedback loop reports sent by mailbox providers can be parsed with
+//! [`MailInfo::feedback_report()`](crate::MailInfo::feedback_report) to learn which
+//! sender or campaign users complained about.
+//!
+//! # Example
+//!
+//! ```ignore
+//! if mail_info.get_only_recipient() == "fbl@example.com" {
+//!     if let Some(report) = mail_info.feedback_report() {
+//!         mail_info.log(&format!(
+//!             "complaint ({}) about {} from {:?}",
+//!             report.feedback_type, report.original_mail_from, report.source_ip
+//!         ));
+//!     }
+//!     return mail_info.accept("feedback report");
+//! }
+//! ```
+
+use crate::MailInfo;
+use mail_parser::{HeaderName, Message, MessageParser, MimeHeaders as _};
+use std::net::IpAddr;
+
+/// Information extracted from an ARF feedback report.
+///
+/// Text fields are `""` when the corresponding field is missing in the report.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeedbackReport {
+    /// `Feedback-Type:` of the report, lowercased (e.g. `"abuse"`, `"fraud"`, `"virus"`).
+    pub feedback_type: String,
+    /// `User-Agent:` of the software which generated the report.
+    pub user_agent: String,
+    /// `Original-Mail-From:`, the envelope sender of the reported message.
+    pub original_mail_from: String,
+    /// `Original-Rcpt-To:`, the envelope recipients of the reported message.
+    pub original_rcpt_to: Vec<String>,
+    /// `Source-IP:`, the IP address the reported message was received from.
+    pub source_ip: Option<IpAddr>,
+    /// `Reported-Domain:` of the report.
+    pub reported_domain: String,
+    /// `Arrival-Date:` of the reported message.
+    pub arrival_date: String,
+    /// Address from the `From:` header of the reported message.
+    pub original_from: String,
+    /// `Subject:` of the reported message.
+    pub original_subject: String,
+    /// `Message-ID:` of the reported message.
+    pub original_message_id: String,
+    /// `Feedback-ID:` (campaign identifier) of the reported message.
+    pub original_feedback_id: String,
+}
+
+pub(crate) fn anglestrip(s: &str) -> &str {
+    s.strip_prefix('<')
+        .and_then(|s| s.strip_suffix('>'))
+        .unwrap_or(s)
+}
+
+// Parses the `name: value` fields of the message/feedback-report part, unfolding
+// continuation lines.
+pub(crate) fn parse_fields(text: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in text.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    fields
+}
+
+fn set_original(report: &mut FeedbackReport, msg: &Message) {
+    report.original_from = msg
+        .from()
+        .and_then(|v| v.first())
+        .and_then(|v| v.address())
+        .unwrap_or("")
+        .to_string();
+    report.original_subject = msg.subject().unwrap_or("").to_string();
+    report.original_message_id = msg.message_id().unwrap_or("").to_string();
+    report.original_feedback_id = msg
+        .header(HeaderName::Other("Feedback-ID".into()))
+        .and_then(|v| v.as_text())
+        .unwrap_or("")
+        .to_string();
+}
+
+pub(crate) fn feedback_report(mail_info: &MailInfo) -> Option<FeedbackReport> {
+    let msg = mail_info.get_message();
+    if !msg
+        .content_type()
+        .map(|c| c.ctype().eq_ignore_ascii_case("multipart") && c.subtype() == Some("report"))
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    let report_part = msg
+        .parts
+        .iter()
+        .find(|p| p.is_content_type("message", "feedback-report"))?;
+    let mut report = FeedbackReport::default();
+    for (name, value) in parse_fields(&String::from_utf8_lossy(report_part.contents())) {
+        match name.as_str() {
+            "feedback-type" => report.feedback_type = value.to_ascii_lowercase(),
+            "user-agent" => report.user_agent = value,
+            "original-mail-from" => report.original_mail_from = anglestrip(&value).to_string(),
+            "original-rcpt-to" => report.original_rcpt_to.push(anglestrip(&value).to_string()),
+            "source-ip" => report.source_ip = value.parse().ok(),
+            "reported-domain" => report.reported_domain = value,
+            "arrival-date" | "received-date" => report.arrival_date = value,
+            _ => (),
+        }
+    }
+    if let Some(original) = msg.parts.iter().find_map(|p| p.message()) {
+        set_original(&mut report, original);
+    } else if let Some(part) = msg
+        .parts
+        .iter()
+        .find(|p| p.is_content_type("text", "rfc822-headers"))
+        && let Some(original) = MessageParser::default().parse_headers(part.contents())
+    {
+        set_original(&mut report, &original);
+    }
+    Some(report)
+}
+
+#[test]
+fn test_feedback_report() {
+    use crate::MailInfoStorage;
+
+    let storage = MailInfoStorage {
+        mail_buffer: std::fs::read("tests/parse_005.eml").unwrap(),
+        ..Default::default()
+    };
+    let mail_info = MailInfo::new(
+        &storage,
+        MessageParser::default()
+            .parse(&storage.mail_buffer)
+            .unwrap(),
+    );
+    let report = mail_info.feedback_report().unwrap();
+    assert_eq!(report.feedback_type, "abuse");
+    assert_eq!(report.user_agent, "SomeGenerator/1.0");
+    assert_eq!(report.original_mail_from, "campaign@sender.example");
+    assert_eq!(report.original_rcpt_to, ["user@isp.example"]);
+    assert_eq!(report.source_ip, Some("192.0.2.1".parse().unwrap()));
+    assert_eq!(report.reported_domain, "sender.example");
+    assert_eq!(report.arrival_date, "Tue, 07 Oct 2025 08:14:55 +0200");
+    assert_eq!(report.original_from, "offers@sender.example");
+    assert_eq!(report.original_subject, "Weekly offers");
+    assert_eq!(
+        report.original_message_id,
+        "8787KJKJ3K4J3K4J3K4J3.mail@sender.example"
+    );
+
+    let storage = MailInfoStorage {
+        mail_buffer: std::fs::read("tests/parse_001.eml").unwrap(),
+        ..Default::default()
+    };
+    let mail_info = MailInfo::new(
+        &storage,
+        MessageParser::default()
+            .parse(&storage.mail_buffer)
+            .unwrap(),
+    );
+    assert_eq!(mail_info.feedback_report(), None);
+}
@@ -0,0 +1,352 @@
+//! Attachment inspection utilities.
+//!
+//! Use [`MailInfo::attachments()`](crate::MailInfo::attachments) to iterate over the
+//! attachments of a message. [`AttachmentLimits`] checks count and size limits.
+
+use crate::{ClassifyResult, MailInfo};
+use mail_parser::{MessagePart, MimeHeaders as _};
+use sha2::{Digest as _, Sha256};
+
+/// A single attachment of the message being classified.
+pub struct Attachment<'a> {
+    part: &'a MessagePart<'a>,
+}
+
+impl<'a> Attachment<'a> {
+    pub(crate) fn new(part: &'a MessagePart<'a>) -> Self {
+        Attachment { part }
+    }
+    /// Returns the file name of the attachment, or `""` if it has none.
+    pub fn name(&self) -> &str {
+        self.part.attachment_name().unwrap_or("")
+    }
+    /// Returns the declared MIME type (e.g. `"application/zip"`), or `""` if missing.
+    pub fn content_type(&self) -> String {
+        match self.part.content_type() {
+            Some(c) => match c.subtype() {
+                Some(subtype) => format!("{}/{}", c.ctype(), subtype),
+                None => c.ctype().to_string(),
+            },
+            None => "".to_string(),
+        }
+    }
+    /// Returns the decoded contents of the attachment.
+    pub fn contents(&self) -> &[u8] {
+        self.part.contents()
+    }
+    /// Returns the size of the decoded contents in bytes.
+    pub fn size(&self) -> usize {
+        self.part.len()
+    }
+    /// Returns the SHA-256 digest of the decoded contents as lowercase hex.
+    pub fn sha256(&self) -> String {
+        format!("{:x}", Sha256::digest(self.contents()))
+    }
+    /// Checks whether the SHA-256 digest of the attachment is in `hashes`.
+    ///
+    /// `hashes` is typically loaded with [`read_array`](crate::read_array) from a file with
+    /// one hex digest per line. The comparison is case-insensitive.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// if mail_info.attachments().any(|a| a.known_bad(&ctx.bad_hashes)) {
+    ///     return mail_info.reject("known bad attachment");
+    /// }
+    /// ```
+    pub fn known_bad(&self, hashes: &[String]) -> bool {
+        let digest = self.sha256();
+        hashes.iter().any(|h| h.eq_ignore_ascii_case(&digest))
+    }
+    /// Checks whether the attachment is a password-protected archive or an encrypted
+    /// document, which content scanners can not inspect.
+    ///
+    /// Detected are encrypted entries in ZIP archives, encrypted 7-Zip archives, RAR
+    /// archives with encrypted headers or files, encrypted PDF documents and encrypted
+    /// Office documents (OOXML in an OLE container). The type is detected from the
+    /// contents, not from the declared type or file name.
+    ///
+    /// 7-Zip archives whose header is compressed but not encrypted (`-mhe=off` with
+    /// header compression, the default of 7-Zip) are not detected, because the coder list
+    /// is only found in the compressed header.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// if mail_info.attachments().any(|a| a.is_encrypted()) && !ctx.known_senders.contains(..) {
+    ///     return mail_info.quarantine("encrypted attachment from unknown sender");
+    /// }
+    /// ```
+    pub fn is_encrypted(&self) -> bool {
+        is_encrypted(self.contents())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn is_encrypted(data: &[u8]) -> bool {
+    const ZIP: &[u8] = b"PK\x03\x04";
+    const SEVEN_ZIP: &[u8] = b"7z\xbc\xaf\x27\x1c";
+    const RAR4: &[u8] = b"Rar!\x1a\x07\x00";
+    const RAR5: &[u8] = b"Rar!\x1a\x07\x01\x00";
+    const OLE: &[u8] = b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1";
+    if data.starts_with(ZIP) {
+        // general purpose bit 0 of any local file header
+        let mut rest = data;
+        while let Some(pos) = find(rest, ZIP) {
+            if rest.get(pos + 6).is_some_and(|flags| flags & 1 != 0) {
+                return true;
+            }
+            rest = &rest[pos + ZIP.len()..];
+        }
+        false
+    } else if data.starts_with(SEVEN_ZIP) {
+        // AES-256 + SHA-256 coder in the header, or in the streams info of an encoded
+        // (kEncodedHeader) header
+        seven_zip_header(data).is_some_and(|header| find(header, b"\x06\xf1\x07\x01").is_some())
+    } else if let Some(rest) = data.strip_prefix(RAR4) {
+        rar4_encrypted(rest)
+    } else if let Some(rest) = data.strip_prefix(RAR5) {
+        // the first header after CRC32 and size is the archive encryption header (type 4)
+        let size_len = rest.iter().skip(4).position(|&b| b & 0x80 == 0);
+        size_len.is_some_and(|n| rest.get(4 + n + 1) == Some(&4))
+    } else if data.starts_with(b"%PDF") {
+        find(data, b"/Encrypt").is_some()
+    } else if data.starts_with(OLE) {
+        // encrypted OOXML documents are stored in an OLE container with this stream
+        let name: Vec<u8> = "EncryptionInfo"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        find(data, &name).is_some()
+    } else {
+        false
+    }
+}
+
+// Returns the header of a 7-Zip archive, located by the start header.
+fn seven_zip_header(data: &[u8]) -> Option<&[u8]> {
+    let u64_at = |pos: usize| Some(u64::from_le_bytes(data.get(pos..pos + 8)?.try_into().ok()?));
+    let start = usize::try_from(u64_at(12)?).ok()?.checked_add(32)?;
+    let size = usize::try_from(u64_at(20)?).ok()?;
+    data.get(start..start.checked_add(size)?)
+}
+
+// Walks the blocks of a RAR 4 archive after the marker block and checks for a main header
+// with MHD_PASSWORD or a file header with LHD_PASSWORD.
+fn rar4_encrypted(data: &[u8]) -> bool {
+    const MAIN_HEAD: u8 = 0x73;
+    const FILE_HEAD: u8 = 0x74;
+    const END_ARC: u8 = 0x7b;
+    let mut pos = 0;
+    while let Some(block) = data.get(pos..pos + 7) {
+        let (kind, flags) = (block[2], u16::from_le_bytes([block[3], block[4]]));
+        let head_size = u16::from_le_bytes([block[5], block[6]]) as usize;
+        if head_size < 7 || kind == END_ARC {
+            break;
+        }
+        match kind {
+            MAIN_HEAD if flags & 0x80 != 0 => return true,
+            FILE_HEAD if flags & 0x04 != 0 => return true,
+            _ => {}
+        }
+        // file headers always have a packed size, other blocks with LONG_BLOCK
+        let mut add_size = 0;
+        if kind == FILE_HEAD || flags & 0x8000 != 0 {
+            let Some(low) = data.get(pos + 7..pos + 11) else {
+                break;
+            };
+            add_size = u32::from_le_bytes(low.try_into().unwrap()) as u64;
+            // LHD_LARGE: high 32 bits of the packed size
+            if kind == FILE_HEAD
+                && flags & 0x100 != 0
+                && let Some(high) = data.get(pos + 32..pos + 36)
+            {
+                add_size |= (u32::from_le_bytes(high.try_into().unwrap()) as u64) << 32;
+            }
+        }
+        match usize::try_from(add_size)
+            .ok()
+            .and_then(|add| pos.checked_add(head_size)?.checked_add(add))
+        {
+            Some(next) if next < data.len() => pos = next,
+            _ => break,
+        }
+    }
+    false
+}
+
+#[derive(Clone, Copy)]
+enum Limit {
+    Count,
+    Size,
+    TotalSize,
+}
+
+/// Limits on the number and size of attachments, each with its own verdict.
+///
+/// Limits are checked in the order they were added.
+///
+/// # Example
+///
+/// ```ignore
+/// let limits = AttachmentLimits::new()
+///     .max_size(50 << 20, ClassifyResult::Reject)
+///     .max_count(20, ClassifyResult::Quarantine);
+///
+/// // in the classifier
+/// if let Some(result) = ctx.limits.check(mail_info) {
+///     return result;
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct AttachmentLimits {
+    limits: Vec<(Limit, usize, ClassifyResult)>,
+}
+
+impl AttachmentLimits {
+    /// Creates an empty set of limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Limits the number of attachments.
+    pub fn max_count(mut self, count: usize, verdict: ClassifyResult) -> Self {
+        self.limits.push((Limit::Count, count, verdict));
+        self
+    }
+    /// Limits the decoded size of each single attachment in bytes.
+    pub fn max_size(mut self, bytes: usize, verdict: ClassifyResult) -> Self {
+        self.limits.push((Limit::Size, bytes, verdict));
+        self
+    }
+    /// Limits the sum of the decoded sizes of all attachments in bytes.
+    pub fn max_total_size(mut self, bytes: usize, verdict: ClassifyResult) -> Self {
+        self.limits.push((Limit::TotalSize, bytes, verdict));
+        self
+    }
+    /// Checks the message against the limits.
+    ///
+    /// For the first exceeded limit, the decision is logged with the corresponding decision
+    /// method of `mail_info` and its verdict is returned. Returns `None` if all limits are
+    /// met.
+    pub fn check(&self, mail_info: &MailInfo) -> Option<ClassifyResult> {
+        for &(limit, max, verdict) in &self.limits {
+            let reason = match limit {
+                Limit::Count => {
+                    let count = mail_info.attachment_count();
+                    (count > max).then(|| format!("{count} attachments, limit {max}"))
+                }
+                Limit::Size => mail_info
+                    .largest_attachment()
+                    .filter(|a| a.size() > max)
+                    .map(|a| {
+                        format!(
+                            "attachment {:?} has {} bytes, limit {max}",
+                            a.name(),
+                            a.size()
+                        )
+                    }),
+                Limit::TotalSize => {
+                    let size = mail_info.total_attachment_size();
+                    (size > max).then(|| format!("attachments have {size} bytes, limit {max}"))
+                }
+            };
+            if let Some(reason) = reason {
+                return Some(match verdict {
+                    ClassifyResult::Accept => mail_info.accept(&reason),
+                    ClassifyResult::Quarantine => mail_info.quarantine(&reason),
+                    ClassifyResult::Reject => mail_info.reject(&reason),
+                    ClassifyResult::Tempfail => mail_info.tempfail(&reason),
+                });
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn test_attachment() {
+    use crate::{MailInfo, MailInfoStorage};
+    use mail_parser::MessageParser;
+
+    let storage = MailInfoStorage {
+        mail_buffer: std::fs::read("tests/parse_004.eml").unwrap(),
+        ..Default::default()
+    };
+    let mail_info = MailInfo::new(
+        &storage,
+        MessageParser::default()
+            .parse(&storage.mail_buffer)
+            .unwrap(),
+    );
+    let attachments: Vec<Attachment> = mail_info.attachments().collect();
+    assert_eq!(attachments.len(), 1);
+    let a = &attachments[0];
+    assert_eq!(a.name(), "hello.txt");
+    assert_eq!(a.content_type(), "text/plain");
+    assert_eq!(a.contents(), b"hello world\n");
+    assert_eq!(a.size(), 12);
+    assert_eq!(
+        a.sha256(),
+        "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447"
+    );
+    assert!(a.known_bad(&[
+        "A948904F2F0F479B8F8197694B30184B0D2ED1C1CD2A1EC0FB85D299A192A447".to_string()
+    ]));
+    assert!(!a.known_bad(&["a948904f".to_string()]));
+
+    assert_eq!(mail_info.attachment_count(), 1);
+    assert_eq!(mail_info.total_attachment_size(), 12);
+    assert_eq!(mail_info.largest_attachment().unwrap().name(), "hello.txt");
+    let limits = AttachmentLimits::new()
+        .max_count(1, ClassifyResult::Quarantine)
+        .max_total_size(100, ClassifyResult::Quarantine);
+    assert_eq!(limits.check(&mail_info), None);
+    let limits = limits.max_size(10, ClassifyResult::Reject);
+    assert_eq!(limits.check(&mail_info), Some(ClassifyResult::Reject));
+
+    assert!(!a.is_encrypted());
+    assert!(is_encrypted(b"PK\x03\x04\x14\x00\x01\x00\x08\x00"));
+    assert!(!is_encrypted(b"PK\x03\x04\x14\x00\x00\x08\x08\x00"));
+    assert!(is_encrypted(
+        b"%PDF-1.7\n1 0 obj\ntrailer << /Encrypt 5 0 R >>"
+    ));
+    assert!(!is_encrypted(b"%PDF-1.7\n1 0 obj"));
+    assert!(is_encrypted(
+        b"Rar!\x1a\x07\x01\x00\x00\x00\x00\x00\x0c\x04\x00"
+    ));
+    // stored file whose contents look like an encrypted file header
+    let mut rar4 = std::fs::read("tests/unencrypted.rar").unwrap();
+    assert!(!is_encrypted(&rar4));
+    rar4[0x17] |= 0x04; // LHD_PASSWORD in the file header
+    assert!(is_encrypted(&rar4));
+    assert!(is_encrypted(
+        b"Rar!\x1a\x07\x00\x00\x00\x73\x80\x00\x0d\x00"
+    ));
+    // the AES coder only counts in the header, not in the packed streams
+    let seven_zip = |payload: &[u8], header: &[u8]| {
+        let mut data = b"7z\xbc\xaf\x27\x1c\x00\x04\x00\x00\x00\x00".to_vec();
+        data.extend((payload.len() as u64).to_le_bytes());
+        data.extend((header.len() as u64).to_le_bytes());
+        data.extend([0; 4]);
+        data.extend(payload);
+        data.extend(header);
+        data
+    };
+    assert!(is_encrypted(&seven_zip(
+        b"data",
+        b"\x01\x04\x06\xf1\x07\x01"
+    )));
+    assert!(!is_encrypted(&seven_zip(
+        b"\x06\xf1\x07\x01",
+        b"\x01\x04\x03\x01\x01"
+    )));
+    assert!(!is_encrypted(
+        b"Rar!\x1a\x07\x01\x00\x00\x00\x00\x00\x0c\x01\x00"
+    ));
+    let mut ole = b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1".to_vec();
+    ole.extend("EncryptionInfo".encode_utf16().flat_map(u16::to_le_bytes));
+    assert!(is_encrypted(&ole));
+}
@@ -0,0 +1,123 @@
+//! Bounce Address Tag Validation (BATV) with the `prvs` scheme.
+//!
+//! Outgoing mail is sent with a tagged envelope sender
+//! `prvs=KDDDSSSSSS=user@example.com`, where `K` is the key number, `DDD` the day the tag
+//! expires (modulo 1000) and `SSSSSS` the start of an HMAC-SHA1 over `KDDD` and the
+//! address. Legitimate bounces are sent to the tagged address, so bounces to untagged or
+//! wrongly tagged addresses are backscatter caused by forged senders.
+//!
+//! Outgoing mail is signed with
+//! [`ConfigBuilder::batv_signing()`](crate::ConfigBuilder::batv_signing), which tags the
+//! envelope sender with the change-from milter action. Use [`verify()`] in the classifier
+//! for incoming bounces.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let config = Config::builder()
+//!     .email_classifier(classifier)
+//!     .batv_signing(&batv_key, 7, &["example.com"])
+//!     .build();
+//!
+//! // in the classifier
+//! if mail_info.is_bounce() {
+//!     let recipient = mail_info.get_only_recipient();
+//!     if batv::verify(recipient, &ctx.batv_key, batv::today(), 7).is_none() {
+//!         return mail_info.reject("bounce to untagged address");
+//!     }
+//! }
+//! ```
+
+use hmac::{Hmac, Mac as _};
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KEY_NUMBER: char = '0';
+
+/// Returns the current day number (days since 1970-01-01).
+pub fn today() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    (secs / 86400) as u32
+}
+
+fn signature(key: &[u8], kddd: &str, address: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(kddd.as_bytes());
+    mac.update(address.to_ascii_lowercase().as_bytes());
+    let digest = mac.finalize().into_bytes();
+    format!("{:02x}{:02x}{:02x}", digest[0], digest[1], digest[2])
+}
+
+/// Returns the tagged address for `address`, valid until day `today + valid_days`.
+pub fn sign(address: &str, key: &[u8], today: u32, valid_days: u32) -> String {
+    let kddd = format!("{KEY_NUMBER}{:03}", (today + valid_days) % 1000);
+    format!("prvs={kddd}{}={address}", signature(key, &kddd, address))
+}
+
+/// Returns the address without a `prvs` tag, whether the tag is valid or not.
+pub fn strip(address: &str) -> &str {
+    parse(address)
+        .map(|(_, _, address)| address)
+        .unwrap_or(address)
+}
+
+// Splits a tagged address into KDDD, signature and original address.
+fn parse(address: &str) -> Option<(&str, &str, &str)> {
+    let prefix = address.get(..5)?;
+    if !prefix.eq_ignore_ascii_case("prvs=") {
+        return None;
+    }
+    let (tag, address) = address[5..].split_once('=')?;
+    if tag.len() != 10 || !tag.is_ascii() {
+        return None;
+    }
+    Some((&tag[..4], &tag[4..], address))
+}
+
+/// Verifies the `prvs` tag of `address`.
+///
+/// Returns the original address if the tag was made with `key`, and expires today or
+/// within `max_valid_days`. Returns `None` for untagged, forged or expired addresses.
+pub fn verify<'a>(
+    address: &'a str,
+    key: &[u8],
+    today: u32,
+    max_valid_days: u32,
+) -> Option<&'a str> {
+    let (kddd, sig, original) = parse(address)?;
+    let expiry: u32 = kddd[1..].parse().ok()?;
+    let remaining = (expiry + 1000 - today % 1000) % 1000;
+    if remaining > max_valid_days || !sig.eq_ignore_ascii_case(&signature(key, kddd, original)) {
+        return None;
+    }
+    Some(original)
+}
+
+#[test]
+fn test_batv() {
+    let key = b"secret";
+    let tagged = sign("Alice@example.com", key, 20368, 7);
+    assert!(tagged.starts_with("prvs=0375"));
+    assert_eq!(strip(&tagged), "Alice@example.com");
+    assert_eq!(verify(&tagged, key, 20368, 7), Some("Alice@example.com"));
+    assert_eq!(verify(&tagged, key, 20375, 7), Some("Alice@example.com"));
+    assert_eq!(verify(&tagged, key, 20376, 7), None); // expired
+    assert_eq!(verify(&tagged, b"other", 20368, 7), None);
+    assert_eq!(
+        verify(
+            &tagged.to_uppercase().replace("ALICE", "alice"),
+            key,
+            20368,
+            7
+        ),
+        Some("alice@EXAMPLE.COM")
+    );
+    assert_eq!(verify("alice@example.com", key, 20368, 7), None);
+    assert_eq!(strip("alice@example.com"), "alice@example.com");
+    // wraps around at day 1000
+    let tagged = sign("a@example.com", key, 20998, 7);
+    assert_eq!(verify(&tagged, key, 21000, 7), Some("a@example.com"));
+}
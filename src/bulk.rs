@@ -0,0 +1,298 @@
+//! Mailing-list and bulk-mail classification helpers.
+//!
+//! Use [`MailInfo::bulk_profile()`](crate::MailInfo::bulk_profile) to get a [`BulkProfile`]
+//! of a message.
+
+use crate::MailInfo;
+use mail_parser::HeaderName;
+
+// Headers set by email service providers: (header name, provider)
+const ESP_HEADERS: [(&str, &str); 9] = [
+    ("X-SES-Outgoing", "Amazon SES"),
+    ("X-Mailgun-Sid", "Mailgun"),
+    ("X-SG-EID", "SendGrid"),
+    ("X-MC-User", "Mailchimp"),
+    ("X-Mandrill-User", "Mandrill"),
+    ("X-PM-Message-Id", "Postmark"),
+    ("X-Mailin-EID", "Brevo"),
+    ("X-MJ-Mid", "Mailjet"),
+    ("X-MSFBL", "SparkPost"),
+];
+
+const CAMPAIGN_HEADERS: [&str; 4] = [
+    "X-Campaign-Id",
+    "X-CampaignID",
+    "X-Campaign",
+    "X-Mailgun-Campaign-Id",
+];
+
+/// Summary of the bulk-mail indicators found in a message.
+///
+/// Text fields are `""` when the corresponding header is missing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BulkProfile {
+    /// A `List-Unsubscribe:` header is present.
+    pub list_unsubscribe: bool,
+    /// Value of the `List-Id:` header.
+    pub list_id: String,
+    /// Value of the `Precedence:` header, lowercased (e.g. `"bulk"`, `"list"`).
+    pub precedence: String,
+    /// Value of the `Feedback-ID:` header.
+    pub feedback_id: String,
+    /// Value of the first campaign identifier header found (e.g. `X-Campaign-Id:`).
+    pub campaign_id: String,
+    /// Name of the email service provider recognized from its headers.
+    pub esp: String,
+}
+
+impl BulkProfile {
+    /// Returns `true` if any bulk-mail indicator was found.
+    pub fn is_bulk(&self) -> bool {
+        self.list_unsubscribe
+            || !self.list_id.is_empty()
+            || matches!(self.precedence.as_str(), "bulk" | "list" | "junk")
+            || !self.feedback_id.is_empty()
+            || !self.campaign_id.is_empty()
+            || !self.esp.is_empty()
+    }
+    /// Returns `true` if the message looks like it was sent to a mailing list.
+    pub fn is_list(&self) -> bool {
+        !self.list_id.is_empty() || self.precedence == "list"
+    }
+}
+
+/// Result of the RFC 8058 one-click unsubscribe validation.
+///
+/// See [`MailInfo::one_click_unsubscribe()`](crate::MailInfo::one_click_unsubscribe).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OneClickUnsubscribe {
+    /// No `List-Unsubscribe:` header.
+    Missing,
+    /// The `List-Unsubscribe:` header contains no valid `<uri>` element.
+    Malformed,
+    /// No `List-Unsubscribe-Post: List-Unsubscribe=One-Click` header.
+    NoOneClick,
+    /// One-click is announced, but `List-Unsubscribe:` contains no HTTPS URI.
+    NoHttpsUri,
+    /// The unsubscribe headers are not covered by a DKIM signature.
+    NotSigned,
+    /// The headers satisfy the syntactic requirements of RFC 8058.
+    Compliant,
+}
+
+impl OneClickUnsubscribe {
+    /// Returns `true` if the message is [`Compliant`](Self::Compliant).
+    pub fn is_compliant(self) -> bool {
+        self == OneClickUnsubscribe::Compliant
+    }
+}
+
+fn raw_header_values<'a>(
+    msg: &'a mail_parser::Message<'_>,
+    name: HeaderName<'static>,
+) -> impl Iterator<Item = &'a str> {
+    let raw = msg.raw_message();
+    msg.headers()
+        .iter()
+        .filter(move |h| h.name == name)
+        .filter_map(move |h| {
+            std::str::from_utf8(&raw[h.offset_start as usize..h.offset_end as usize]).ok()
+        })
+}
+
+// Returns the URIs from a RFC 2369 header value: `<uri>, <uri>, ...`
+fn list_header_uris(value: &str) -> Vec<&str> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element
+                .trim()
+                .strip_prefix('<')
+                .and_then(|s| s.strip_suffix('>'))
+                .map(str::trim)
+                .filter(|uri| uri.contains(':'))
+        })
+        .collect()
+}
+
+// Checks whether the `h=` tag of a DKIM-Signature value lists all given header names.
+fn dkim_signs_headers(dkim_signature: &str, names: &[&str]) -> bool {
+    dkim_signature
+        .split(';')
+        .filter_map(|tag| tag.trim().strip_prefix("h="))
+        .any(|h| {
+            let signed: Vec<String> = h
+                .split(':')
+                .map(|n| n.trim().to_ascii_lowercase())
+                .collect();
+            names.iter().all(|n| signed.iter().any(|s| s == n))
+        })
+}
+
+pub(crate) fn one_click_unsubscribe(mail_info: &MailInfo) -> OneClickUnsubscribe {
+    let msg = mail_info.get_message();
+    let Some(list_unsubscribe) = msg.header_raw(HeaderName::ListUnsubscribe) else {
+        return OneClickUnsubscribe::Missing;
+    };
+    let uris = list_header_uris(list_unsubscribe);
+    if uris.is_empty() {
+        return OneClickUnsubscribe::Malformed;
+    }
+    let one_click = msg
+        .header_raw(HeaderName::ListUnsubscribePost)
+        .map(|v| v.trim() == "List-Unsubscribe=One-Click")
+        .unwrap_or(false);
+    if !one_click {
+        return OneClickUnsubscribe::NoOneClick;
+    }
+    if !uris.iter().any(|uri| {
+        uri.len() > 8
+            && uri
+                .get(..8)
+                .is_some_and(|p| p.eq_ignore_ascii_case("https://"))
+    }) {
+        return OneClickUnsubscribe::NoHttpsUri;
+    }
+    if !raw_header_values(msg, HeaderName::DkimSignature)
+        .any(|v| dkim_signs_headers(v, &["list-unsubscribe", "list-unsubscribe-post"]))
+    {
+        return OneClickUnsubscribe::NotSigned;
+    }
+    OneClickUnsubscribe::Compliant
+}
+
+pub(crate) fn bulk_profile(mail_info: &MailInfo) -> BulkProfile {
+    let msg = mail_info.get_message();
+    let campaign_id = CAMPAIGN_HEADERS
+        .iter()
+        .map(|h| mail_info.get_other_header(h))
+        .find(|v| !v.is_empty())
+        .unwrap_or("");
+    let esp = ESP_HEADERS
+        .iter()
+        .find(|(h, _)| !mail_info.get_other_header(h).is_empty())
+        .map(|(_, esp)| *esp)
+        .unwrap_or("");
+    BulkProfile {
+        list_unsubscribe: msg.header(HeaderName::ListUnsubscribe).is_some(),
+        list_id: msg
+            .header_raw(HeaderName::ListId)
+            .unwrap_or("")
+            .trim()
+            .to_string(),
+        precedence: mail_info
+            .get_other_header("Precedence")
+            .trim()
+            .to_lowercase(),
+        feedback_id: mail_info.get_other_header("Feedback-ID").to_string(),
+        campaign_id: campaign_id.to_string(),
+        esp: esp.to_string(),
+    }
+}
+
+#[test]
+fn test_bulk_profile() {
+    use crate::MailInfoStorage;
+    use mail_parser::MessageParser;
+
+    let storage = MailInfoStorage {
+        mail_buffer: b"From: news@example.org\r\n\
+            List-Id: Example News <news.example.org>\r\n\
+            List-Unsubscribe: <https://example.org/unsub?u=1>\r\n\
+            Precedence: Bulk\r\n\
+            Feedback-ID: 123:campaign:example\r\n\
+            X-SES-Outgoing: 2025.10.06-54.240.0.1\r\n\
+            Subject: News\r\n\r\nText"
+            .to_vec(),
+        ..Default::default()
+    };
+    let mail_info = MailInfo::new(
+        &storage,
+        MessageParser::default()
+            .parse(&storage.mail_buffer)
+            .unwrap(),
+    );
+    let profile = mail_info.bulk_profile();
+    assert!(profile.list_unsubscribe);
+    assert_eq!(profile.list_id, "Example News <news.example.org>");
+    assert_eq!(profile.precedence, "bulk");
+    assert_eq!(profile.feedback_id, "123:campaign:example");
+    assert_eq!(profile.campaign_id, "");
+    assert_eq!(profile.esp, "Amazon SES");
+    assert!(profile.is_bulk());
+    assert!(profile.is_list());
+
+    let storage = MailInfoStorage {
+        mail_buffer: std::fs::read("tests/parse_001.eml").unwrap(),
+        ..Default::default()
+    };
+    let mail_info = MailInfo::new(
+        &storage,
+        MessageParser::default()
+            .parse(&storage.mail_buffer)
+            .unwrap(),
+    );
+    assert_eq!(mail_info.bulk_profile(), BulkProfile::default());
+    assert!(!mail_info.bulk_profile().is_bulk());
+}
+
+#[test]
+fn test_one_click_unsubscribe() {
+    use crate::MailInfoStorage;
+    use mail_parser::MessageParser;
+
+    let check = |headers: &str| {
+        let storage = MailInfoStorage {
+            mail_buffer: format!("From: news@example.org\r\n{headers}\r\nText").into_bytes(),
+            ..Default::default()
+        };
+        let mail_info = MailInfo::new(
+            &storage,
+            MessageParser::default()
+                .parse(&storage.mail_buffer)
+                .unwrap(),
+        );
+        mail_info.one_click_unsubscribe()
+    };
+    assert_eq!(check(""), OneClickUnsubscribe::Missing);
+    assert_eq!(
+        check("List-Unsubscribe: https://example.org/u\r\n"),
+        OneClickUnsubscribe::Malformed
+    );
+    assert_eq!(
+        check("List-Unsubscribe: <mailto:u@example.org>, <https://example.org/u>\r\n"),
+        OneClickUnsubscribe::NoOneClick
+    );
+    assert_eq!(
+        check(
+            "List-Unsubscribe: <mailto:u@example.org>\r\n\
+             List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n"
+        ),
+        OneClickUnsubscribe::NoHttpsUri
+    );
+    assert_eq!(
+        check(
+            "List-Unsubscribe: <https:/éxample.org>\r\n\
+             List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n"
+        ),
+        OneClickUnsubscribe::NoHttpsUri
+    );
+    assert_eq!(
+        check(
+            "List-Unsubscribe: <https://example.org/u>\r\n\
+             List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n\
+             DKIM-Signature: v=1; d=example.org; h=From:Subject; b=abc\r\n"
+        ),
+        OneClickUnsubscribe::NotSigned
+    );
+    assert!(
+        check(
+            "DKIM-Signature: v=1; d=example.org;\r\n\
+             \th=From:Subject:List-Unsubscribe:\r\n\
+             \tList-Unsubscribe-Post; b=abc\r\n\
+             List-Unsubscribe: <mailto:u@example.org>,\r\n <https://example.org/u>\r\n\
+             List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n"
+        )
+        .is_compliant()
+    );
+}
@@ -0,0 +1,143 @@
+//! Circuit breaker for calls to external dependencies.
+//!
+//! When a dependency (DNS blocklist, scanner, database, web service) fails repeatedly, waiting
+//! for its timeouts on every message delays mail delivery. A [`CircuitBreaker`] counts
+//! consecutive failures and, after a threshold is reached, skips the dependency for a cooldown
+//! period. After the cooldown, a single trial call is let through: if it succeeds, the breaker
+//! closes again, otherwise it stays open for another cooldown period.
+//!
+//! The breaker keeps its state in memory. In thread mode a breaker stored in the classifier
+//! context is shared by all connections. In fork mode each child process starts with the
+//! state the parent had, so failures seen by one connection are not visible to others.
+//!
+//! # Example
+//!
+//! ```ignore
+//! struct Ctx {
+//!     clamd: CircuitBreaker,
+//! }
+//!
+//! fn classify(ctx: &Ctx, mail_info: &MailInfo) -> ClassifyResult {
+//!     match ctx.clamd.call(|| scan(mail_info)) {
+//!         Some(Ok(true)) => return mail_info.reject("virus found"),
+//!         Some(Ok(false)) => (),
+//!         Some(Err(e)) => mail_info.log(&format!("clamd: {e}")),
+//!         None => mail_info.log("clamd skipped"),
+//!     }
+//!     mail_info.accept("default")
+//! }
+//! ```
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    open_until: Option<Instant>,
+    skipped: u64,
+}
+
+/// Skips an external dependency for a cooldown period after repeated failures.
+pub struct CircuitBreaker {
+    name: String,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    /// Creates a breaker which opens after `threshold` consecutive failures and stays open
+    /// for `cooldown`.
+    ///
+    /// `name` identifies the dependency in log messages.
+    pub fn new(name: &str, threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            name: name.to_string(),
+            threshold: threshold.max(1),
+            cooldown,
+            state: Mutex::new(State::default()),
+        }
+    }
+    /// Returns `true` if calls to the dependency should currently be skipped.
+    ///
+    /// Each `true` result is counted as a skipped call.
+    pub fn is_open(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(until) if Instant::now() < until => {
+                state.skipped += 1;
+                true
+            }
+            Some(_) => {
+                // half-open: let one trial call through, keep others out until it reports
+                state.open_until = Some(Instant::now() + self.cooldown);
+                false
+            }
+            None => false,
+        }
+    }
+    /// Records a successful call, closing the breaker.
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.is_some() {
+            eprintln!("circuit breaker {}: closed", self.name);
+        }
+        state.failures = 0;
+        state.open_until = None;
+    }
+    /// Records a failed call, opening the breaker if the threshold is reached.
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= self.threshold {
+            if state.open_until.is_none() {
+                eprintln!(
+                    "circuit breaker {}: open after {} failures, skipping for {}s",
+                    self.name,
+                    state.failures,
+                    self.cooldown.as_secs_f32()
+                );
+            }
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+    /// Calls `f` unless the breaker is open and records the outcome.
+    ///
+    /// Returns `None` if the call was skipped.
+    pub fn call<T, E>(&self, f: impl FnOnce() -> Result<T, E>) -> Option<Result<T, E>> {
+        if self.is_open() {
+            return None;
+        }
+        let result = f();
+        match result {
+            Ok(_) => self.record_success(),
+            Err(_) => self.record_failure(),
+        }
+        Some(result)
+    }
+    /// Returns the number of calls skipped because the breaker was open.
+    pub fn skipped(&self) -> u64 {
+        self.state.lock().unwrap().skipped
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let breaker = CircuitBreaker::new("test", 2, Duration::from_millis(50));
+    assert_eq!(breaker.call(|| Err::<(), _>("down")), Some(Err("down")));
+    assert_eq!(breaker.call(|| Ok::<_, ()>(1)), Some(Ok(1)));
+    assert_eq!(breaker.call(|| Err::<(), _>("down")), Some(Err("down")));
+    assert_eq!(breaker.call(|| Err::<(), _>("down")), Some(Err("down")));
+    assert!(breaker.call(|| Ok::<_, ()>(1)).is_none());
+    assert_eq!(breaker.skipped(), 1);
+    std::thread::sleep(Duration::from_millis(60));
+    // trial call fails: open again
+    assert_eq!(breaker.call(|| Err::<(), _>("down")), Some(Err("down")));
+    assert!(breaker.is_open());
+    std::thread::sleep(Duration::from_millis(60));
+    // trial call succeeds: closed
+    assert_eq!(breaker.call(|| Ok::<_, ()>(2)), Some(Ok(2)));
+    assert!(!breaker.is_open());
+    assert_eq!(breaker.skipped(), 2);
+}
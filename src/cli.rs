@@ -1,36 +1,296 @@
-use crate::daemon::{daemon, simulate};
-use crate::{Config, MailInfoStorage, classify_mail};
+#[cfg(feature = "daemon")]
+use crate::daemon::{
+    ProtocolOptions, action_names, daemon as run_daemon, negotiated_actions, negotiated_protocol,
+};
+#[cfg(feature = "daemon")]
+use crate::loadgen::loadgen;
+#[cfg(feature = "daemon")]
+use crate::milter::constants::*;
+#[cfg(feature = "daemon")]
+use crate::simulate::simulate;
+use crate::{
+    ClassifyResult, Config, Decision, MailInfo, MailInfoStorage, batv, bypass, classify_mail, html,
+    secrets, spamhaus_zen,
+};
 use clap::Parser;
 use mail_parser::{MessageParser, MimeHeaders};
+#[cfg(unix)]
+use nix::unistd::dup2_stderr;
 use std::error::Error;
 use std::fs;
+use std::io::{self, Read as _, Write};
+#[cfg(feature = "daemon")]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::process::exit;
+#[cfg(feature = "daemon")]
+use std::thread;
+
+// Reads a message from `path`, or from stdin if `path` is `-`.
+fn read_message(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    if path == Path::new("-") {
+        let mut buffer = Vec::new();
+        io::stdin().lock().read_to_end(&mut buffer)?;
+        Ok(buffer)
+    } else {
+        Ok(fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?)
+    }
+}
+
+// Exit status of the test command for a verdict.
+fn exit_code(result: ClassifyResult) -> i32 {
+    match result {
+        ClassifyResult::Accept => 0,
+        ClassifyResult::Quarantine => 10,
+        ClassifyResult::Reject => 20,
+        ClassifyResult::Tempfail => 30,
+    }
+}
 
 fn cmd_test(
     config: &Config,
     filename: &Path,
     sender: String,
     recipients: Vec<String>,
+    quiet: bool,
+    explain: bool,
 ) -> Result<(), Box<dyn Error>> {
     let storage = MailInfoStorage {
         sender,
         recipients,
-        mail_buffer: fs::read(filename)?,
+        mail_buffer: read_message(filename)?,
         id: "test".to_string(),
         ..Default::default()
     };
-    classify_mail(config, &storage);
+    #[cfg(unix)]
+    if quiet {
+        // silence the log output of the classifier
+        dup2_stderr(fs::File::options().write(true).open("/dev/null")?)?;
+    }
+    let verdict = classify_mail(config, &storage);
+    if explain {
+        let decision = Decision {
+            queue_id: &storage.id,
+            sender: &storage.sender,
+            recipients: &storage.recipients,
+            result: verdict.result,
+            reason: &verdict.reason,
+            log: &verdict.log,
+        };
+        println!("{}", decision.explain());
+    } else if !quiet {
+        println!("{}\t{}", verdict.result.uc(), verdict.reason);
+    }
+    match exit_code(verdict.result) {
+        0 => Ok(()),
+        code => exit(code),
+    }
+}
+
+// Quotes a CSV field if needed (RFC 4180).
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// Quotes a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Columns of the `score` report.
+const SCORE_COLUMNS: [&str; 11] = [
+    "file",
+    "verdict",
+    "spam_score",
+    "dnsbl_hits",
+    "spf",
+    "dkim",
+    "dmarc",
+    "bulk",
+    "list",
+    "from",
+    "subject",
+];
+
+// A value of the `score` report, empty in CSV and null in JSON if missing.
+enum ScoreValue {
+    Missing,
+    Score(f32),
+    Count(usize),
+    Flag(bool),
+    Text(String),
+}
+
+impl ScoreValue {
+    fn text(s: Option<&str>) -> Self {
+        s.map_or(ScoreValue::Missing, |s| ScoreValue::Text(s.to_string()))
+    }
+}
+
+// Result of `method` in an `Authentication-Results:` header value (RFC 8601), e.g. `pass`
+// for `dkim=pass`.
+fn auth_result<'a>(value: &'a str, method: &str) -> Option<&'a str> {
+    // the first element is the authserv-id
+    value.split(';').skip(1).find_map(|resinfo| {
+        let (name, result) = resinfo.split_whitespace().next()?.split_once('=')?;
+        name.eq_ignore_ascii_case(method).then_some(result)
+    })
+}
+
+// Classifies `file` and returns its row of the `score` report. An unreadable file gets the
+// verdict `error`, so that the rest of the directory is still scored.
+fn score_row(config: &Config, file: &Path, dns: bool) -> Vec<ScoreValue> {
+    let mut row = vec![ScoreValue::Text(file.to_string_lossy().into())];
+    let mail_buffer = match read_message(file) {
+        Ok(mail_buffer) => mail_buffer,
+        Err(e) => {
+            eprintln!("{e}");
+            row.push(ScoreValue::Text("error".to_string()));
+            return row;
+        }
+    };
+    let storage = MailInfoStorage {
+        mail_buffer,
+        id: file
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into(),
+        ..Default::default()
+    };
+    let verdict = classify_mail(config, &storage).result;
+    row.push(ScoreValue::Text(verdict.uc().to_string()));
+    if let Some(msg) = MessageParser::default().parse(&storage.mail_buffer) {
+        let mail_info = MailInfo::new(&storage, msg);
+        let bulk = mail_info.bulk_profile();
+        // not verified to be added by a trusted host, good enough for offline analysis
+        let auth_results = mail_info.header_values("Authentication-Results");
+        let auth =
+            |method| ScoreValue::text(auth_results.iter().find_map(|v| auth_result(v, method)));
+        row.extend([
+            ScoreValue::Score(mail_info.get_spam_score()),
+            if dns {
+                ScoreValue::Count(spamhaus_zen::listed_count(mail_info.received_ip_iter()))
+            } else {
+                ScoreValue::Missing
+            },
+            auth("spf"),
+            auth("dkim"),
+            auth("dmarc"),
+            ScoreValue::Flag(bulk.is_bulk()),
+            ScoreValue::Flag(bulk.is_list()),
+            ScoreValue::Text(mail_info.get_from_address().to_string()),
+            ScoreValue::Text(mail_info.get_subject().to_string()),
+        ]);
+    }
+    row
+}
+
+fn write_score_csv(out: &mut dyn Write, row: &[ScoreValue]) -> io::Result<()> {
+    let fields: Vec<String> = (0..SCORE_COLUMNS.len())
+        .map(|i| match row.get(i) {
+            None | Some(ScoreValue::Missing) => String::new(),
+            Some(ScoreValue::Score(score)) => score.to_string(),
+            Some(ScoreValue::Count(count)) => count.to_string(),
+            Some(ScoreValue::Flag(flag)) => flag.to_string(),
+            Some(ScoreValue::Text(text)) => csv_field(text),
+        })
+        .collect();
+    writeln!(out, "{}", fields.join(","))
+}
+
+fn write_score_json(out: &mut dyn Write, row: &[ScoreValue]) -> io::Result<()> {
+    let fields: Vec<String> = SCORE_COLUMNS
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            let value = match row.get(i) {
+                None | Some(ScoreValue::Missing) => "null".to_string(),
+                // the header may say "nan" or "inf", which JSON can not represent
+                Some(ScoreValue::Score(score)) if !score.is_finite() => "null".to_string(),
+                Some(ScoreValue::Score(score)) => score.to_string(),
+                Some(ScoreValue::Count(count)) => count.to_string(),
+                Some(ScoreValue::Flag(flag)) => flag.to_string(),
+                Some(ScoreValue::Text(text)) => json_string(text),
+            };
+            format!("{}:{value}", json_string(column))
+        })
+        .collect();
+    write!(out, "{{{}}}", fields.join(","))
+}
+
+fn cmd_score(config: &Config, args: &ScoreArgs) -> Result<(), Box<dyn Error>> {
+    let json = match args.format.as_deref() {
+        Some(format) => format == "json",
+        None => args
+            .output
+            .as_ref()
+            .is_some_and(|output| output.extension().is_some_and(|ext| ext == "json")),
+    };
+    let files: Vec<PathBuf> = if args.input == Path::new("-") {
+        vec![args.input.clone()]
+    } else {
+        let mut files: Vec<PathBuf> = fs::read_dir(&args.input)
+            .map_err(|e| format!("{}: {e}", args.input.display()))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        files.sort();
+        files
+    };
+    let mut out: Box<dyn Write> = match args.output {
+        Some(ref output) => Box::new(io::BufWriter::new(
+            fs::File::create(output).map_err(|e| format!("{}: {e}", output.display()))?,
+        )),
+        None => Box::new(io::stdout().lock()),
+    };
+    if json {
+        write!(out, "[")?;
+    } else {
+        writeln!(out, "{}", SCORE_COLUMNS.join(","))?;
+    }
+    for (i, file) in files.iter().enumerate() {
+        let row = score_row(config, file, !args.no_dns);
+        if json {
+            writeln!(out, "{}", if i == 0 { "" } else { "," })?;
+            write_score_json(&mut out, &row)?;
+        } else {
+            write_score_csv(&mut out, &row)?;
+        }
+    }
+    if json {
+        writeln!(out, "\n]")?;
+    }
+    out.flush()?;
     Ok(())
 }
 
-fn cmd_dump(dump_args: &DumpArgs) -> Result<(), Box<dyn Error>> {
+fn cmd_dump(config: &Config, dump_args: &DumpArgs) -> Result<(), Box<dyn Error>> {
     let (dump_header, dump_body) = match (dump_args.header, dump_args.body) {
         (false, false) => (true, true),
         (dump_header, dump_body) => (dump_header, dump_body),
     };
     let dump_html = dump_args.dump_html;
     let filename = &dump_args.filename;
-    let mail_buffer = fs::read(filename)?;
+    let mail_buffer = read_message(filename)?;
     let r = MessageParser::default().parse(&mail_buffer);
     match r {
         Some(msg) => {
@@ -59,8 +319,7 @@ fn cmd_dump(dump_args: &DumpArgs) -> Result<(), Box<dyn Error>> {
                         && part.is_content_type("text", "html")
                         && let Some(text) = part.text_contents()
                     {
-                        let md = html2md::rewrite_html(text, false);
-                        println!("{}", md);
+                        println!("{}", html::to_text(text, &config.html_limits));
                     }
                 }
             }
@@ -70,6 +329,109 @@ fn cmd_dump(dump_args: &DumpArgs) -> Result<(), Box<dyn Error>> {
     }
 }
 
+// Describes the negotiated stages and actions, one line per item.
+#[cfg(feature = "daemon")]
+fn explain_negotiation(config: &Config, args: &DaemonArgs) -> Vec<String> {
+    // (stage, SMFIC command, flag to skip stage, flag to skip reply)
+    const STAGES: [(&str, char, u32, u32); 9] = [
+        ("connect", 'C', SMFIP_NOCONNECT, SMFIP_NR_CONN),
+        ("helo", 'H', SMFIP_NOHELO, SMFIP_NR_HELO),
+        ("mail", 'M', SMFIP_NOMAIL, SMFIP_NR_MAIL),
+        ("rcpt", 'R', SMFIP_NORCPT, SMFIP_NR_RCPT),
+        ("data", 'T', SMFIP_NODATA, SMFIP_NR_DATA),
+        ("header", 'L', SMFIP_NOHDRS, SMFIP_NR_HDR),
+        ("eoh", 'N', SMFIP_NOEOH, SMFIP_NR_EOH),
+        ("body", 'B', SMFIP_NOBODY, SMFIP_NR_BODY),
+        ("unknown", 'U', SMFIP_NOUNKNOWN, SMFIP_NR_UNKN),
+    ];
+
+    let protocol = negotiated_protocol(ProtocolOptions::new(config, args));
+    let actions = negotiated_actions();
+    let mut lines = vec![format!("protocol flags: 0x{protocol:08x}")];
+    for (name, cmd, no_flag, nr_flag) in STAGES {
+        let state = if protocol & no_flag != 0 {
+            "not sent"
+        } else if protocol & nr_flag != 0 {
+            "received, no reply"
+        } else {
+            "received, reply"
+        };
+        lines.push(format!("  {name:<10} ({cmd})  {state}"));
+    }
+    lines.push(format!("  {:<10} (E)  received, reply", "eom"));
+    if protocol & SMFIP_NOBODY == 0 {
+        if args.truncate == usize::MAX {
+            lines.push("  body is received in full".to_string());
+        } else if protocol & SMFIP_SKIP != 0 {
+            lines.push(format!(
+                "  body chunks after {} bytes are skipped",
+                args.truncate
+            ));
+        }
+    }
+    if protocol & SMFIP_HDR_LEADSPC != 0 {
+        lines.push("  header values keep their leading whitespace".to_string());
+    }
+    if protocol & SMFIP_RCPT_REJ != 0 {
+        lines.push("  recipients rejected by Postfix are received".to_string());
+    }
+    lines.push(format!("action flags: 0x{actions:08x}"));
+    for name in action_names(actions) {
+        lines.push(format!("  {name}"));
+    }
+    lines
+}
+
+#[cfg(feature = "daemon")]
+fn cmd_explain_negotiation(config: &Config, args: &DaemonArgs) -> Result<(), Box<dyn Error>> {
+    for line in explain_negotiation(config, args) {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "daemon")]
+fn cmd_postfix_snippet(config: &Config, args: &DaemonArgs) -> Result<(), Box<dyn Error>> {
+    let mut address: SocketAddr = args
+        .address
+        .parse()
+        .map_err(|e| format!("{}: {e}", args.address))?;
+    if address.ip().is_unspecified() {
+        // listening on all addresses, Postfix connects via loopback
+        address.set_ip(match address {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let mut mail_macros = String::from(
+        "i {auth_type} {auth_authen} {auth_author} {mail_addr} {mail_host} {mail_mailer}\n    \
+         {tls_version} {cipher} {cert_subject} {cert_issuer}",
+    );
+    if !config.trusted_networks.is_empty() || config.trusted_client.is_some() {
+        // for the trusted client fast path
+        mail_macros.push_str(" {client_addr}");
+    }
+    // a recipient validator may take several network timeouts per RCPT
+    let command_timeout = if config.recipient_validator.is_some() {
+        60
+    } else {
+        30
+    };
+    println!("# srmilter {} at {address}", env!("CARGO_PKG_VERSION"));
+    println!("smtpd_milters = inet:{address}");
+    println!("milter_protocol = 6");
+    println!("milter_default_action = tempfail");
+    println!("milter_connect_timeout = 30s");
+    println!("milter_command_timeout = {command_timeout}s");
+    println!("milter_content_timeout = 300s");
+    println!("milter_mail_macros = {mail_macros}");
+    if args.rcpt_rej || config.recipient_validator.is_some() {
+        println!("milter_rcpt_macros = i {{rcpt_addr}} {{rcpt_host}} {{rcpt_mailer}}");
+    }
+    println!("milter_end_of_data_macros = i");
+    Ok(())
+}
+
 #[derive(clap::Parser)]
 #[command()]
 struct Cli {
@@ -79,8 +441,68 @@ struct Cli {
     command: Command,
 }
 
+// Parses a rate given as `N` or `N/s`.
+#[cfg(feature = "daemon")]
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s
+        .strip_suffix("/s")
+        .unwrap_or(s)
+        .parse()
+        .map_err(|e| format!("{e}"))?;
+    if rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err("rate must be positive".to_string())
+    }
+}
+
+#[cfg(feature = "daemon")]
+#[derive(clap::Args, Debug)]
+pub(crate) struct SimulateArgs {
+    pub input: PathBuf,
+    #[arg(long = "fork", default_value_t = 0, hide_default_value = true)]
+    pub fork_max: u16,
+    #[arg(long = "threads", default_value_t = 0, hide_default_value = true)]
+    pub threads_max: u16,
+    #[arg(long = "rate", value_name = "N/s", value_parser = parse_rate)]
+    pub rate: Option<f64>,
+}
+
+#[cfg(feature = "daemon")]
+#[derive(clap::Args, Debug, Clone)]
+pub(crate) struct LoadgenArgs {
+    #[arg(long = "target", default_value = "127.0.0.1:7044")]
+    pub target: String,
+    #[arg(long = "concurrency", default_value_t = 1)]
+    pub concurrency: u16,
+    #[arg(long = "mbox", value_name = "DIR-OR-MBOX")]
+    pub mbox: PathBuf,
+    #[arg(long = "rate", value_name = "N/s", value_parser = parse_rate)]
+    pub rate: Option<f64>,
+    #[arg(long = "sender", default_value = "loadgen@localhost")]
+    pub sender: String,
+    #[arg(long = "recipient", default_value = "postmaster@localhost")]
+    pub recipients: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ScoreArgs {
+    /// Directory of messages, or `-` for a single message from stdin
+    #[arg(long = "input", value_name = "DIR")]
+    input: PathBuf,
+    #[arg(long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Output format, default `json` if the output file name ends with `.json`, else `csv`
+    #[arg(long = "format", value_parser = ["csv", "json"])]
+    format: Option<String>,
+    /// Don't look up the IPs of `Received:` headers in Spamhaus ZEN (no `dnsbl_hits`)
+    #[arg(long = "no-dns")]
+    no_dns: bool,
+}
+
 #[derive(clap::Args, Debug)]
 struct DumpArgs {
+    /// Message file, or `-` for stdin
     filename: PathBuf,
     #[arg(short = 'H', long)]
     header: bool,
@@ -90,38 +512,352 @@ struct DumpArgs {
     dump_html: bool,
 }
 
-#[derive(clap::Args, Debug)]
-pub(crate) struct DaemonArgs {
+/// Concurrency model of the daemon.
+///
+/// Parsed from `auto`, `single`, `threads(N)`, `fork(N)` and `prefork(N)`; `threads:N`,
+/// `fork:N` and `prefork:N` are accepted as well, which need no quoting in a shell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Concurrency {
+    /// Threads, as many as the machine has CPUs (default).
+    Auto,
+    /// Single-threaded, one connection after the other.
+    Single,
+    /// Up to N connections in threads.
+    Threads(u16),
+    /// Up to N connections in child processes, forked for each connection, see
+    /// [`ConfigBuilder::enable_fork_mode()`](crate::ConfigBuilder::enable_fork_mode).
+    Fork(u16),
+    /// N child processes, forked at startup, each serving one connection at a time. Saves
+    /// the fork per connection; like [`Fork`](Self::Fork), it requires
+    /// [`ConfigBuilder::enable_fork_mode()`](crate::ConfigBuilder::enable_fork_mode).
+    Prefork(u16),
+}
+
+impl std::str::FromStr for Concurrency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("invalid concurrency: {s} (auto, single, threads(N), fork(N), prefork(N))");
+        let (mode, n) = if let Some((mode, n)) = s.split_once('(') {
+            (mode, Some(n.strip_suffix(')').ok_or_else(invalid)?))
+        } else if let Some((mode, n)) = s.split_once(':') {
+            (mode, Some(n))
+        } else {
+            (s, None)
+        };
+        let n = n
+            .map(|n| n.parse::<u16>().map_err(|_| invalid()))
+            .transpose()?;
+        match (mode, n) {
+            ("auto", None) => Ok(Concurrency::Auto),
+            ("single", None) => Ok(Concurrency::Single),
+            ("threads", Some(n)) => Ok(Concurrency::Threads(n)),
+            ("fork", Some(n)) => Ok(Concurrency::Fork(n)),
+            ("prefork", Some(n)) => Ok(Concurrency::Prefork(n)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for Concurrency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Concurrency::Auto => write!(f, "auto"),
+            Concurrency::Single => write!(f, "single"),
+            Concurrency::Threads(n) => write!(f, "threads({n})"),
+            Concurrency::Fork(n) => write!(f, "fork({n})"),
+            Concurrency::Prefork(n) => write!(f, "prefork({n})"),
+        }
+    }
+}
+
+/// Options of the `daemon` command.
+///
+/// Applications with their own command line parser create them with
+/// [`DaemonArgs::builder()`] and run the daemon with [`daemon()`].
+#[cfg(feature = "daemon")]
+#[derive(clap::Args, Debug, Clone)]
+#[command(about = None, long_about = None)]
+pub struct DaemonArgs {
     #[arg(default_value = "0.0.0.0:7044")]
-    pub address: String,
+    pub(crate) address: String,
+    /// Concurrency model: auto (default), single, threads(N), fork(N) or prefork(N)
+    #[arg(long = "concurrency", value_name = "MODE", conflicts_with_all = ["fork_max", "threads_max"])]
+    pub(crate) concurrency: Option<Concurrency>,
+    /// Same as --concurrency fork(N)
     #[arg(long = "fork", default_value_t = 0, hide_default_value = true)]
-    pub fork_max: u16,
+    pub(crate) fork_max: u16,
+    /// Same as --concurrency threads(N)
     #[arg(long = "threads", default_value_t = 0, hide_default_value = true)]
-    pub threads_max: u16,
+    pub(crate) threads_max: u16,
     #[arg(long = "truncate", default_value_t = usize::MAX, hide_default_value = true, value_name = "BYTES")]
-    pub truncate: usize,
+    pub(crate) truncate: usize,
+    #[arg(long = "header-leadspc")]
+    pub(crate) header_leadspc: bool,
+    #[arg(long = "rcpt-rej")]
+    pub(crate) rcpt_rej: bool,
+    #[arg(long = "health-listen", value_name = "ADDRESS")]
+    pub(crate) health_listen: Option<String>,
+    /// Limit the address space of each child (fork mode only, threads are not protected)
+    #[arg(long = "rlimit-as", value_name = "BYTES")]
+    pub(crate) rlimit_as: Option<u64>,
+    /// Limit the CPU time of each child (fork mode only, threads are not protected)
+    #[arg(long = "rlimit-cpu", value_name = "SECONDS")]
+    pub(crate) rlimit_cpu: Option<u64>,
+    /// Write a trace of connections failing with a protocol error or classifier panic to DIR
+    #[arg(long = "trace-dir", value_name = "DIR")]
+    pub(crate) trace_dir: Option<PathBuf>,
+}
+
+#[cfg(feature = "daemon")]
+impl DaemonArgs {
+    /// Creates a [`DaemonArgsBuilder`] with the defaults of the `daemon` command.
+    pub fn builder() -> DaemonArgsBuilder {
+        DaemonArgsBuilder {
+            args: DaemonArgs::default(),
+        }
+    }
+
+    /// Returns the concurrency model: from `--concurrency`, `--fork` or `--threads` if given,
+    /// otherwise the one set with
+    /// [`ConfigBuilder::concurrency()`](crate::ConfigBuilder::concurrency).
+    pub fn concurrency(&self, config: &Config) -> Concurrency {
+        match self.concurrency {
+            Some(concurrency) => concurrency,
+            None if self.fork_max > 0 => Concurrency::Fork(self.fork_max),
+            None if self.threads_max > 0 => Concurrency::Threads(self.threads_max),
+            None => config.concurrency.unwrap_or(Concurrency::Auto),
+        }
+    }
+
+    // Checks for combinations of options which can not be used together.
+    pub(crate) fn check(&self, config: &Config) -> Result<(), Box<dyn Error>> {
+        if self.fork_max > 0 && self.threads_max > 0 {
+            return Err("--fork and --threads are mutually exclusive".into());
+        }
+        let concurrency = self.concurrency(config);
+        match concurrency {
+            Concurrency::Threads(0) | Concurrency::Fork(0) | Concurrency::Prefork(0) => {
+                return Err(format!("invalid concurrency: {concurrency}").into());
+            }
+            Concurrency::Fork(_) | Concurrency::Prefork(_) if !config.fork_mode_enabled => {
+                return Err(format!(
+                    "--concurrency {concurrency} not available: fork mode needs to be enabled \
+                     by the milter program"
+                )
+                .into());
+            }
+            _ => (),
+        }
+        if !matches!(concurrency, Concurrency::Fork(_))
+            && (self.rlimit_as.is_some() || self.rlimit_cpu.is_some())
+        {
+            return Err("--rlimit-as and --rlimit-cpu require --concurrency fork(N)".into());
+        }
+        Ok(())
+    }
+
+    // Returns the options with the concurrency model in fork_max and threads_max, as used
+    // by the daemon. Prefork mode is only kept in `concurrency`.
+    pub(crate) fn resolved(&self, config: &Config) -> DaemonArgs {
+        let concurrency = match self.concurrency(config) {
+            Concurrency::Auto => {
+                let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+                Concurrency::Threads(u16::try_from(cpus).unwrap_or(u16::MAX))
+            }
+            concurrency => concurrency,
+        };
+        let (fork_max, threads_max) = match concurrency {
+            Concurrency::Threads(n) => (0, n),
+            Concurrency::Fork(n) => (n, 0),
+            _ => (0, 0),
+        };
+        DaemonArgs {
+            concurrency: Some(concurrency),
+            fork_max,
+            threads_max,
+            ..self.clone()
+        }
+    }
+}
+
+/// Builder for [`DaemonArgs`], the programmatic equivalent of the options of the `daemon`
+/// command.
+///
+/// # Example
+///
+/// ```ignore
+/// let args = DaemonArgs::builder()
+///     .address("127.0.0.1:7044")
+///     .threads(16)
+///     .health_listen("127.0.0.1:7045")
+///     .build();
+/// srmilter::cli::daemon(&config, &args)?;
+/// ```
+#[cfg(feature = "daemon")]
+pub struct DaemonArgsBuilder {
+    args: DaemonArgs,
+}
+
+#[cfg(feature = "daemon")]
+impl DaemonArgsBuilder {
+    /// Build the final [`DaemonArgs`]
+    pub fn build(self) -> DaemonArgs {
+        self.args
+    }
+    /// Listens on `address` (default `0.0.0.0:7044`), unless a socket is passed by systemd.
+    pub fn address(mut self, address: &str) -> Self {
+        self.args.address = address.to_string();
+        self
+    }
+    /// Sets the concurrency model (`--concurrency`, default [`Concurrency::Auto`]).
+    pub fn concurrency(mut self, concurrency: Concurrency) -> Self {
+        self.args.concurrency = Some(concurrency);
+        self
+    }
+    /// Forks up to `max` child processes, one per connection, same as
+    /// [`Concurrency::Fork`]. Requires
+    /// [`ConfigBuilder::enable_fork_mode()`](crate::ConfigBuilder::enable_fork_mode).
+    pub fn fork(self, max: u16) -> Self {
+        self.concurrency(Concurrency::Fork(max))
+    }
+    /// Serves up to `max` connections in threads, same as [`Concurrency::Threads`].
+    pub fn threads(self, max: u16) -> Self {
+        self.concurrency(Concurrency::Threads(max))
+    }
+    /// Receives only the first `bytes` bytes of the body (`--truncate`).
+    pub fn truncate(mut self, bytes: usize) -> Self {
+        self.args.truncate = bytes;
+        self
+    }
+    /// Receives header values with their original leading whitespace (`--header-leadspc`).
+    pub fn header_leadspc(mut self, enabled: bool) -> Self {
+        self.args.header_leadspc = enabled;
+        self
+    }
+    /// Receives recipients already rejected by Postfix (`--rcpt-rej`).
+    pub fn rcpt_rej(mut self, enabled: bool) -> Self {
+        self.args.rcpt_rej = enabled;
+        self
+    }
+    /// Answers HTTP health checks on `address` (`--health-listen`).
+    pub fn health_listen(mut self, address: &str) -> Self {
+        self.args.health_listen = Some(address.to_string());
+        self
+    }
+    /// Limits the address space of each fork mode child (`--rlimit-as`).
+    pub fn rlimit_as(mut self, bytes: u64) -> Self {
+        self.args.rlimit_as = Some(bytes);
+        self
+    }
+    /// Limits the CPU time of each fork mode child (`--rlimit-cpu`).
+    pub fn rlimit_cpu(mut self, seconds: u64) -> Self {
+        self.args.rlimit_cpu = Some(seconds);
+        self
+    }
+    /// Writes traces of failed connections to `dir` (`--trace-dir`).
+    pub fn trace_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.args.trace_dir = Some(dir.into());
+        self
+    }
+}
+
+/// Runs the milter daemon like the `daemon` command, with options from `args`.
+///
+/// The options are checked against `config` first, e.g. fork mode must be enabled for
+/// [`fork()`](DaemonArgsBuilder::fork). Returns when the daemon is stopped with SIGTERM or
+/// SIGINT.
+#[cfg(feature = "daemon")]
+pub fn daemon(config: &Config, args: &DaemonArgs) -> Result<(), Box<dyn Error>> {
+    args.check(config)?;
+    run_daemon(config, &args.resolved(config))
+}
+
+#[cfg(feature = "daemon")]
+impl Default for DaemonArgs {
+    // the defaults of the daemon command
+    fn default() -> Self {
+        DaemonArgs {
+            address: "0.0.0.0:7044".to_string(),
+            concurrency: None,
+            fork_max: 0,
+            threads_max: 0,
+            truncate: usize::MAX,
+            header_leadspc: false,
+            rcpt_rej: false,
+            health_listen: None,
+            rlimit_as: None,
+            rlimit_cpu: None,
+            trace_dir: None,
+        }
+    }
 }
 
 #[derive(clap::Subcommand)]
 enum Command {
     Test {
+        /// Message file, or `-` for stdin
         filename: PathBuf,
         sender: Option<String>,
         recipients: Option<Vec<String>>,
+        /// Print nothing, only set the exit status
+        #[arg(short, long)]
+        quiet: bool,
+        /// Print an explanation of the decision with everything logged for the message
+        #[arg(long, conflicts_with = "quiet")]
+        explain: bool,
     },
+    #[cfg(feature = "daemon")]
     Daemon(DaemonArgs),
-    Simulate(DaemonArgs),
+    #[cfg(feature = "daemon")]
+    Simulate(SimulateArgs),
     Dump(DumpArgs),
+    Score(ScoreArgs),
+    #[cfg(feature = "daemon")]
+    Loadgen(LoadgenArgs),
+    #[cfg(feature = "daemon")]
+    ExplainNegotiation(DaemonArgs),
+    #[cfg(feature = "daemon")]
+    PostfixSnippet(DaemonArgs),
+    BypassToken {
+        recipient: String,
+        /// Site key, as secret reference (`file:PATH`, `env:NAME`, `credential:NAME`)
+        #[arg(long, value_name = "REF")]
+        key: String,
+        /// Days until the token expires
+        #[arg(long, default_value_t = 1)]
+        days: u32,
+    },
 }
 
 /// Main entry point for the milter CLI.
 ///
 /// Parses command-line arguments and runs the appropriate subcommand:
 ///
-/// - `daemon [address] [--fork N] [--threads N] [--truncate N]` - Run the milter server
-///   (default address: `0.0.0.0:7044`)
-/// - `test <file> [sender] [recipients...]` - Test the classifier against an `.eml` file
-/// - `dump <file> [-H] [-b] [--html]` - Dump parsed email headers and/or body
+/// - `daemon [address] [--concurrency MODE] [--truncate N] [--header-leadspc] [--rcpt-rej]
+///   [--health-listen ADDRESS] [--rlimit-as BYTES] [--rlimit-cpu SECONDS] [--trace-dir DIR]` -
+///   Run the milter server (default address: `0.0.0.0:7044`)
+/// - `test <file> [sender] [recipients...] [--quiet | --explain]` - Test the classifier against
+///   an `.eml` file (`-` for stdin). Prints the verdict and reason and exits with status 0
+///   (accept), 10 (quarantine), 20 (reject) or 30 (tempfail)
+/// - `dump <file> [-H] [-b] [--html]` - Dump parsed email headers and/or body (`-` for stdin)
+/// - `simulate <dir-or-mbox> [--fork N] [--threads N] [--rate N/s]` - Classify messages from
+///   files with the given concurrency model and report throughput and latency
+/// - `loadgen --mbox <dir-or-mbox> [--target host:port] [--concurrency N] [--rate N/s]` -
+///   Replay messages to a running milter and report throughput and latency
+/// - `score --input <dir> [--output <file>] [--format csv|json] [--no-dns]` - Classify all
+///   files in a directory and write a CSV or JSON report with verdict and features per
+///   message: spam score, Spamhaus ZEN hits, SPF/DKIM/DMARC results from
+///   `Authentication-Results:`, bulk and list. `-` reads a single message from stdin
+/// - `explain-negotiation [--truncate N]` - Show which milter stages and actions are negotiated
+/// - `postfix-snippet [address] [--rcpt-rej]` - Print the `main.cf` settings for connecting
+///   Postfix to the daemon with the given arguments
+/// - `bypass-token <recipient> --key <ref> [--days N]` - Print a [`bypass`] token for mail to
+///   `recipient`, with the site key given as [`secrets`] reference
+///
+/// `daemon`, `simulate`, `loadgen`, `explain-negotiation` and `postfix-snippet` are only
+/// available with the feature `daemon` (default, Unix only).
 ///
 /// # Example
 ///
@@ -141,26 +877,20 @@ pub fn cli(config: &Config) -> Result<(), Box<dyn Error>> {
             filename,
             sender,
             recipients,
+            quiet,
+            explain,
         } => cmd_test(
             config,
             &filename,
             sender.unwrap_or_default(),
             recipients.unwrap_or_default(),
+            quiet,
+            explain,
         ),
-        Command::Daemon(args) => {
-            if args.fork_max > 0 && args.threads_max > 0 {
-                return Err("--fork and --threads are mutually exclusive".into());
-            }
-            if args.fork_max > 0 && !config.fork_mode_enabled {
-                return Err(
-                    "--fork mode not available: Needs to be opted in by main milter program."
-                        .into(),
-                );
-            }
-            daemon(config, &args)
-        }
+        #[cfg(feature = "daemon")]
+        Command::Daemon(args) => daemon(config, &args),
+        #[cfg(feature = "daemon")]
         Command::Simulate(args) => {
-            eprintln!("WARNING: simulate is unstable, temprary and only for development");
             if args.fork_max > 0 && args.threads_max > 0 {
                 return Err("--fork and --threads are mutually exclusive".into());
             }
@@ -172,6 +902,161 @@ pub fn cli(config: &Config) -> Result<(), Box<dyn Error>> {
             }
             simulate(config, &args)
         }
-        Command::Dump(dump_args) => cmd_dump(&dump_args),
+        Command::Dump(dump_args) => cmd_dump(config, &dump_args),
+        Command::Score(score_args) => cmd_score(config, &score_args),
+        #[cfg(feature = "daemon")]
+        Command::Loadgen(loadgen_args) => loadgen(&loadgen_args),
+        #[cfg(feature = "daemon")]
+        Command::ExplainNegotiation(args) => cmd_explain_negotiation(config, &args),
+        #[cfg(feature = "daemon")]
+        Command::PostfixSnippet(args) => cmd_postfix_snippet(config, &args),
+        Command::BypassToken {
+            recipient,
+            key,
+            days,
+        } => {
+            let key = secrets::resolve(&key)?;
+            println!(
+                "{}",
+                bypass::sign(&recipient, key.as_bytes(), batv::today(), days)
+            );
+            Ok(())
+        }
     }
 }
+
+#[cfg(feature = "daemon")]
+#[test]
+fn test_daemon_args() {
+    let config = Config::builder().build();
+    let args = DaemonArgs::builder()
+        .address("127.0.0.1:7055")
+        .threads(4)
+        .truncate(1024)
+        .build();
+    assert_eq!(args.address, "127.0.0.1:7055");
+    assert_eq!(args.concurrency(&config), Concurrency::Threads(4));
+    assert_eq!(args.resolved(&config).threads_max, 4);
+    assert_eq!(args.truncate, 1024);
+    assert!(args.check(&config).is_ok());
+    let args = DaemonArgs::builder().build();
+    assert_eq!(args.concurrency(&config), Concurrency::Auto);
+    assert!(args.resolved(&config).threads_max >= 1);
+    // the configured model applies unless one is given on the command line
+    let fork_config = Config::builder()
+        .enable_fork_mode()
+        .concurrency(Concurrency::Prefork(4))
+        .build();
+    assert_eq!(args.concurrency(&fork_config), Concurrency::Prefork(4));
+    assert!(args.check(&fork_config).is_ok());
+    let resolved = args.resolved(&fork_config);
+    assert_eq!((resolved.fork_max, resolved.threads_max), (0, 0));
+    let single = DaemonArgs::builder()
+        .concurrency(Concurrency::Single)
+        .build();
+    assert_eq!(single.concurrency(&fork_config), Concurrency::Single);
+    let prefork_config = Config::builder()
+        .concurrency(Concurrency::Prefork(4))
+        .build();
+    assert!(
+        args.check(&prefork_config)
+            .unwrap_err()
+            .to_string()
+            .starts_with("--concurrency prefork(4) not available")
+    );
+    assert!(
+        DaemonArgs::builder()
+            .threads(0)
+            .build()
+            .check(&config)
+            .is_err()
+    );
+    let args = DaemonArgs::builder().fork(2).build();
+    assert!(args.check(&config).is_err());
+    assert!(
+        args.check(&Config::builder().enable_fork_mode().build())
+            .is_ok()
+    );
+    assert!(
+        DaemonArgs::builder()
+            .rlimit_cpu(10)
+            .build()
+            .check(&config)
+            .is_err()
+    );
+
+    assert_eq!("auto".parse(), Ok(Concurrency::Auto));
+    assert_eq!("single".parse(), Ok(Concurrency::Single));
+    assert_eq!("threads(8)".parse(), Ok(Concurrency::Threads(8)));
+    assert_eq!("fork:4".parse(), Ok(Concurrency::Fork(4)));
+    assert_eq!("prefork(2)".parse(), Ok(Concurrency::Prefork(2)));
+    for invalid in [
+        "threads",
+        "threads(8",
+        "threads:8)",
+        "threads(8))",
+        "fork()",
+        "single(1)",
+    ] {
+        assert!(invalid.parse::<Concurrency>().is_err(), "{invalid}");
+    }
+    assert_eq!(Concurrency::Fork(4).to_string(), "fork(4)");
+}
+
+#[cfg(feature = "daemon")]
+#[test]
+fn test_explain_negotiation() {
+    let config = Config::builder().build();
+    let lines = explain_negotiation(&config, &DaemonArgs::builder().build());
+    assert!(
+        lines
+            .iter()
+            .any(|line| line == "  body is received in full")
+    );
+    assert!(!lines.iter().any(|line| line.contains("skipped")));
+    let lines = explain_negotiation(&config, &DaemonArgs::builder().truncate(1000).build());
+    assert!(
+        lines
+            .iter()
+            .any(|line| line == "  body chunks after 1000 bytes are skipped")
+    );
+}
+
+#[test]
+fn test_score_report() {
+    assert_eq!(json_string("a\"b\\c\n\x01ä"), r#""a\"b\\c\n\u0001ä""#);
+    let value = "mx.example.org; spf=pass smtp.mailfrom=example.com; DKIM=fail (bad signature) \
+                 header.d=example.com; dmarc=none";
+    assert_eq!(auth_result(value, "spf"), Some("pass"));
+    assert_eq!(auth_result(value, "dkim"), Some("fail"));
+    assert_eq!(auth_result(value, "dmarc"), Some("none"));
+    assert_eq!(auth_result("spf=pass", "spf"), None);
+
+    let dir = std::env::temp_dir().join(format!("srmilter-score-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("m1");
+    fs::write(
+        &file,
+        format!(
+            "Authentication-Results: {value}\r\nX-Spam-Score: 5.5\r\nSubject: hi\r\n\r\nText\r\n"
+        ),
+    )
+    .unwrap();
+    let config = Config::builder().build();
+    let mut out = Vec::new();
+    write_score_json(&mut out, &score_row(&config, &file, false)).unwrap();
+    let expected = format!(
+        r#"{{"file":{},"verdict":"ACCEPT","spam_score":5.5,"dnsbl_hits":null,"spf":"pass","dkim":"fail","dmarc":"none","bulk":false,"list":false,"from":"","subject":"hi"}}"#,
+        json_string(&file.to_string_lossy())
+    );
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+    // an unreadable file does not stop the report
+    let missing = dir.join("missing");
+    let mut out = Vec::new();
+    write_score_csv(&mut out, &score_row(&config, &missing, false)).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        format!("{},error,,,,,,,,,\n", missing.display())
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
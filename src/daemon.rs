@@ -1,86 +1,274 @@
-use crate::cli::DaemonArgs;
+use crate::cli::{Concurrency, DaemonArgs};
+use crate::milter::PacketBuffer;
 use crate::milter::constants::*;
 use crate::reader_extention::{BufReadExt as _, ReadExt as _};
-use crate::{ClassifyResult, Config, MailInfoStorage, classify_mail};
+use crate::recipient::RecipientStatus;
+use crate::trace::{TraceReader, write_trace};
+use crate::{
+    ClassifyResult, Config, Decision, MailInfoStorage, batv_signed_sender, classify_mail,
+    panic_message, trusted_client_reason,
+};
 use nix::libc::c_int;
-use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::resource::{Resource, setrlimit};
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, kill, sigaction};
 use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
 use nix::unistd::{ForkResult, Pid, fork, pause};
 use socket2::{Domain, Protocol, Socket, Type};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::io::{BufRead, BufReader, BufWriter, Cursor, Read as _, Seek as _, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsFd as _;
 #[cfg(feature = "systemd")]
 use std::os::fd::FromRawFd as _;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::Path;
 use std::process::exit;
-use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 // https://codeberg.org/glts/indymilter
 // https://www.postfix.org/MILTER_README.html
 // https://github.com/emersion/go-milter/blob/master/milter-protocol.txt
 // https://github.com/emersion/go-milter/blob/master/milter-protocol-extras.txt
 
-static FLAG_SHUTDOWN: AtomicBool = AtomicBool::new(false);
-static CHILDREN_CNT: AtomicU16 = AtomicU16::new(0);
+// set by the SIGTERM and SIGINT handlers, see DaemonRuntime::install_signal_handlers()
+static SHUTDOWN_SIGNALED: AtomicBool = AtomicBool::new(false);
+const CRASH_BUDGET: u32 = 10;
+// log verbosity, raised with SIGUSR1 and lowered with SIGUSR2:
+// 1 logs the envelope of every message, 2 additionally traces milter commands
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+const VERBOSITY_MAX: u8 = 2;
+// 65536+4096 bc. postfix milter8.c : #define MILTER_CHUNK_SIZE 65535 /* body chunk size */
+const PACKET_MAX: u32 = 69632;
+// macros Postfix sends for each recipient
+const RCPT_MACROS: [&str; 3] = ["{rcpt_addr}", "{rcpt_host}", "{rcpt_mailer}"];
+// oversized packets skipped by this process, reported by the health endpoint; connections
+// served by fork(N) children and prefork(N) workers are not counted by the parent
+static OVERSIZED_PACKETS: AtomicU32 = AtomicU32::new(0);
+// how often waiting for connections, data and children is interrupted to check for shutdown
+const SHUTDOWN_POLL: Duration = Duration::from_secs(1);
+
+/// Protocol options from the command line and configuration, copied into every connection.
+#[derive(Clone, Copy)]
+pub(crate) struct ProtocolOptions {
+    /// Number of body bytes to receive (`--truncate`).
+    pub truncate: usize,
+    /// Receive header values with their original leading whitespace (`--header-leadspc`).
+    pub header_leadspc: bool,
+    /// Receive recipients already rejected by Postfix (`--rcpt-rej`).
+    pub rcpt_rej: bool,
+    /// Reply to RCPT, set if a recipient validator is configured.
+    pub rcpt_reply: bool,
+    /// Apply reply delays requested by the classifier, set unless single-threaded.
+    pub tarpit: bool,
+}
+
+impl ProtocolOptions {
+    pub(crate) fn new(config: &Config, args: &DaemonArgs) -> Self {
+        ProtocolOptions {
+            truncate: args.truncate,
+            header_leadspc: args.header_leadspc,
+            rcpt_rej: args.rcpt_rej,
+            rcpt_reply: config.recipient_validator.is_some(),
+            tarpit: args.concurrency(config) != Concurrency::Single,
+        }
+    }
+}
+
+/// Action flags (SMFIF_*) advertised in the option negotiation reply.
+pub(crate) fn negotiated_actions() -> u32 {
+    SMFIF_ADDHDRS | SMFIF_ADDRCPT | SMFIF_CHGHDRS | SMFIF_QUARANTINE | SMFIF_CHGFROM
+}
+
+/// Returns the names of the action flags set in `actions`.
+pub(crate) fn action_names(actions: u32) -> Vec<&'static str> {
+    const ACTIONS: [(&str, u32); 9] = [
+        ("add header", SMFIF_ADDHDRS),
+        ("change body", SMFIF_CHGBODY),
+        ("add recipient", SMFIF_ADDRCPT),
+        ("delete recipient", SMFIF_DELRCPT),
+        ("change header", SMFIF_CHGHDRS),
+        ("quarantine", SMFIF_QUARANTINE),
+        ("change from", SMFIF_CHGFROM),
+        ("add recipient with args", SMFIF_ADDRCPT_PAR),
+        ("set macro list", SMFIF_SETSYMLIST),
+    ];
+    ACTIONS
+        .iter()
+        .filter(|(_, flag)| actions & flag != 0)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Protocol flags (SMFIP_*) advertised in the option negotiation reply.
+///
+/// These decide which stages the MTA sends to us and which of them expect a reply.
+pub(crate) fn negotiated_protocol(options: ProtocolOptions) -> u32 {
+    let mut protocol = SMFIP_NOCONNECT
+        | SMFIP_NOHELO
+        | SMFIP_NR_HDR
+        | SMFIP_NOUNKNOWN
+        | SMFIP_NODATA
+        | SMFIP_SKIP
+        | SMFIP_NR_CONN
+        | SMFIP_NR_MAIL
+        | SMFIP_NR_EOH;
+    if !options.rcpt_reply {
+        protocol |= SMFIP_NR_RCPT
+    }
+    if options.truncate == 0 {
+        protocol |= SMFIP_NOBODY
+    }
+    if options.truncate == usize::MAX {
+        protocol |= SMFIP_NR_BODY
+    }
+    if options.header_leadspc {
+        protocol |= SMFIP_HDR_LEADSPC
+    }
+    if options.rcpt_rej {
+        protocol |= SMFIP_RCPT_REJ
+    }
+    protocol
+}
+
+/// Appends `data` to `buffer`, converting bare LF line endings (used by Postfix in folded
+/// header values) to CRLF, as in the message body.
+pub(crate) fn extend_crlf(buffer: &mut Vec<u8>, data: &[u8]) {
+    let mut prev = 0u8;
+    for &c in data {
+        if c == b'\n' && prev != b'\r' {
+            buffer.push(b'\r');
+        }
+        buffer.push(c);
+        prev = c;
+    }
+}
+
+/// Reads the ESMTP parameters following the address of a MAIL or RCPT command.
+fn read_esmtp_parameters(
+    reader: &mut impl BufRead,
+    buffer: &mut Vec<u8>,
+    parameters: &mut Vec<String>,
+) -> std::io::Result<()> {
+    while !reader.fill_buf()?.is_empty() {
+        let parameter = reader.read_zstring(buffer)?;
+        if !parameter.is_empty() {
+            parameters.push(parameter);
+        }
+    }
+    Ok(())
+}
+
+fn add_recipient(
+    storage: &mut MailInfoStorage,
+    recipient: String,
+    reader: &mut impl BufRead,
+    buffer: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    storage.recipients.push(recipient);
+    let mut parameters = Vec::new();
+    read_esmtp_parameters(reader, buffer, &mut parameters)?;
+    storage.recipient_parameters.push(parameters);
+    Ok(())
+}
+
+/// Returns the client address from the macro `{client_addr}` or from `_`
+/// (`name [address]`), looked up in `macros` first.
+fn client_ip(
+    macros: &HashMap<String, String>,
+    connect_macros: &HashMap<String, String>,
+) -> Option<IpAddr> {
+    let lookup = |name: &str| macros.get(name).or_else(|| connect_macros.get(name));
+    if let Some(addr) = lookup("{client_addr}") {
+        return addr.parse().ok();
+    }
+    let addr = lookup("_")?.rsplit_once('[')?.1.strip_suffix(']')?;
+    addr.trim_start_matches("IPv6:").parse().ok()
+}
 
 fn process_client(
     config: &Config,
     mut stream_reader: impl BufRead,
     mut stream_writer: impl Write,
-    truncate: usize,
+    options: ProtocolOptions,
 ) -> Result<(), Box<dyn Error>> {
+    let truncate = options.truncate;
     let mut data_read_buffer: Vec<u8> = Vec::with_capacity(4096);
-    let data_write_buffer: Vec<u8> = Vec::with_capacity(64);
-    let mut writer = Cursor::new(data_write_buffer);
+    let mut replies = PacketBuffer::default();
 
     let mut connect_macros: HashMap<String, String> = HashMap::new();
     let mut storage = MailInfoStorage::default();
 
     let mut string_buffer = Vec::<u8>::new();
+    // an oversized packet of the current message was skipped
+    let mut oversized = false;
+    // the message is accepted without classification for this reason
+    let mut trusted: Option<String> = None;
 
     loop {
+        if stream_reader.fill_buf()?.is_empty() {
+            // connection closed between commands, e.g. by a monitoring probe
+            break;
+        }
         let len = stream_reader.read_u32_be()?;
-        if len > 69632 {
-            // 65536+4096 bc. postfix milter8.c : #define MILTER_CHUNK_SIZE 65535 /* body chunk size */
-            return Err("received line to long (len} > 69632".into());
+        if len > PACKET_MAX {
+            let cmd = stream_reader.read_char()?;
+            if matches!(cmd, 'O' | 'C' | 'H') {
+                return Err(
+                    format!("received packet '{cmd}' too long ({len} > {PACKET_MAX})").into(),
+                );
+            }
+            // drain the packet, so that the connection stays usable, and tempfail the message
+            io::copy(
+                &mut (&mut stream_reader).take(u64::from(len) - 1),
+                &mut io::sink(),
+            )?;
+            OVERSIZED_PACKETS.fetch_add(1, Ordering::Relaxed);
+            let queue_id = storage.macros.get("i").map(String::as_str).unwrap_or("-");
+            eprintln!("{queue_id}: skipped oversized milter packet '{cmd}' ({len} bytes)");
+            let reply_expected = match cmd {
+                'R' => options.rcpt_reply,
+                'B' => truncate != usize::MAX,
+                'E' => true,
+                _ => false,
+            };
+            if !reply_expected {
+                // tempfail at end of message
+                oversized = true;
+                continue;
+            }
+            replies.push(b't', b""); // SMFIR_TEMPFAIL
+            replies.send(&mut stream_writer)?;
+            if cmd != 'R' {
+                eprintln!("{queue_id}: TEMPFAIL (oversized milter packet)");
+                storage.clear();
+                oversized = false;
+                trusted = None;
+            }
+            continue;
         }
         stream_reader.read_bytes(len as usize, &mut data_read_buffer)?;
         let mut data_reader = Cursor::new(data_read_buffer);
         let cmd = data_reader.read_char()?;
+        if VERBOSITY.load(Ordering::Relaxed) >= 2 {
+            eprintln!("milter: command '{cmd}' ({len} bytes)");
+        }
         match cmd {
             'O' => {
                 // ignored:
                 // let version = data_reader.read_u32_be()?;
                 // let actions = data_reader.read_u32_be()?;
                 // let protocol = data_reader.read_u32_be()?;
-                writer.rewind()?;
-                writer.write_all(b"O")?;
-                writer.write_all(&SMFIF_VERSION.to_be_bytes())?;
-                writer.write_all(&SMFIF_QUARANTINE.to_be_bytes())?;
-                let mut protocol = SMFIP_NOCONNECT
-                    | SMFIP_NOHELO
-                    | SMFIP_NR_HDR
-                    | SMFIP_NOUNKNOWN
-                    | SMFIP_NODATA
-                    | SMFIP_SKIP
-                    | SMFIP_NR_CONN
-                    | SMFIP_NR_MAIL
-                    | SMFIP_NR_RCPT
-                    | SMFIP_NR_EOH;
-                if truncate == 0 {
-                    protocol |= SMFIP_NOBODY
-                }
-                if truncate == usize::MAX {
-                    protocol |= SMFIP_NR_BODY
-                }
-                writer.write_all(&protocol.to_be_bytes())?;
-                stream_writer.write_all(&((writer.position() as u32).to_be_bytes()))?;
-                stream_writer.write_all(&writer.get_ref()[0..writer.position() as usize])?;
-                stream_writer.flush()?;
+                let mut payload = [0u8; 12];
+                payload[0..4].copy_from_slice(&SMFIF_VERSION.to_be_bytes());
+                payload[4..8].copy_from_slice(&negotiated_actions().to_be_bytes());
+                payload[8..12].copy_from_slice(&negotiated_protocol(options).to_be_bytes());
+                replies.push(b'O', &payload);
+                replies.send(&mut stream_writer)?;
             }
             'D' => {
                 let for_cmd = data_reader.read_char()?;
@@ -99,24 +287,91 @@ fn process_client(
                 // no reply to SMIC_MACRO
             }
             'M' => {
+                storage.received_at = Some(Instant::now());
                 storage.sender = data_reader.read_zstring_anglestripped(&mut string_buffer)?;
-                // possibly followed by more strings (ESMPT arguments)
+                trusted =
+                    trusted_client_reason(config, client_ip(&storage.macros, &connect_macros));
+                read_esmtp_parameters(
+                    &mut data_reader,
+                    &mut string_buffer,
+                    &mut storage.mail_parameters,
+                )?;
                 // reply disabled with SMFIP_NR_MAIL
             }
             'R' => {
-                storage
-                    .recipients
-                    .push(data_reader.read_zstring_anglestripped(&mut string_buffer)?);
-                // reply disabled with SMFIP_NR_RCPT
+                let recipient = data_reader.read_zstring_anglestripped(&mut string_buffer)?;
+                // with SMFIP_RCPT_REJ, Postfix also sends rejected recipients, marked with the
+                // rcpt_mailer "error"
+                if storage.macros.get("{rcpt_mailer}").map(String::as_str) == Some("error") {
+                    storage.rejected_recipients.push(recipient);
+                    if options.rcpt_reply {
+                        replies.push(b'c', b""); // SMFIR_CONTINUE
+                    }
+                } else if let Some(ref validator) = config.recipient_validator {
+                    let queue_id = storage.macros.get("i").map(String::as_str).unwrap_or("");
+                    let reply: &[u8] = match validator(&recipient) {
+                        RecipientStatus::Valid => b"",
+                        RecipientStatus::Unknown => {
+                            eprintln!("{queue_id}: REJECT recipient {recipient} (unknown)");
+                            b"550 5.1.1 Recipient address rejected: User unknown\0"
+                        }
+                        RecipientStatus::Unavailable => {
+                            eprintln!("{queue_id}: TEMPFAIL recipient {recipient} (unavailable)");
+                            b"451 4.3.0 Recipient address verification unavailable\0"
+                        }
+                    };
+                    if reply.is_empty() {
+                        add_recipient(
+                            &mut storage,
+                            recipient,
+                            &mut data_reader,
+                            &mut string_buffer,
+                        )?;
+                        replies.push(b'c', b""); // SMFIR_CONTINUE
+                    } else {
+                        storage.rejected_recipients.push(recipient);
+                        replies.push(b'y', reply); // SMFIR_REPLYCODE
+                    }
+                } else {
+                    add_recipient(
+                        &mut storage,
+                        recipient,
+                        &mut data_reader,
+                        &mut string_buffer,
+                    )?;
+                }
+                if options.rcpt_reply {
+                    replies.send(&mut stream_writer)?;
+                }
+                // otherwise reply disabled with SMFIP_NR_RCPT
+                // they describe this recipient only, the next one may come without macros
+                for name in RCPT_MACROS {
+                    storage.macros.remove(name);
+                }
+            }
+            'L' | 'N' if trusted.is_some() => {
+                // not buffered, reply disabled with SMFIP_NR_HDR and SMFIP_NR_EOH
+            }
+            'B' if trusted.is_some() => {
+                if truncate != usize::MAX {
+                    replies.push(b's', b""); // SMFIR_SKIP
+                    replies.send(&mut stream_writer)?;
+                }
             }
             'L' => {
                 storage
                     .mail_buffer
                     .extend_from_slice(data_reader.read_zbytes(&mut string_buffer)?);
-                storage.mail_buffer.extend_from_slice(b": ");
-                storage
-                    .mail_buffer
-                    .extend_from_slice(data_reader.read_zbytes(&mut string_buffer)?);
+                if options.header_leadspc {
+                    // value includes the original whitespace after the colon
+                    storage.mail_buffer.push(b':');
+                } else {
+                    storage.mail_buffer.extend_from_slice(b": ");
+                }
+                extend_crlf(
+                    &mut storage.mail_buffer,
+                    data_reader.read_zbytes(&mut string_buffer)?,
+                );
                 storage.mail_buffer.extend_from_slice(b"\r\n");
                 // reply disabled with SMFIP_NR_HDR
             }
@@ -125,7 +380,8 @@ fn process_client(
                 // reply disabled with SMFIP_NR_EOH
             }
             'B' => {
-                let buffer_space = truncate - storage.mail_buffer.len();
+                // the header alone may exceed the limit
+                let buffer_space = truncate.saturating_sub(storage.mail_buffer.len());
                 let pos = data_reader.position() as usize;
                 let data = &data_reader.get_ref()[pos..];
                 if data.len() <= buffer_space {
@@ -139,23 +395,48 @@ fn process_client(
                     // reply disabled with SMFIP_NR_BODY
                 } else {
                     if storage.mail_buffer.len() < truncate {
-                        // continue
-                        writer.rewind()?;
-                        writer.write_all(b"c")?; // SMFIR_CONTINUE
-                        stream_writer.write_all(&((writer.position() as u32).to_be_bytes()))?;
-                        stream_writer
-                            .write_all(&writer.get_ref()[0..writer.position() as usize])?;
+                        replies.push(b'c', b""); // SMFIR_CONTINUE
                     } else {
-                        // skip
-                        writer.rewind()?;
-                        writer.write_all(b"s")?; // SMFIR_SKIP
-                        stream_writer.write_all(&((writer.position() as u32).to_be_bytes()))?;
-                        stream_writer
-                            .write_all(&writer.get_ref()[0..writer.position() as usize])?;
+                        replies.push(b's', b""); // SMFIR_SKIP
                     }
-                    stream_writer.flush()?;
+                    replies.send(&mut stream_writer)?;
                 }
             }
+            // also for trusted clients, the content is incomplete
+            'E' if oversized => {
+                let queue_id = storage.macros.get("i").map(String::as_str).unwrap_or("-");
+                eprintln!("{queue_id}: TEMPFAIL (oversized milter packet)");
+                replies.push(b't', b""); // SMFIR_TEMPFAIL
+                replies.send(&mut stream_writer)?;
+                storage.clear();
+                oversized = false;
+                trusted = None;
+            }
+            'E' if trusted.is_some() => {
+                let reason = trusted.take().unwrap_or_default();
+                for (key, value) in &connect_macros {
+                    storage.macros.insert(key.clone(), value.clone());
+                }
+                let queue_id = storage.macros.get("i").map(String::as_str).unwrap_or("-");
+                eprintln!("{queue_id}: ACCEPT ({reason})");
+                if let Some(sender) = batv_signed_sender(config, &storage.sender) {
+                    eprintln!("{queue_id}: changing sender to <{sender}>");
+                    replies.push(b'e', format!("<{sender}>\0").as_bytes()); // SMFIR_CHGFROM
+                }
+                replies.push(b'a', b""); // SMFIR_ACCEPT
+                replies.send(&mut stream_writer)?;
+                if let Some(ref callback) = config.decision_callback {
+                    callback(&Decision {
+                        queue_id,
+                        sender: &storage.sender,
+                        recipients: &storage.recipients,
+                        result: ClassifyResult::Accept,
+                        reason: &reason,
+                        log: &[],
+                    });
+                }
+                storage.clear();
+            }
             'E' => {
                 for (key, value) in &connect_macros {
                     storage.macros.insert(key.clone(), value.clone());
@@ -166,44 +447,88 @@ fn process_client(
                     .map(AsRef::as_ref)
                     .unwrap_or("-")
                     .to_string();
-                let result = classify_mail(config, &storage);
-                match result {
+                if VERBOSITY.load(Ordering::Relaxed) >= 1 {
+                    eprintln!(
+                        "{}: from=<{}> to={:?} size={}",
+                        storage.id,
+                        storage.sender,
+                        storage.recipients,
+                        storage.mail_buffer.len()
+                    );
+                }
+                let verdict = classify_mail(config, &storage);
+                if !verdict.delay.is_zero() {
+                    if options.tarpit {
+                        eprintln!(
+                            "{}: delaying reply by {}s",
+                            storage.id,
+                            verdict.delay.as_secs()
+                        );
+                        thread::sleep(verdict.delay);
+                    } else {
+                        eprintln!(
+                            "{}: reply delay ignored, connections are served one at a time",
+                            storage.id
+                        );
+                    }
+                }
+                for (name, value) in &verdict.headers {
+                    let mut payload = Vec::with_capacity(name.len() + value.len() + 2);
+                    payload.extend_from_slice(name.as_bytes());
+                    payload.push(0);
+                    payload.extend_from_slice(value.as_bytes());
+                    payload.push(0);
+                    replies.push(b'h', &payload); // SMFIR_ADDHEADER
+                }
+                for (name, index) in &verdict.removed_headers {
+                    let mut payload = Vec::with_capacity(name.len() + 6);
+                    payload.extend_from_slice(&index.to_be_bytes());
+                    payload.extend_from_slice(name.as_bytes());
+                    payload.extend_from_slice(b"\0\0"); // empty value deletes the header
+                    replies.push(b'm', &payload); // SMFIR_CHGHEADER
+                }
+                for recipient in &verdict.added_recipients {
+                    replies.push(b'+', format!("<{recipient}>\0").as_bytes()); // SMFIR_ADDRCPT
+                }
+                if let Some(ref sender) = verdict.changed_sender {
+                    replies.push(b'e', format!("<{sender}>\0").as_bytes()); // SMFIR_CHGFROM
+                }
+                match verdict.result {
                     ClassifyResult::Accept => {
-                        writer.rewind()?;
-                        writer.write_all(b"a")?; // SMFIR_ACCEPT
-                        stream_writer.write_all(&((writer.position() as u32).to_be_bytes()))?;
-                        stream_writer
-                            .write_all(&writer.get_ref()[0..writer.position() as usize])?;
+                        replies.push(b'a', b""); // SMFIR_ACCEPT
                     }
                     ClassifyResult::Reject => {
-                        writer.rewind()?;
-                        writer.write_all(b"r")?; // SMFIR_REJECT
-                        stream_writer.write_all(&((writer.position() as u32).to_be_bytes()))?;
-                        stream_writer
-                            .write_all(&writer.get_ref()[0..writer.position() as usize])?;
+                        replies.push(b'r', b""); // SMFIR_REJECT
                     }
                     ClassifyResult::Quarantine => {
-                        writer.rewind()?;
-                        writer.write_all(b"qmilter\0")?; // SMFIR_QUARANTINE
-                        stream_writer.write_all(&((writer.position() as u32).to_be_bytes()))?;
-                        stream_writer
-                            .write_all(&writer.get_ref()[0..writer.position() as usize])?;
-                        writer.rewind()?;
-                        writer.write_all(b"a")?; // SMFIR_ACCEPT
-                        stream_writer.write_all(&((writer.position() as u32).to_be_bytes()))?;
-                        stream_writer
-                            .write_all(&writer.get_ref()[0..writer.position() as usize])?;
+                        replies.push(b'q', b"milter\0"); // SMFIR_QUARANTINE
+                        replies.push(b'a', b""); // SMFIR_ACCEPT
+                    }
+                    ClassifyResult::Tempfail => {
+                        replies.push(b't', b""); // SMFIR_TEMPFAIL
                     }
                 };
-                stream_writer.flush()?;
-                storage = MailInfoStorage::default();
+                replies.send(&mut stream_writer)?;
+                if let Some(ref callback) = config.decision_callback {
+                    callback(&Decision {
+                        queue_id: &storage.id,
+                        sender: &storage.sender,
+                        recipients: &storage.recipients,
+                        result: verdict.result,
+                        reason: &verdict.reason,
+                        log: &verdict.log,
+                    });
+                }
+                storage.clear();
             }
             'Q' => {
                 // no reply to SMFIC_QUIT
                 break;
             }
             'A' => {
-                storage = MailInfoStorage::default();
+                storage.clear();
+                oversized = false;
+                trusted = None;
                 // no reply to SMFIC_ABORT
             }
             _ => {
@@ -221,301 +546,1028 @@ fn process_client(
 
 extern "C" fn handlerfunc(signum: c_int) {
     eprintln!("received signal {signum}");
-    FLAG_SHUTDOWN.store(true, Ordering::Relaxed);
+    SHUTDOWN_SIGNALED.store(true, Ordering::Relaxed);
 }
 
-extern "C" fn handlerfunc_child(_signum: c_int) {
-    if let WaitStatus::Exited(_pid, _exit_code) =
-        waitpid(Some(Pid::from_raw(-1)), Some(WaitPidFlag::WNOHANG)).unwrap()
-        && CHILDREN_CNT.fetch_sub(1, Ordering::Relaxed) == 0
-    {
-        panic!("children underflow");
-    }
+/// Shutdown request of a daemon instance.
+#[derive(Clone)]
+struct ShutdownFlag {
+    flag: Arc<AtomicBool>,
+    // SIGTERM and SIGINT request shutdown as well
+    signals: bool,
 }
 
-fn install_signal_handler() {
-    unsafe {
-        let handler = SigHandler::Handler(handlerfunc);
-        let action = SigAction::new(handler, SaFlags::empty(), SigSet::empty());
-        sigaction(Signal::SIGTERM, &action).unwrap();
-        let action = SigAction::new(handler, SaFlags::empty(), SigSet::empty());
-        sigaction(Signal::SIGINT, &action).unwrap();
-        let handler = SigHandler::Handler(handlerfunc_child);
-        let action = SigAction::new(handler, SaFlags::SA_NOCLDSTOP, SigSet::empty());
-        sigaction(Signal::SIGCHLD, &action).unwrap();
+impl ShutdownFlag {
+    fn requested(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+            || self.signals && SHUTDOWN_SIGNALED.load(Ordering::Relaxed)
     }
 }
 
-pub fn daemon(config: &Config, args: &DaemonArgs) -> Result<(), Box<dyn Error>> {
-    #[cfg(feature = "systemd")]
-    let listen_socket = match systemd::daemon::listen_fds(false).unwrap().iter().next() {
-        Some(fd) => unsafe { Socket::from_raw_fd(fd) },
-        None => {
-            let address: SocketAddr = args.address.parse()?;
-            let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-            socket.set_reuse_address(true)?;
-            socket.bind(&address.into())?;
-            socket.listen(1)?;
-            socket
+/// Reads from a milter connection with a timeout of [`SHUTDOWN_POLL`] and reports the end
+/// of the connection when shutdown is requested while waiting, so that idle connections
+/// do not delay the shutdown.
+struct ConnectionReader<'a> {
+    stream: &'a TcpStream,
+    shutdown: &'a ShutdownFlag,
+}
+
+impl Read for ConnectionReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    if self.shutdown.requested() {
+                        return Ok(0);
+                    }
+                }
+                result => return result,
+            }
         }
+    }
+}
+
+/// Runs [`process_client()`] on `stream`. With `trace_dir`, the session is recorded and
+/// written to a trace file if it fails, see [`crate::trace`].
+fn serve_connection(
+    config: &Config,
+    stream: &TcpStream,
+    options: ProtocolOptions,
+    trace_dir: Option<&Path>,
+    shutdown: &ShutdownFlag,
+) -> Result<(), Box<dyn Error>> {
+    // inherited from the listener on some systems
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(SHUTDOWN_POLL))?;
+    let reader = BufReader::new(ConnectionReader { stream, shutdown });
+    let Some(dir) = trace_dir else {
+        return process_client(config, reader, stream, options);
     };
+    let mut reader = TraceReader::new(reader);
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        process_client(config, &mut reader, stream, options)
+    }))
+    .unwrap_or_else(|panic| Err(format!("classifier panic: {}", panic_message(&*panic)).into()));
+    if let Err(ref e) = result {
+        match write_trace(dir, &e.to_string(), &reader) {
+            Ok(path) => eprintln!("session trace written to {}", path.display()),
+            Err(e) => eprintln!("{}: {e}", dir.display()),
+        }
+    }
+    result
+}
 
-    #[cfg(not(feature = "systemd"))]
-    let listen_socket = {
-        let address: SocketAddr = args.address.parse()?;
-        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-        socket.set_reuse_address(true)?;
-        socket.bind(&address.into())?;
-        socket.listen(1)?;
-        socket
+// Raises (`up`) or lowers `verbosity` by one step and returns the new value.
+fn adjust_verbosity(verbosity: &AtomicU8, up: bool) -> u8 {
+    let step = |v: u8| {
+        Some(if up {
+            (v + 1).min(VERBOSITY_MAX)
+        } else {
+            v.saturating_sub(1)
+        })
     };
+    let old = verbosity
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, step)
+        .unwrap();
+    step(old).unwrap()
+}
+
+extern "C" fn handlerfunc_verbosity(signum: c_int) {
+    // only async-signal-safe atomics here, the change is logged by DaemonRuntime::run()
+    adjust_verbosity(&VERBOSITY, signum == Signal::SIGUSR1 as c_int);
+}
+
+extern "C" fn handlerfunc_child(_signum: c_int) {
+    // only interrupts pause(), see DaemonRuntime::reap_children()
+}
+
+/// State of a daemon instance.
+///
+/// Signal handlers are only installed with
+/// [`install_signal_handlers()`](Self::install_signal_handlers), so that several instances
+/// can run in one process (e.g. in tests) and be stopped with their shutdown flag.
+pub(crate) struct DaemonRuntime {
+    shutdown: ShutdownFlag,
+    // fork mode children running
+    children: HashSet<Pid>,
+    // children killed by a signal since the last one exited normally
+    crashes: u32,
+    // --rlimit-as and --rlimit-cpu are set for the children
+    rlimit_as: bool,
+    rlimit_cpu: bool,
+    crash_signal: Option<Signal>,
+    // crash count already reported
+    crashes_seen: u32,
+    // verbosity already reported
+    verbosity_seen: u8,
+}
 
-    if args.fork_max > 0 && args.threads_max > 0 {
-        return Err("Cannot use both fork and thread modes simultaneously".into());
+impl DaemonRuntime {
+    /// Creates a runtime which stops when `shutdown` is set.
+    ///
+    /// The flag is checked at least every [`SHUTDOWN_POLL`] while waiting for connections
+    /// and data. Idle connections are closed, messages being received are aborted.
+    pub(crate) fn new(shutdown: Arc<AtomicBool>) -> Self {
+        DaemonRuntime {
+            shutdown: ShutdownFlag {
+                flag: shutdown,
+                signals: false,
+            },
+            children: HashSet::new(),
+            crashes: 0,
+            rlimit_as: false,
+            rlimit_cpu: false,
+            crash_signal: None,
+            crashes_seen: 0,
+            verbosity_seen: VERBOSITY.load(Ordering::Relaxed),
+        }
     }
 
-    let thread_state: Option<Arc<(Mutex<u16>, Condvar)>> = if args.threads_max > 0 {
-        Some(Arc::new((Mutex::new(0), Condvar::new())))
-    } else {
-        None
-    };
+    /// Stops the daemon on SIGTERM and SIGINT and changes the log verbosity on SIGUSR1 and
+    /// SIGUSR2. These handlers are process-wide.
+    pub(crate) fn install_signal_handlers(&mut self) {
+        unsafe {
+            let handler = SigHandler::Handler(handlerfunc);
+            let action = SigAction::new(handler, SaFlags::empty(), SigSet::empty());
+            sigaction(Signal::SIGTERM, &action).unwrap();
+            let action = SigAction::new(handler, SaFlags::empty(), SigSet::empty());
+            sigaction(Signal::SIGINT, &action).unwrap();
+            let handler = SigHandler::Handler(handlerfunc_child);
+            let action = SigAction::new(handler, SaFlags::SA_NOCLDSTOP, SigSet::empty());
+            sigaction(Signal::SIGCHLD, &action).unwrap();
+            // SA_RESTART, so that reads on milter connections are not interrupted
+            let handler = SigHandler::Handler(handlerfunc_verbosity);
+            let action = SigAction::new(handler, SaFlags::SA_RESTART, SigSet::empty());
+            sigaction(Signal::SIGUSR1, &action).unwrap();
+            sigaction(Signal::SIGUSR2, &action).unwrap();
+        }
+        self.shutdown.signals = true;
+    }
 
-    install_signal_handler();
-    loop {
-        if args.fork_max > 0 {
-            while CHILDREN_CNT.load(Ordering::Relaxed) >= args.fork_max {
-                pause()
-            }
-        } else if let Some(ref state) = thread_state {
-            let (lock, cvar) = state.as_ref();
-            let mut count = lock.lock().unwrap();
-            while *count >= args.threads_max {
-                count = cvar.wait(count).unwrap();
+    /// Reaps terminated fork mode children and counts the crashed ones.
+    ///
+    /// Only the children forked by this runtime are waited for, other child processes of
+    /// an embedding application are left alone.
+    fn reap_children(&mut self) {
+        let children: Vec<Pid> = self.children.iter().copied().collect();
+        for pid in children {
+            match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(_pid, _exit_code)) => {
+                    self.crashes = 0;
+                }
+                Ok(WaitStatus::Signaled(pid, signal, _core_dumped))
+                    if killed_by_rlimit(signal, self.rlimit_as, self.rlimit_cpu) =>
+                {
+                    // a problem of the message, not of the classifier
+                    eprintln!(
+                        "child process {pid} killed ({}), probably by a resource limit",
+                        signal.as_str()
+                    );
+                }
+                Ok(WaitStatus::Signaled(_pid, signal, _core_dumped)) => {
+                    self.crashes += 1;
+                    self.crash_signal = Some(signal);
+                }
+                Ok(_) => continue,
+                // e.g. reaped by the application, which ignores SIGCHLD
+                Err(_) => (),
             }
+            self.children.remove(&pid);
         }
-        match listen_socket.accept() {
-            Ok((socket, _addr)) => {
-                if args.fork_max > 0 {
-                    match unsafe { fork() } {
-                        Ok(ForkResult::Parent { .. }) => {
-                            CHILDREN_CNT.fetch_add(1, Ordering::Relaxed);
-                        }
-                        Ok(ForkResult::Child) => {
-                            drop(listen_socket);
-                            let stream: TcpStream = socket.into();
-                            let reader = BufReader::new(&stream);
-                            let writer = BufWriter::new(&stream);
-                            match process_client(config, reader, writer, args.truncate) {
-                                Ok(_) => exit(0),
-                                Err(e) => {
-                                    eprintln!("{e}");
+    }
+
+    /// Waits until a connection can be accepted or [`SHUTDOWN_POLL`] has passed.
+    fn wait_for_connection(&self, listen_socket: &Socket) -> io::Result<bool> {
+        let mut fds = [PollFd::new(listen_socket.as_fd(), PollFlags::POLLIN)];
+        let timeout = PollTimeout::try_from(SHUTDOWN_POLL).unwrap();
+        match poll(&mut fds, timeout) {
+            Ok(n) => Ok(n > 0),
+            Err(nix::errno::Errno::EINTR) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Delays forking while children keep crashing and gives up after [`CRASH_BUDGET`]
+    /// crashes in a row.
+    fn crash_backoff(&mut self) -> Result<(), Box<dyn Error>> {
+        let crashes = self.crashes;
+        if crashes == 0 {
+            self.crashes_seen = 0;
+            return Ok(());
+        }
+        let signal = self
+            .crash_signal
+            .map(|s| s.as_str())
+            .unwrap_or("unknown signal");
+        if crashes >= CRASH_BUDGET {
+            return Err(format!(
+                "{crashes} child processes in a row were killed ({signal}), giving up; \
+                 check the classifier and the libraries it loads"
+            )
+            .into());
+        }
+        let delay = Duration::from_secs(1 << (crashes - 1).min(6));
+        if crashes != self.crashes_seen {
+            eprintln!(
+                "child process killed ({signal}), {crashes} in a row, delaying next fork by {}s",
+                delay.as_secs()
+            );
+            self.crashes_seen = crashes;
+        }
+        thread::sleep(delay);
+        Ok(())
+    }
+
+    /// Accepts milter connections on `listen_socket` until shutdown is requested.
+    pub(crate) fn run(
+        &mut self,
+        config: &Config,
+        args: &DaemonArgs,
+        listen_socket: Socket,
+    ) -> Result<(), Box<dyn Error>> {
+        if args.fork_max > 0 && args.threads_max > 0 {
+            return Err("Cannot use both fork and thread modes simultaneously".into());
+        }
+        if let Some(Concurrency::Prefork(workers)) = args.concurrency {
+            return self.run_prefork(config, args, listen_socket, workers);
+        }
+
+        let thread_state: Option<Arc<(Mutex<u16>, Condvar)>> = if args.threads_max > 0 {
+            Some(Arc::new((Mutex::new(0), Condvar::new())))
+        } else {
+            None
+        };
+
+        self.rlimit_as = args.rlimit_as.is_some();
+        self.rlimit_cpu = args.rlimit_cpu.is_some();
+        let options = ProtocolOptions::new(config, args);
+        let trace_dir = args.trace_dir.as_deref();
+        while !self.shutdown.requested() {
+            let verbosity = VERBOSITY.load(Ordering::Relaxed);
+            if verbosity != self.verbosity_seen {
+                eprintln!("verbosity {verbosity}");
+                self.verbosity_seen = verbosity;
+            }
+            if args.fork_max > 0 {
+                self.reap_children();
+                while self.children.len() >= usize::from(args.fork_max) {
+                    if self.shutdown.requested() {
+                        return Ok(());
+                    }
+                    if self.shutdown.signals {
+                        // interrupted by SIGCHLD
+                        pause();
+                    } else {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    self.reap_children();
+                }
+                self.crash_backoff()?;
+            } else if let Some(ref state) = thread_state {
+                let (lock, cvar) = state.as_ref();
+                let mut count = lock.lock().unwrap();
+                while *count >= args.threads_max {
+                    count = cvar.wait(count).unwrap();
+                }
+            }
+            if !self.wait_for_connection(&listen_socket)? {
+                continue;
+            }
+            match listen_socket.accept() {
+                Ok((socket, _addr)) => {
+                    if args.fork_max > 0 {
+                        match unsafe { fork() } {
+                            Ok(ForkResult::Parent { child }) => {
+                                self.children.insert(child);
+                            }
+                            Ok(ForkResult::Child) => {
+                                drop(listen_socket);
+                                if let Err(e) = apply_rlimits(args) {
+                                    eprintln!("setrlimit: {e}");
                                     exit(1)
                                 }
+                                let stream: TcpStream = socket.into();
+                                match serve_connection(
+                                    config,
+                                    &stream,
+                                    options,
+                                    trace_dir,
+                                    &self.shutdown,
+                                ) {
+                                    Ok(_) => exit(0),
+                                    Err(e) => {
+                                        eprintln!("{e}");
+                                        exit(1)
+                                    }
+                                }
                             }
+                            Err(e) => eprintln!("fork: {e}"),
+                        }
+                    } else if args.threads_max > 0 {
+                        let state_clone = thread_state.as_ref().unwrap().clone();
+
+                        // Increment thread count
+                        {
+                            let (lock, _) = state_clone.as_ref();
+                            let mut count = lock.lock().unwrap();
+                            *count += 1;
+                        }
+
+                        let stream: TcpStream = socket.into();
+                        let thread_config = config.clone();
+                        let thread_trace_dir = args.trace_dir.clone();
+                        let shutdown = self.shutdown.clone();
+                        thread::spawn(move || {
+                            let trace_dir = thread_trace_dir.as_deref();
+                            if let Err(e) = serve_connection(
+                                &thread_config,
+                                &stream,
+                                options,
+                                trace_dir,
+                                &shutdown,
+                            ) {
+                                eprintln!("thread error: {e}");
+                            }
+                            // Decrement count and signal
+                            let (lock, cvar) = &*state_clone;
+                            let mut count = lock.lock().unwrap();
+                            *count -= 1;
+                            cvar.notify_one();
+                        });
+                    } else {
+                        let stream: TcpStream = socket.into();
+                        if let Err(e) =
+                            serve_connection(config, &stream, options, trace_dir, &self.shutdown)
+                        {
+                            eprintln!("{e}");
                         }
-                        Err(e) => eprintln!("fork: {e}"),
-                    }
-                } else if args.threads_max > 0 {
-                    let state_clone = thread_state.as_ref().unwrap().clone();
-
-                    // Increment thread count
-                    {
-                        let (lock, _) = state_clone.as_ref();
-                        let mut count = lock.lock().unwrap();
-                        *count += 1;
                     }
+                }
+                // in prefork mode, another worker may have accepted the connection
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+                    ) => {}
+                Err(e) => eprintln!("fork: {e}"),
+            }
+        }
 
-                    let stream: TcpStream = socket.into();
-                    let thread_config = config.clone();
-                    let truncate = args.truncate;
-                    thread::spawn(move || {
-                        let reader = BufReader::new(&stream);
-                        let writer = BufWriter::new(&stream);
-                        if let Err(e) = process_client(&thread_config, reader, writer, truncate) {
-                            eprintln!("thread error: {e}");
+        // Wait for active threads to complete
+        if let Some(ref state) = thread_state {
+            let (lock, cvar) = state.as_ref();
+            let mut count = lock.lock().unwrap();
+            while *count > 0 {
+                eprintln!("Waiting for {} threads to complete", *count);
+                let result = cvar.wait_timeout(count, Duration::from_secs(1)).unwrap();
+                count = result.0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs `workers` child processes which accept connections on `listen_socket` one at a
+    /// time, and replaces workers which exit, until shutdown is requested. The workers are
+    /// then stopped with SIGTERM after their current connection.
+    fn run_prefork(
+        &mut self,
+        config: &Config,
+        args: &DaemonArgs,
+        listen_socket: Socket,
+        workers: u16,
+    ) -> Result<(), Box<dyn Error>> {
+        // all workers are woken up by a new connection, only one of them gets it
+        listen_socket.set_nonblocking(true)?;
+        let worker_args = DaemonArgs {
+            concurrency: Some(Concurrency::Single),
+            fork_max: 0,
+            threads_max: 0,
+            ..args.clone()
+        };
+        while !self.shutdown.requested() {
+            self.reap_children();
+            if self.children.len() < usize::from(workers) {
+                self.crash_backoff()?;
+                match unsafe { fork() } {
+                    Ok(ForkResult::Parent { child }) => {
+                        self.children.insert(child);
+                        continue;
+                    }
+                    Ok(ForkResult::Child) => {
+                        self.children.clear();
+                        // stop on SIGTERM from the parent, also when embedded with serve()
+                        self.install_signal_handlers();
+                        match self.run(config, &worker_args, listen_socket) {
+                            Ok(()) => exit(0),
+                            Err(e) => {
+                                eprintln!("{e}");
+                                exit(1)
+                            }
                         }
-                        // Decrement count and signal
-                        let (lock, cvar) = &*state_clone;
-                        let mut count = lock.lock().unwrap();
-                        *count -= 1;
-                        cvar.notify_one();
-                    });
-                } else {
-                    let stream: TcpStream = socket.into();
-                    let reader = BufReader::new(&stream);
-                    let writer = BufWriter::new(&stream);
-                    if let Err(e) = process_client(config, reader, writer, args.truncate) {
-                        eprintln!("{e}");
                     }
+                    Err(e) => eprintln!("fork: {e}"),
                 }
             }
-            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
-            Err(e) => eprintln!("fork: {e}"),
+            thread::sleep(Duration::from_millis(100));
         }
-        if FLAG_SHUTDOWN.load(Ordering::Relaxed) {
-            break;
+        for &pid in &self.children {
+            let _ = kill(pid, Signal::SIGTERM);
+        }
+        for pid in self.children.drain() {
+            let _ = waitpid(pid, None);
         }
+        Ok(())
     }
+}
+
+/// Self-test results reported by the health endpoint.
+///
+/// The count of oversized packets only covers connections served by this process, i.e.
+/// `single`, `auto` and `threads(N)` mode.
+fn health_report(config: &Config) -> (bool, String) {
+    let classifier = config.full_mail_classifier.is_some();
+    let report = format!(
+        "{}\nclassifier: {}\noversized packets: {}\n",
+        if classifier { "ok" } else { "fail" },
+        if classifier { "loaded" } else { "missing" },
+        OVERSIZED_PACKETS.load(Ordering::Relaxed),
+    );
+    (classifier, report)
+}
 
-    // Wait for active threads to complete
-    if let Some(ref state) = thread_state {
-        let (lock, cvar) = state.as_ref();
-        let mut count = lock.lock().unwrap();
-        while *count > 0 {
-            eprintln!("Waiting for {} threads to complete", *count);
-            let result = cvar.wait_timeout(count, Duration::from_secs(1)).unwrap();
-            count = result.0;
+// Time a health check client may take to send its request and receive the response.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Serves `--health-listen`: answers every HTTP request with 200 (or 503) and the
+/// self-test report, which is created for each request. Returns the bound address.
+///
+/// Runs in its own thread and answers one request at a time; [`HEALTH_TIMEOUT`] keeps a
+/// stalled client from blocking the following checks for long. In fork mode the thread
+/// exists in the parent only, so it must not take locks a forked child could need (e.g.
+/// stderr): errors are silently ignored.
+fn spawn_health_listener(config: &Config, address: &str) -> Result<SocketAddr, Box<dyn Error>> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("{address}: {e}"))?;
+    let local_address = listener.local_addr()?;
+    let config = config.clone();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = stream.set_read_timeout(Some(HEALTH_TIMEOUT));
+            let _ = stream.set_write_timeout(Some(HEALTH_TIMEOUT));
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            let (healthy, report) = health_report(&config);
+            let status = if healthy {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let response = format!(
+                "HTTP/1.0 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{report}",
+                report.len()
+            );
+            let _ = stream.write_all(response.as_bytes());
         }
+    });
+    Ok(local_address)
+}
+
+/// Checks if a child killed by `signal` probably hit a resource limit, rather than crashed.
+///
+/// Such deaths are failures of a single message and do not count against
+/// [`CRASH_BUDGET`]; otherwise a few oversized messages would stop the daemon. Only the
+/// signals the configured limits can cause are taken into account, so that a classifier
+/// crashing for other reasons still uses up the budget.
+fn killed_by_rlimit(signal: Signal, rlimit_as: bool, rlimit_cpu: bool) -> bool {
+    match signal {
+        Signal::SIGXCPU => true,
+        // failed allocations abort or crash
+        Signal::SIGABRT | Signal::SIGSEGV => rlimit_as,
+        // the hard CPU limit kills
+        Signal::SIGKILL => rlimit_cpu,
+        _ => false,
     }
+}
 
+/// Applies `--rlimit-as` and `--rlimit-cpu` to the current (child) process.
+///
+/// When the CPU limit is reached, the child is killed with SIGXCPU. When the address space
+/// limit is reached, allocations fail and the child aborts. In both cases Postfix applies
+/// its `milter_default_action` to the message.
+fn apply_rlimits(args: &DaemonArgs) -> nix::Result<()> {
+    if let Some(bytes) = args.rlimit_as {
+        setrlimit(Resource::RLIMIT_AS, bytes, bytes)?;
+    }
+    if let Some(seconds) = args.rlimit_cpu {
+        setrlimit(Resource::RLIMIT_CPU, seconds, seconds + 1)?;
+    }
     Ok(())
 }
 
-fn simulate_client(config: &Config) -> Result<(), Box<dyn Error>> {
-    let storage = MailInfoStorage {
-        id: "test".into(),
-        recipients: ["testrecipient".into()].into(),
-        sender: "testsender".into(),
-        mail_buffer: b"From: me\nTo: yu\nSubject: test\n\nTest".to_vec(),
-        ..Default::default()
+/// Summary of the configuration logged at startup, one line per item.
+fn startup_report(config: &Config, args: &DaemonArgs, listener: &str) -> Vec<String> {
+    let mut features = vec!["daemon"];
+    if cfg!(feature = "systemd") {
+        features.push("systemd");
+    }
+    if cfg!(feature = "phishing") {
+        features.push("phishing");
+    }
+    let yes_no = |enabled: bool| if enabled { "yes" } else { "no" };
+    let mut report = vec![
+        format!("srmilter {} starting", env!("CARGO_PKG_VERSION")),
+        format!("  features: {}", features.join(", ")),
+        format!("  listener: {listener}"),
+        format!("  concurrency: {}", args.concurrency(config)),
+        format!(
+            "  actions: {}",
+            action_names(negotiated_actions()).join(", ")
+        ),
+        format!(
+            "  protocol: 0x{:08x}",
+            negotiated_protocol(ProtocolOptions::new(config, args))
+        ),
+        format!(
+            "  classifiers: main {}, shadow {}, fallback {}, recipient validator {}",
+            yes_no(config.full_mail_classifier.is_some()),
+            yes_no(config.shadow_classifier.is_some()),
+            yes_no(config.fallback_classifier.is_some()),
+            yes_no(config.recipient_validator.is_some()),
+        ),
+        format!(
+            "  lists: always deliver {}, trusted networks {}, untrusted headers {}",
+            config.always_deliver.len(),
+            config.trusted_networks.len(),
+            config.untrusted_headers.len(),
+        ),
+        format!(
+            "  options: trusted client {}, bypass key {}, batv signing {}, decision callback {}",
+            yes_no(config.trusted_client.is_some()),
+            yes_no(config.bypass_key.is_some()),
+            yes_no(config.batv_signing.is_some()),
+            yes_no(config.decision_callback.is_some()),
+        ),
+    ];
+    if let Some(ref dir) = config.sieve_dir {
+        report.push(format!("  sieve dir: {}", dir.display()));
+    }
+    if let Some(ref address) = args.health_listen {
+        report.push(format!("  health listener: {address}"));
+    }
+    if let Some(ref dir) = args.trace_dir {
+        report.push(format!("  trace dir: {}", dir.display()));
+    }
+    report
+}
+
+pub fn daemon(config: &Config, args: &DaemonArgs) -> Result<(), Box<dyn Error>> {
+    #[cfg(feature = "systemd")]
+    let (listen_socket, activated) = match systemd::daemon::listen_fds(false).unwrap().iter().next()
+    {
+        Some(fd) => (unsafe { Socket::from_raw_fd(fd) }, true),
+        None => {
+            let address: SocketAddr = args.address.parse()?;
+            let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&address.into())?;
+            socket.listen(1)?;
+            (socket, false)
+        }
     };
-    let _result = classify_mail(config, &storage);
-    Ok(())
+
+    #[cfg(not(feature = "systemd"))]
+    let (listen_socket, activated) = {
+        let address: SocketAddr = args.address.parse()?;
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(1)?;
+        (socket, false)
+    };
+
+    let mut listener = match listen_socket.local_addr()?.as_socket() {
+        Some(address) => address.to_string(),
+        None => "unknown".to_string(),
+    };
+    if activated {
+        listener.push_str(" (systemd socket activation)");
+    }
+    for line in startup_report(config, args, &listener) {
+        eprintln!("{line}");
+    }
+
+    if let Some(ref address) = args.health_listen {
+        spawn_health_listener(config, address)?;
+    }
+
+    let mut runtime = DaemonRuntime::new(Arc::default());
+    runtime.install_signal_handlers();
+    runtime.run(config, args, listen_socket)
 }
 
-#[rustfmt::skip]
-#[allow(unused_variables, dead_code)]
-pub fn simulate(config: &Config, args: &DaemonArgs) -> Result<(), Box<dyn Error>> {
-//    #[cfg(feature = "systemd")]
-//    let listen_socket = match systemd::daemon::listen_fds(false).unwrap().iter().next() {
-//        Some(fd) => unsafe { Socket::from_raw_fd(fd) },
-//        None => {
-//            let address: SocketAddr = args.address.parse()?;
-//            let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-//            socket.set_reuse_address(true)?;
-//            socket.bind(&address.into())?;
-//            socket.listen(1)?;
-//            socket
-//        }
-//    };
-//
-//    #[cfg(not(feature = "systemd"))]
-//    let listen_socket = {
-//        let address: SocketAddr = args.address.parse()?;
-//        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-//        socket.set_reuse_address(true)?;
-//        socket.bind(&address.into())?;
-//        socket.listen(1)?;
-//        socket
-//    };
-//
-    if args.fork_max > 0 && args.threads_max > 0 {
-        return Err("Cannot use both fork and thread modes simultaneously".into());
-    }
-
-    let thread_state: Option<Arc<(Mutex<u16>, Condvar)>> = if args.threads_max > 0 {
-        Some(Arc::new((Mutex::new(0), Condvar::new())))
-    } else {
-        None
+#[cfg(test)]
+const OPTIONS: ProtocolOptions = ProtocolOptions {
+    truncate: usize::MAX,
+    header_leadspc: false,
+    rcpt_rej: false,
+    rcpt_reply: false,
+    tarpit: false,
+};
+
+#[test]
+fn test_startup_report() {
+    let config = Config::builder()
+        .always_deliver(&["postmaster@example.org"])
+        .sieve_dir("/etc/srmilter/sieve")
+        .build();
+    let args = DaemonArgs::builder().threads(4).build();
+    let report = startup_report(&config, &args, "127.0.0.1:7044");
+    assert!(report[0].starts_with("srmilter "));
+    let line = |key: &str| {
+        let prefix = format!("  {key}: ");
+        report
+            .iter()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .unwrap_or_else(|| panic!("no {key} in {report:?}"))
     };
+    assert_eq!(line("listener"), "127.0.0.1:7044");
+    assert_eq!(line("concurrency"), "threads(4)");
+    assert!(line("actions").starts_with("add header, "));
+    assert!(line("classifiers").starts_with("main no, "));
+    assert_eq!(
+        line("lists"),
+        "always deliver 1, trusted networks 0, untrusted headers 0"
+    );
+    assert_eq!(line("sieve dir"), "/etc/srmilter/sieve");
+}
 
-    install_signal_handler();
-    let mut simulate_cnt = 8;
-    loop {
-        if simulate_cnt == 0 {
-            break;
-        } else {
-            simulate_cnt -= 1
-        }
-        if args.fork_max > 0 {
-            while CHILDREN_CNT.load(Ordering::Relaxed) >= args.fork_max {
-                pause()
-            }
-        } else if let Some(ref state) = thread_state {
-            let (lock, cvar) = state.as_ref();
-            let mut count = lock.lock().unwrap();
-            while *count >= args.threads_max {
-                count = cvar.wait(count).unwrap();
-            }
-        }
-//        match listen_socket.accept() {
-//            Ok((socket, _addr)) => {
-                if args.fork_max > 0 {
-                    match unsafe { fork() } {
-                        Ok(ForkResult::Parent { .. }) => {
-                            CHILDREN_CNT.fetch_add(1, Ordering::Relaxed);
-                        }
-                        Ok(ForkResult::Child) => {
-//                            drop(listen_socket);
-//                            let stream: TcpStream = socket.into();
-//                            let reader = BufReader::new(&stream);
-//                            let writer = BufWriter::new(&stream);
-//                            match process_client(config, reader, writer, args.truncate) {
-                            match simulate_client(config) {
-                                Ok(_) => exit(0),
-                                Err(e) => {
-                                    eprintln!("{e}");
-                                    exit(1)
-                                }
-                            }
-                        }
-                        Err(e) => eprintln!("fork: {e}"),
-                    }
-                } else if args.threads_max > 0 {
-                    let state_clone = thread_state.as_ref().unwrap().clone();
-
-                    // Increment thread count
-                    {
-                        let (lock, _) = state_clone.as_ref();
-                        let mut count = lock.lock().unwrap();
-                        *count += 1;
-                    }
+#[test]
+fn test_extend_crlf() {
+    let mut buffer = b"Subject:".to_vec();
+    extend_crlf(&mut buffer, b" a\n\tb\r\n c");
+    assert_eq!(buffer, b"Subject: a\r\n\tb\r\n c");
+}
 
-//                    let stream: TcpStream = socket.into();
-
-                    // Extract Arc from config
-                    let thread_config = config.clone();
-                    let truncate = args.truncate;
-                    thread::spawn(move || {
-//                        let reader = BufReader::new(&stream);
-//                        let writer = BufWriter::new(&stream);
-//                        if let Err(e) = process_client(&thread_config, reader, writer, truncate) {
-                        if let Err(e) = simulate_client(&thread_config) {
-                            eprintln!("thread error: {e}");
-                        }
-                        // Decrement count and signal
-                        let (lock, cvar) = &*state_clone;
-                        let mut count = lock.lock().unwrap();
-                        *count -= 1;
-                        cvar.notify_one();
-                    });
-                } else {
-//                    let stream: TcpStream = socket.into();
-//                    let reader = BufReader::new(&stream);
-//                    let writer = BufWriter::new(&stream);
-//                    if let Err(e) = process_client(config, reader, writer, args.truncate) {
-                    if let Err(e) = simulate_client(config) {
-                        eprintln!("{e}");
-                    }
-                }
-//            }
-//            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
-//            Err(e) => eprintln!("fork: {e}"),
-//        }
-        if FLAG_SHUTDOWN.load(Ordering::Relaxed) {
-            break;
-        }
+#[test]
+fn test_process_client_probe() {
+    let config = Config::builder().build();
+    // OPTNEG followed by QUIT
+    let mut input = Vec::new();
+    input.extend_from_slice(&13u32.to_be_bytes());
+    input.push(b'O');
+    input.extend_from_slice(&[0u8; 12]);
+    input.extend_from_slice(&1u32.to_be_bytes());
+    input.push(b'Q');
+    let mut output = Vec::new();
+    process_client(&config, &input[..], &mut output, OPTIONS).unwrap();
+    assert_eq!(&output[0..5], b"\0\0\0\x0dO");
+    assert_eq!(output.len(), 17);
+    // OPTNEG followed by disconnect
+    let mut output = Vec::new();
+    process_client(&config, &input[..17], &mut output, OPTIONS).unwrap();
+    assert_eq!(output.len(), 17);
+    // disconnect within a command is still an error
+    assert!(process_client(&config, &input[..19], &mut Vec::new(), OPTIONS).is_err());
+    assert!(health_report(&config).1.starts_with("fail"));
+}
+
+#[test]
+fn test_health_listener() {
+    let address = spawn_health_listener(&Config::builder().build(), "127.0.0.1:0").unwrap();
+    // a client which sends nothing only holds up the next check until it times out
+    let _stalled = TcpStream::connect(address).unwrap();
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.0 503 Service Unavailable\r\n"));
+    assert!(response.contains("\r\n\r\nfail\nclassifier: missing\noversized packets: "));
+}
+
+#[test]
+fn test_process_client_decision() {
+    use crate::milter::PacketBuffer;
+
+    let decisions = Arc::new(Mutex::new(Vec::new()));
+    let decisions_clone = decisions.clone();
+    let config = Config::builder()
+        .on_decision(move |d| {
+            decisions_clone.lock().unwrap().push(format!(
+                "{} {} {:?} {} {}",
+                d.queue_id,
+                d.sender,
+                d.recipients,
+                d.result.uc(),
+                d.reason
+            ))
+        })
+        .build();
+    let mut packets = PacketBuffer::default();
+    packets.push(b'D', b"Mi\0ABC123\0");
+    packets.push(b'M', b"<a@example.com>\0SIZE=100\0");
+    packets.push(b'R', b"<b@example.com>\0");
+    packets.push(b'L', b"Subject\0test\0");
+    packets.push(b'N', b"");
+    packets.push(b'B', b"Text\r\n");
+    packets.push(b'E', b"");
+    packets.push(b'Q', b"");
+    let mut input = Vec::new();
+    packets.send(&mut input).unwrap();
+    let mut output = Vec::new();
+    process_client(&config, &input[..], &mut output, OPTIONS).unwrap();
+    assert_eq!(output, b"\0\0\0\x01a");
+    assert_eq!(
+        *decisions.lock().unwrap(),
+        ["ABC123 a@example.com [\"b@example.com\"] ACCEPT no classifier configured"]
+    );
+}
+
+#[test]
+fn test_process_client_recipient_validator() {
+    use crate::milter::PacketBuffer;
+
+    let config = Config::builder()
+        .recipient_validator(crate::recipient::static_list(
+            &["b@example.com".to_string()],
+        ))
+        .build();
+    let options = ProtocolOptions {
+        rcpt_reply: true,
+        ..OPTIONS
+    };
+    assert_eq!(negotiated_protocol(options) & SMFIP_NR_RCPT, 0);
+    let mut packets = PacketBuffer::default();
+    packets.push(b'M', b"<a@example.com>\0");
+    // rejected by Postfix (--rcpt-rej), the macro must not stick to the next recipients
+    packets.push(b'D', b"R{rcpt_mailer}\0error\0");
+    packets.push(b'R', b"<x@example.com>\0");
+    packets.push(b'R', b"<b@example.com>\0");
+    packets.push(b'R', b"<c@example.com>\0");
+    packets.push(b'Q', b"");
+    let mut input = Vec::new();
+    packets.send(&mut input).unwrap();
+    let mut output = Vec::new();
+    process_client(&config, &input[..], &mut output, options).unwrap();
+    let mut expected = PacketBuffer::default();
+    expected.push(b'c', b"");
+    expected.push(b'c', b"");
+    expected.push(
+        b'y',
+        b"550 5.1.1 Recipient address rejected: User unknown\0",
+    );
+    let mut expected_output = Vec::new();
+    expected.send(&mut expected_output).unwrap();
+    assert_eq!(output, expected_output);
+}
+
+#[test]
+fn test_process_client_trusted_networks() {
+    use crate::milter::PacketBuffer;
+    use crate::{EmailClassifier, MailInfo};
+
+    fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+        mail_info.reject("classified")
     }
+    let config = Config::builder()
+        .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+        .trusted_networks(&["10.0.0.0/8".parse().unwrap()])
+        .batv_signing(b"key", 7, &["example.org"])
+        .build();
+    let mut packets = PacketBuffer::default();
+    packets.push(b'D', b"M{client_addr}\x0010.1.2.3\0");
+    packets.push(b'M', b"<a@example.org>\0");
+    packets.push(b'E', b"");
+    for client in ["10.1.2.3", "192.0.2.1"] {
+        packets.push(b'D', format!("M{{client_addr}}\0{client}\0").as_bytes());
+        packets.push(b'M', b"<a@example.com>\0");
+        packets.push(b'L', b"Subject\0test\0");
+        packets.push(b'N', b"");
+        packets.push(b'B', b"Text\r\n");
+        packets.push(b'E', b"");
+    }
+    packets.push(b'Q', b"");
+    let mut input = Vec::new();
+    packets.send(&mut input).unwrap();
+    let mut output = Vec::new();
+    process_client(&config, &input[..], &mut output, OPTIONS).unwrap();
+    let tagged = crate::batv::sign("a@example.org", b"key", crate::batv::today(), 7);
+    let mut expected = PacketBuffer::default();
+    expected.push(b'e', format!("<{tagged}>\0").as_bytes());
+    expected.push(b'a', b"");
+    expected.push(b'a', b"");
+    expected.push(b'r', b"");
+    let mut expected_output = Vec::new();
+    expected.send(&mut expected_output).unwrap();
+    assert_eq!(output, expected_output);
+
+    let mut macros = HashMap::new();
+    macros.insert(
+        "_".to_string(),
+        "mx.example.org [IPv6:2001:db8::1]".to_string(),
+    );
+    assert_eq!(
+        client_ip(&HashMap::new(), &macros),
+        Some("2001:db8::1".parse().unwrap())
+    );
+    macros.insert("{client_addr}".to_string(), "192.0.2.1".to_string());
+    assert_eq!(
+        client_ip(&HashMap::new(), &macros),
+        Some("192.0.2.1".parse().unwrap())
+    );
+    assert_eq!(client_ip(&HashMap::new(), &HashMap::new()), None);
+}
+
+#[test]
+fn test_daemon_runtime() {
+    // two instances in a row in the same process, each started and stopped in-process
+    for threads_max in [0, 2] {
+        let args = DaemonArgs {
+            address: "127.0.0.1:0".to_string(),
+            threads_max,
+            ..Default::default()
+        };
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        let address: SocketAddr = args.address.parse().unwrap();
+        socket.bind(&address.into()).unwrap();
+        socket.listen(1).unwrap();
+        let address = socket.local_addr().unwrap().as_socket().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let mut runtime = DaemonRuntime::new(shutdown.clone());
+        let daemon = thread::spawn(move || {
+            runtime
+                .run(&Config::builder().build(), &args, socket)
+                .map_err(|e| e.to_string())
+        });
+        // OPTNEG followed by QUIT
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(&13u32.to_be_bytes()).unwrap();
+        stream.write_all(b"O").unwrap();
+        stream.write_all(&[0u8; 12]).unwrap();
+        let mut reply = [0u8; 17];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[0..5], b"\0\0\0\x0dO");
+        stream.write_all(&1u32.to_be_bytes()).unwrap();
+        stream.write_all(b"Q").unwrap();
+        drop(stream);
+        // an idle connection does not keep the daemon from stopping
+        let mut idle = TcpStream::connect(address).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        shutdown.store(true, Ordering::Relaxed);
+        daemon.join().unwrap().unwrap();
+        // closed by the daemon
+        assert_eq!(idle.read(&mut reply).unwrap(), 0);
+    }
+}
 
-    // Wait for active threads to complete
-    if let Some(ref state) = thread_state {
-        let (lock, cvar) = state.as_ref();
-        let mut count = lock.lock().unwrap();
-        while *count > 0 {
-            eprintln!("Waiting for {} threads to complete", *count);
-            let result = cvar.wait_timeout(count, Duration::from_secs(1)).unwrap();
-            count = result.0;
+#[test]
+fn test_killed_by_rlimit() {
+    assert!(killed_by_rlimit(Signal::SIGXCPU, false, false));
+    assert!(killed_by_rlimit(Signal::SIGABRT, true, false));
+    assert!(killed_by_rlimit(Signal::SIGSEGV, true, false));
+    assert!(killed_by_rlimit(Signal::SIGKILL, false, true));
+    assert!(!killed_by_rlimit(Signal::SIGABRT, false, false));
+    assert!(!killed_by_rlimit(Signal::SIGSEGV, false, false));
+    // a segfault is a crash unless the address space is limited
+    assert!(!killed_by_rlimit(Signal::SIGSEGV, false, true));
+    assert!(!killed_by_rlimit(Signal::SIGKILL, true, false));
+    assert!(!killed_by_rlimit(Signal::SIGBUS, true, true));
+    assert!(!killed_by_rlimit(Signal::SIGILL, true, true));
+}
+
+#[test]
+fn test_adjust_verbosity() {
+    // not VERBOSITY, which other tests running concurrently read
+    let verbosity = AtomicU8::new(0);
+    assert_eq!(adjust_verbosity(&verbosity, true), 1);
+    assert_eq!(adjust_verbosity(&verbosity, true), 2);
+    assert_eq!(adjust_verbosity(&verbosity, true), 2);
+    assert_eq!(adjust_verbosity(&verbosity, false), 1);
+    assert_eq!(adjust_verbosity(&verbosity, false), 0);
+    assert_eq!(adjust_verbosity(&verbosity, false), 0);
+}
+
+#[test]
+fn test_process_client_truncate() {
+    use crate::milter::PacketBuffer;
+
+    // the header is longer than the body bytes to receive
+    let mut packets = PacketBuffer::default();
+    packets.push(b'M', b"<a@example.com>\0");
+    packets.push(b'L', b"Subject\0a subject longer than the limit\0");
+    packets.push(b'N', b"");
+    packets.push(b'B', b"Text\r\n");
+    packets.push(b'E', b"");
+    packets.push(b'Q', b"");
+    let mut input = Vec::new();
+    packets.send(&mut input).unwrap();
+    let mut output = Vec::new();
+    let options = ProtocolOptions {
+        truncate: 16,
+        ..OPTIONS
+    };
+    process_client(&Config::builder().build(), &input[..], &mut output, options).unwrap();
+    assert_eq!(output, b"\0\0\0\x01s\0\0\0\x01a");
+}
+
+#[test]
+fn test_process_client_single_write() {
+    use crate::milter::PacketBuffer;
+    use crate::{EmailClassifier, MailInfo};
+
+    // records each write separately
+    struct Writes(Vec<Vec<u8>>);
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
     }
-    Ok(())
+    fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+        mail_info.quarantine("classified")
+    }
+    let config = Config::builder()
+        .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+        .build();
+    let mut packets = PacketBuffer::default();
+    packets.push(b'O', &[0; 12]);
+    packets.push(b'M', b"<a@example.com>\0");
+    packets.push(b'L', b"Subject\0test\0");
+    packets.push(b'N', b"");
+    packets.push(b'B', b"Text\r\n");
+    packets.push(b'E', b"");
+    packets.push(b'Q', b"");
+    let mut input = Vec::new();
+    packets.send(&mut input).unwrap();
+    let mut writes = Writes(Vec::new());
+    process_client(&config, &input[..], &mut writes, OPTIONS).unwrap();
+    // one write for the option negotiation, one for both end of message replies
+    assert_eq!(writes.0.len(), 2);
+    assert_eq!(writes.0[1], b"\0\0\0\x08qmilter\0\0\0\0\x01a");
+}
+
+#[test]
+fn test_process_client_oversized() {
+    use crate::milter::PacketBuffer;
+
+    let mut packets = PacketBuffer::default();
+    packets.push(b'M', b"<a@example.com>\0");
+    packets.push(b'B', &vec![b'x'; PACKET_MAX as usize + 1]);
+    packets.push(b'E', b"");
+    packets.push(b'M', b"<a@example.com>\0");
+    packets.push(b'B', b"Text\r\n");
+    packets.push(b'E', b"");
+    packets.push(b'Q', b"");
+    let mut input = Vec::new();
+    packets.send(&mut input).unwrap();
+    let mut output = Vec::new();
+    process_client(&Config::builder().build(), &input[..], &mut output, OPTIONS).unwrap();
+    assert_eq!(output, b"\0\0\0\x01t\0\0\0\x01a");
+    assert!(
+        health_report(&Config::builder().build())
+            .1
+            .contains("oversized packets: 1")
+    );
+
+    // a trusted client does not get the incomplete message accepted
+    let config = Config::builder()
+        .trusted_networks(&["10.0.0.0/8".parse().unwrap()])
+        .build();
+    let mut packets = PacketBuffer::default();
+    packets.push(b'D', b"M{client_addr}\x0010.1.2.3\0");
+    packets.push(b'M', b"<a@example.com>\0");
+    packets.push(b'B', &vec![b'x'; PACKET_MAX as usize + 1]);
+    packets.push(b'E', b"");
+    packets.push(b'M', b"<a@example.com>\0");
+    packets.push(b'B', b"Text\r\n");
+    packets.push(b'E', b"");
+    packets.push(b'Q', b"");
+    let mut input = Vec::new();
+    packets.send(&mut input).unwrap();
+    let mut output = Vec::new();
+    process_client(&config, &input[..], &mut output, OPTIONS).unwrap();
+    assert_eq!(output, b"\0\0\0\x01t\0\0\0\x01a");
+
+    let mut packets = PacketBuffer::default();
+    packets.push(b'C', &vec![b'x'; PACKET_MAX as usize + 1]);
+    let mut input = Vec::new();
+    packets.send(&mut input).unwrap();
+    assert!(
+        process_client(
+            &Config::builder().build(),
+            &input[..],
+            &mut Vec::new(),
+            OPTIONS
+        )
+        .is_err()
+    );
 }
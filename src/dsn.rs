@@ -0,0 +1,185 @@
+//! Delivery status notification (DSN, RFC 3464) parsing for bounce handling.
+//!
+//! Bounces are sent with an empty envelope sender, see
+//! [`MailInfo::is_bounce()`](crate::MailInfo::is_bounce). Standard bounces can be parsed
+//! with [`MailInfo::delivery_status()`](crate::MailInfo::delivery_status), e.g. to check
+//! that the bounced message was actually sent by us and not forged (backscatter).
+//!
+//! # Example
+//!
+//! ```ignore
+//! if mail_info.is_bounce() {
+//!     if let Some(dsn) = mail_info.delivery_status()
+//!         && !dsn.original_message_id.ends_with("@example.com")
+//!     {
+//!         return mail_info.reject("bounce for a message we did not send");
+//!     }
+//! }
+//! ```
+
+use crate::MailInfo;
+use crate::arf::{anglestrip, parse_fields};
+use mail_parser::{MessageParser, MimeHeaders as _};
+
+/// Per-recipient fields of a delivery status notification.
+///
+/// Text fields are `""` when the corresponding field is missing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DsnRecipient {
+    /// Address of `Final-Recipient:`, without the address type.
+    pub final_recipient: String,
+    /// Address of `Original-Recipient:`, without the address type.
+    pub original_recipient: String,
+    /// `Action:`, lowercased (`"failed"`, `"delayed"`, `"delivered"`, `"relayed"`,
+    /// `"expanded"`).
+    pub action: String,
+    /// `Status:`, the enhanced status code (e.g. `"5.1.1"`).
+    pub status: String,
+    /// `Remote-MTA:`, without the name type.
+    pub remote_mta: String,
+    /// `Diagnostic-Code:`, without the diagnostic type.
+    pub diagnostic_code: String,
+}
+
+/// Information extracted from a delivery status notification.
+///
+/// Text fields are `""` when the corresponding field is missing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryStatus {
+    /// `Reporting-MTA:`, without the name type.
+    pub reporting_mta: String,
+    /// `Original-Envelope-Id:` of the bounced message.
+    pub original_envelope_id: String,
+    /// Per-recipient fields.
+    pub recipients: Vec<DsnRecipient>,
+    /// Address from the `From:` header of the bounced message.
+    pub original_from: String,
+    /// `Subject:` of the bounced message.
+    pub original_subject: String,
+    /// `Message-ID:` of the bounced message.
+    pub original_message_id: String,
+}
+
+// Strips the type of a typed field (e.g. `rfc822; user@example.com`).
+fn untyped(value: &str) -> String {
+    let value = value.split_once(';').map(|(_, v)| v).unwrap_or(value);
+    anglestrip(value.trim()).to_string()
+}
+
+pub(crate) fn delivery_status(mail_info: &MailInfo) -> Option<DeliveryStatus> {
+    let msg = mail_info.get_message();
+    if !msg
+        .content_type()
+        .map(|c| c.ctype().eq_ignore_ascii_case("multipart") && c.subtype() == Some("report"))
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    let status_part = msg.parts.iter().find(|p| {
+        p.is_content_type("message", "delivery-status")
+            || p.is_content_type("message", "global-delivery-status")
+    })?;
+    let mut status = DeliveryStatus::default();
+    let text = String::from_utf8_lossy(status_part.contents()).replace("\r\n", "\n");
+    for (i, block) in text
+        .split("\n\n")
+        .filter(|b| !b.trim().is_empty())
+        .enumerate()
+    {
+        let fields = parse_fields(block);
+        if i == 0 {
+            for (name, value) in fields {
+                match name.as_str() {
+                    "reporting-mta" => status.reporting_mta = untyped(&value),
+                    "original-envelope-id" => status.original_envelope_id = value,
+                    _ => (),
+                }
+            }
+            continue;
+        }
+        let mut recipient = DsnRecipient::default();
+        for (name, value) in fields {
+            match name.as_str() {
+                "final-recipient" => recipient.final_recipient = untyped(&value),
+                "original-recipient" => recipient.original_recipient = untyped(&value),
+                "action" => recipient.action = value.to_ascii_lowercase(),
+                "status" => recipient.status = value,
+                "remote-mta" => recipient.remote_mta = untyped(&value),
+                "diagnostic-code" => recipient.diagnostic_code = untyped(&value),
+                _ => (),
+            }
+        }
+        status.recipients.push(recipient);
+    }
+    let original = msg
+        .parts
+        .iter()
+        .find_map(|p| p.message())
+        .cloned()
+        .or_else(|| {
+            msg.parts
+                .iter()
+                .find(|p| p.is_content_type("text", "rfc822-headers"))
+                .and_then(|p| MessageParser::default().parse_headers(p.contents()))
+        });
+    if let Some(original) = original {
+        status.original_from = original
+            .from()
+            .and_then(|v| v.first())
+            .and_then(|v| v.address())
+            .unwrap_or("")
+            .to_string();
+        status.original_subject = original.subject().unwrap_or("").to_string();
+        status.original_message_id = original.message_id().unwrap_or("").to_string();
+    }
+    Some(status)
+}
+
+#[test]
+fn test_delivery_status() {
+    use crate::MailInfoStorage;
+
+    let storage = MailInfoStorage {
+        mail_buffer: std::fs::read("tests/parse_006.eml").unwrap(),
+        ..Default::default()
+    };
+    let mail_info = MailInfo::new(
+        &storage,
+        MessageParser::default()
+            .parse(&storage.mail_buffer)
+            .unwrap(),
+    );
+    assert!(mail_info.is_bounce());
+    let dsn = mail_info.delivery_status().unwrap();
+    assert_eq!(dsn.reporting_mta, "mx.remote.example");
+    assert_eq!(
+        dsn.recipients,
+        [DsnRecipient {
+            final_recipient: "nobody@remote.example".into(),
+            original_recipient: "nobody@remote.example".into(),
+            action: "failed".into(),
+            status: "5.1.1".into(),
+            remote_mta: "mailstore.remote.example".into(),
+            diagnostic_code: "550 5.1.1 <nobody@remote.example>: Recipient address \
+                rejected: User unknown"
+                .into(),
+        }]
+    );
+    assert_eq!(dsn.original_from, "alice@example.com");
+    assert_eq!(dsn.original_subject, "Meeting");
+    assert_eq!(dsn.original_message_id, "0123456789.abcdef@example.com");
+
+    let storage = MailInfoStorage {
+        sender: "alice@example.com".into(),
+        mail_buffer: std::fs::read("tests/parse_005.eml").unwrap(),
+        ..Default::default()
+    };
+    let mail_info = MailInfo::new(
+        &storage,
+        MessageParser::default()
+            .parse(&storage.mail_buffer)
+            .unwrap(),
+    );
+    assert!(!mail_info.is_bounce());
+    assert_eq!(mail_info.delivery_status(), None);
+}
@@ -0,0 +1,274 @@
+//! Bounded HTML to text conversion.
+//!
+//! HTML bodies are attacker-supplied, and a full HTML to Markdown conversion can take
+//! very long on crafted input. [`to_text()`] only converts inputs up to
+//! [`HtmlLimits::max_input`] bytes and gives up after [`HtmlLimits::timeout`]. In both
+//! cases it falls back to simple tag stripping, which runs in linear time.
+//!
+//! The limits used by the `dump` command are set with
+//! [`ConfigBuilder::html_limits()`](crate::ConfigBuilder::html_limits). The daemon does
+//! not convert HTML; [`MailInfo::get_text()`](crate::MailInfo::get_text) uses
+//! the text conversion of `mail_parser`.
+//!
+//! [`credential_forms()`] looks for the typical signs of credential harvesting in HTML
+//! bodies: password input fields and forms or pages embedded as `data:` URIs. Legitimate
+//! mail practically never contains either, so a hit is a strong phishing signal on its own.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Limits for [`to_text()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HtmlLimits {
+    /// Maximum input size in bytes for the full conversion (default 1 MiB).
+    pub max_input: usize,
+    /// Maximum time for the full conversion (default 1s).
+    pub timeout: Duration,
+}
+
+impl Default for HtmlLimits {
+    fn default() -> Self {
+        HtmlLimits {
+            max_input: 1 << 20,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Maximum number of conversion threads running at the same time, including those which
+/// timed out and are still running.
+pub const MAX_WORKERS: usize = 16;
+
+static WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+// Decrements WORKERS when a conversion thread ends.
+struct WorkerGuard;
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        WORKERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Converts `html` to Markdown text within `limits`, falling back to [`strip_tags()`].
+///
+/// The conversion runs in a separate thread. If it times out, the thread is left running
+/// until the conversion finishes, but its result is discarded. While [`MAX_WORKERS`]
+/// threads are running, [`strip_tags()`] is used right away.
+pub fn to_text(html: &str, limits: &HtmlLimits) -> String {
+    if html.len() > limits.max_input {
+        return strip_tags(html);
+    }
+    if WORKERS.fetch_add(1, Ordering::Relaxed) >= MAX_WORKERS {
+        WORKERS.fetch_sub(1, Ordering::Relaxed);
+        return strip_tags(html);
+    }
+    let guard = WorkerGuard;
+    let (sender, receiver) = mpsc::channel();
+    let input = html.to_string();
+    let spawned = thread::Builder::new()
+        .name("html2md".into())
+        .spawn(move || {
+            let _guard = guard;
+            let _ = sender.send(html2md::rewrite_html(input.as_str(), false));
+        });
+    match spawned {
+        Ok(_) => receiver
+            .recv_timeout(limits.timeout)
+            .unwrap_or_else(|_| strip_tags(html)),
+        Err(_) => strip_tags(html),
+    }
+}
+
+/// Removes tags, comments and the contents of `<script>` and `<style>` elements from
+/// `html` and decodes the most common character entities.
+pub fn strip_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len() / 2);
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        push_decoded(&mut out, &rest[..start]);
+        rest = &rest[start..];
+        let starts_with = |tag: &str| {
+            rest.as_bytes()
+                .get(..tag.len())
+                .is_some_and(|b| b.eq_ignore_ascii_case(tag.as_bytes()))
+        };
+        let end_marker = if rest.starts_with("<!--") {
+            "-->"
+        } else if starts_with("<script") {
+            "</script>"
+        } else if starts_with("<style") {
+            "</style>"
+        } else {
+            ">"
+        };
+        let end = if end_marker.len() > 3 {
+            find_ascii_case_insensitive(rest, end_marker)
+        } else {
+            rest.find(end_marker)
+        };
+        match end {
+            Some(end) => rest = &rest[end + end_marker.len()..],
+            None => rest = "",
+        }
+        out.push(' ');
+    }
+    push_decoded(&mut out, rest);
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Credential-harvesting signals found by [`credential_forms()`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CredentialForms {
+    /// Number of `<input type="password">` fields.
+    pub password_inputs: usize,
+    /// Number of forms submitting to a `data:` URI and links or frames to `data:text/html`.
+    pub data_uri_forms: usize,
+}
+
+impl CredentialForms {
+    /// Returns `true` if any signal was found.
+    pub fn found(&self) -> bool {
+        self.password_inputs > 0 || self.data_uri_forms > 0
+    }
+}
+
+/// Scans `html` for password input fields and `data:` URI forms.
+///
+/// The scan works on the raw tags and runs in linear time, so it is safe on untrusted
+/// input of any size.
+pub fn credential_forms(html: &str) -> CredentialForms {
+    let mut forms = CredentialForms::default();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest.find('>').unwrap_or(rest.len());
+        let tag = rest[..end].to_ascii_lowercase();
+        rest = &rest[end..];
+        let name = tag
+            .split(|c: char| c.is_ascii_whitespace() || c == '/')
+            .next();
+        match name {
+            Some("input") if attribute(&tag, "type") == Some("password") => {
+                forms.password_inputs += 1;
+            }
+            Some("form") if attribute(&tag, "action").is_some_and(|a| a.starts_with("data:")) => {
+                forms.data_uri_forms += 1;
+            }
+            Some("a" | "iframe" | "frame" | "object" | "embed") => {
+                let target = ["href", "src", "data"]
+                    .iter()
+                    .find_map(|name| attribute(&tag, name));
+                if target.is_some_and(|t| t.starts_with("data:text/html")) {
+                    forms.data_uri_forms += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    forms
+}
+
+// Returns the value of attribute `name` in the lowercased `tag` contents.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(pos) = rest.find(name) {
+        let preceded_by_space = rest[..pos]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_ascii_whitespace());
+        let after = rest[pos + name.len()..].trim_start();
+        rest = &rest[pos + name.len()..];
+        if !preceded_by_space {
+            continue;
+        }
+        let Some(value) = after.strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        return Some(match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let value = &value[1..];
+                value[..value.find(quote).unwrap_or(value.len())].trim()
+            }
+            _ => value
+                .split(|c: char| c.is_ascii_whitespace() || c == '/')
+                .next()
+                .unwrap_or(""),
+        });
+    }
+    None
+}
+
+fn find_ascii_case_insensitive(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+fn push_decoded(out: &mut String, text: &str) {
+    const ENTITIES: [(&str, &str); 6] = [
+        ("&amp;", "&"),
+        ("&lt;", "<"),
+        ("&gt;", ">"),
+        ("&quot;", "\""),
+        ("&#39;", "'"),
+        ("&nbsp;", " "),
+    ];
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        match ENTITIES.iter().find(|(e, _)| rest.starts_with(e)) {
+            Some((entity, replacement)) => {
+                out.push_str(replacement);
+                rest = &rest[entity.len()..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+}
+
+#[test]
+fn test_strip_tags() {
+    assert_eq!(
+        strip_tags(
+            "<html><STYLE>p {}</STYLE><p>Hello&nbsp;<b>World</b> &amp; co</p>\
+             <!-- <p>hidden</p> --><script>alert(1)</SCRIPT>&copy; x < y"
+        ),
+        "Hello World & co &copy; x"
+    );
+    let limits = HtmlLimits {
+        max_input: 10,
+        ..Default::default()
+    };
+    assert_eq!(to_text("<p>Hello World</p>", &limits), "Hello World");
+    assert_eq!(strip_tags("<aé>x<sé>y<styl"), "x y");
+}
+
+#[test]
+fn test_credential_forms() {
+    let forms = credential_forms(
+        "<form action=\"https://example.com/login\"><INPUT Type=\"Password\" name=pw>\
+         <input type=text name=user><input data-type='password' type=hidden>\
+         <input type=password/></form>",
+    );
+    assert_eq!(forms.password_inputs, 2);
+    assert_eq!(forms.data_uri_forms, 0);
+    assert!(forms.found());
+    let forms = credential_forms(
+        "<form action='data:text/html;base64,PGgxPg=='></form>\
+         <a href=\"DATA:text/html,<h1>Login</h1>\">Open</a><a href=\"https://x\">x</a>\
+         <img src=\"data:image/png;base64,AAAA\">",
+    );
+    assert_eq!(forms.password_inputs, 0);
+    assert_eq!(forms.data_uri_forms, 2);
+    assert!(!credential_forms("<p>Enter your password</p>").found());
+}
@@ -1,25 +1,95 @@
+use crate::arf::FeedbackReport;
+use crate::attachment::Attachment;
+use crate::bulk::{BulkProfile, OneClickUnsubscribe};
+use crate::dsn::DeliveryStatus;
+use crate::html::{CredentialForms, HtmlLimits};
+use crate::network::Network;
+use crate::recipient::RecipientStatus;
+use crate::scripts::{ScriptReport, ScriptStats};
 use mail_parser::{HeaderName, MessageParser};
+use std::any::Any;
 use std::borrow::Cow::Borrowed;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::{BufRead as _, BufReader};
 use std::net::IpAddr;
-use std::sync::Arc;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
+pub mod addresses;
+pub mod arf;
+pub mod attachment;
+pub mod batv;
+pub mod bulk;
+pub mod bypass;
+pub mod circuit_breaker;
 pub mod cli;
+#[cfg(feature = "daemon")]
 mod daemon;
+pub mod dsn;
+pub mod html;
+#[cfg(feature = "daemon")]
+mod loadgen;
+#[cfg(feature = "daemon")]
 mod milter;
+pub mod network;
+pub mod notify;
+#[cfg(feature = "phishing")]
+pub mod phishing;
+#[cfg(feature = "daemon")]
 mod reader_extention;
+pub mod recipient;
+pub mod score;
+pub mod scripts;
+pub mod secrets;
+pub mod sieve;
+#[cfg(feature = "daemon")]
+mod simulate;
 pub mod spamhaus_zen;
+pub mod srs;
+pub mod testing;
+#[cfg(feature = "daemon")]
+mod trace;
+
+#[cfg(all(feature = "daemon", not(unix)))]
+compile_error!("feature `daemon` requires a Unix platform");
 
 #[derive(Default)]
 struct MailInfoStorage {
     sender: String,
+    mail_parameters: Vec<String>, // ESMTP parameters of MAIL FROM
     recipients: Vec<String>,
+    recipient_parameters: Vec<Vec<String>>, // ESMTP parameters of each RCPT TO
+    rejected_recipients: Vec<String>,       // rejected by Postfix, only with SMFIP_RCPT_REJ
     macros: HashMap<String, String>,
-    id: String, // postfix queue ident
-    mail_buffer: Vec<u8>,
+    id: String,                   // postfix queue ident
+    mail_buffer: Vec<u8>,         // header and body with CRLF line endings
+    received_at: Option<Instant>, // start of the message on the milter connection
+}
+
+#[cfg(feature = "daemon")]
+impl MailInfoStorage {
+    // Capacity of mail_buffer kept for the next message on the same connection. Larger
+    // buffers are shrunk, so a single big message does not pin its memory until disconnect.
+    const MAIL_BUFFER_KEEP: usize = 1 << 20;
+
+    /// Resets the storage for the next message, keeping allocated capacity.
+    fn clear(&mut self) {
+        self.sender.clear();
+        self.mail_parameters.clear();
+        self.recipients.clear();
+        self.recipient_parameters.clear();
+        self.rejected_recipients.clear();
+        self.macros.clear();
+        self.id.clear();
+        self.mail_buffer.clear();
+        self.mail_buffer.shrink_to(Self::MAIL_BUFFER_KEEP);
+        self.received_at = None;
+    }
 }
 
 /// Provides read-only access to a parsed email message.
@@ -38,11 +108,63 @@ struct MailInfoStorage {
 /// # Decision Methods
 ///
 /// When a classifier reaches a final decision, it should use one of the decision methods:
-/// [`accept`](Self::accept), [`reject`](Self::reject), or [`quarantine`](Self::quarantine).
-/// These methods log the decision with a reason and return the appropriate [`ClassifyResult`].
+/// [`accept`](Self::accept), [`reject`](Self::reject), [`quarantine`](Self::quarantine) or
+/// [`tempfail`](Self::tempfail). These methods log the decision with a reason and return the
+/// appropriate [`ClassifyResult`].
 pub struct MailInfo<'a> {
     storage: &'a MailInfoStorage,
     msg: mail_parser::Message<'a>,
+    // lazily computed values, so that accessors can be called freely
+    text: OnceLock<String>,
+    urls: OnceLock<Vec<String>>,
+    normalized_subject: OnceLock<String>,
+    // set by the decision and action methods; behind locks, so that classifiers can share
+    // the MailInfo with threads
+    actions: Mutex<ClassifierActions>,
+    // suppresses logging while the shadow classifier runs
+    quiet: AtomicBool,
+    // messages logged with log(), see Decision::log
+    logged: Mutex<Vec<String>>,
+}
+
+// What the classifier asked for besides its result.
+#[derive(Default)]
+struct ClassifierActions {
+    // reason given to the last decision method, see testing::TestMail::classify
+    reason: String,
+    // hidden recipients
+    added_recipients: Vec<String>,
+    // new envelope sender
+    changed_sender: Option<String>,
+    // delay of the reply
+    delay: Duration,
+}
+
+impl<'a> MailInfo<'a> {
+    fn new(storage: &'a MailInfoStorage, msg: mail_parser::Message<'a>) -> Self {
+        MailInfo {
+            storage,
+            msg,
+            text: OnceLock::new(),
+            urls: OnceLock::new(),
+            normalized_subject: OnceLock::new(),
+            actions: Mutex::new(ClassifierActions::default()),
+            quiet: AtomicBool::new(false),
+            logged: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Returns the verdict for `result` with the actions requested by the classifier.
+    fn into_verdict(self, result: ClassifyResult) -> Verdict {
+        let actions = self.actions.into_inner().unwrap();
+        Verdict {
+            added_recipients: actions.added_recipients,
+            changed_sender: actions.changed_sender,
+            delay: actions.delay,
+            log: self.logged.into_inner().unwrap(),
+            ..Verdict::new(result, actions.reason)
+        }
+    }
 }
 
 impl MailInfo<'_> {
@@ -76,6 +198,76 @@ impl MailInfo<'_> {
             .and_then(|v| v.address())
             .unwrap_or("")
     }
+    /// Returns the `To:` and `Cc:` addresses which are not envelope recipients but resemble
+    /// an address in `protected`, see [`addresses::lookalike()`].
+    ///
+    /// Attackers add near-miss addresses of local users (`ceo@examp1e.com`) to the visible
+    /// recipients, so that replies to all go to them. Addresses which are envelope
+    /// recipients themselves are not reported.
+    pub fn get_lookalike_recipients(&self, protected: &[String]) -> Vec<&str> {
+        let mut found: Vec<&str> = Vec::new();
+        for name in [HeaderName::To, HeaderName::Cc] {
+            let Some(list) = self.msg.header(name).and_then(|v| v.as_address()) else {
+                continue;
+            };
+            for address in list.iter().filter_map(|addr| addr.address()) {
+                if !addresses::contains(self.get_recipients(), address)
+                    && addresses::lookalike(protected, address).is_some()
+                    && !found.contains(&address)
+                {
+                    found.push(address);
+                }
+            }
+        }
+        found
+    }
+    /// Returns the message IDs from the `References:` and `In-Reply-To:` headers, without
+    /// angle brackets.
+    ///
+    /// The IDs are returned in the order of `References:` (oldest first), followed by
+    /// `In-Reply-To:` IDs not already listed there, without duplicates.
+    pub fn references(&self) -> Vec<&str> {
+        let mut ids: Vec<&str> = Vec::new();
+        for name in [HeaderName::References, HeaderName::InReplyTo] {
+            let value = self.msg.header(name).and_then(|v| v.as_text_list());
+            for id in value.unwrap_or_default() {
+                if !ids.contains(&id.as_ref()) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids
+    }
+    /// Checks whether a reply comes from a domain which never took part in the thread.
+    ///
+    /// `thread_domains` is called for each of the [`references()`](Self::references) and
+    /// returns the sender domains recorded for that message ID, typically from a store of
+    /// past correspondence kept by the classifier. The message is considered a hijack if it
+    /// has an `In-Reply-To:` header, domains are known for the thread and the domain of the
+    /// `From:` address is neither one of them nor a subdomain of one. Messages continuing
+    /// unknown threads are not flagged.
+    pub fn is_thread_hijack(&self, thread_domains: impl Fn(&str) -> Vec<String>) -> bool {
+        if self.msg.header(HeaderName::InReplyTo).is_none() {
+            return false;
+        }
+        let known: Vec<String> = self
+            .references()
+            .into_iter()
+            .flat_map(thread_domains)
+            .collect();
+        if known.is_empty() {
+            return false;
+        }
+        let from = self.get_from_address().to_ascii_lowercase();
+        let domain = from.rsplit_once('@').map_or("", |(_, domain)| domain);
+        !known.iter().any(|known| {
+            let known = known.to_ascii_lowercase();
+            domain == known
+                || domain
+                    .strip_suffix(known.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+    }
     /// Returns the display name from the `To:` header.
     pub fn get_to_name(&self) -> &str {
         self.msg
@@ -93,18 +285,112 @@ impl MailInfo<'_> {
             .and_then(|v| v.as_text())
             .unwrap_or("")
     }
+    /// Returns the subject normalized for comparisons.
+    ///
+    /// The subject is lowercased, reply and forward prefixes (`Re:`, `Fwd:`, `AW:`, ...) are
+    /// removed and runs of whitespace are collapsed into a single space.
+    pub fn get_normalized_subject(&self) -> &str {
+        self.normalized_subject.get_or_init(|| {
+            let mut subject = self.get_subject().trim().to_lowercase();
+            while let Some((prefix, rest)) = subject.split_once(':')
+                && matches!(
+                    prefix.trim(),
+                    "re" | "fw" | "fwd" | "aw" | "wg" | "sv" | "tr"
+                )
+            {
+                subject = rest.trim_start().to_string();
+            }
+            subject.split_whitespace().collect::<Vec<_>>().join(" ")
+        })
+    }
     /// Returns the SMTP envelope sender (MAIL FROM address).
     pub fn get_sender(&self) -> &str {
         &self.storage.sender
     }
     /// Returns the first text/plain body part of the message.
+    ///
+    /// If the message has only an HTML body, it is converted to text. The result is computed
+    /// once and cached, so repeated calls are cheap.
     pub fn get_text(&self) -> std::borrow::Cow<'_, str> {
-        self.msg.body_text(0).unwrap_or(Borrowed(""))
+        Borrowed(
+            self.text
+                .get_or_init(|| self.msg.body_text(0).unwrap_or(Borrowed("")).into_owned()),
+        )
+    }
+    /// Returns the `http://` and `https://` URLs found in the text and HTML bodies.
+    ///
+    /// URLs are returned in order of their first occurrence, without duplicates. The result
+    /// is computed once and cached.
+    pub fn get_urls(&self) -> &[String] {
+        self.urls.get_or_init(|| {
+            let mut urls: Vec<String> = Vec::new();
+            for part in self.msg.text_bodies().chain(self.msg.html_bodies()) {
+                if let Some(text) = part.text_contents() {
+                    for url in find_urls(text) {
+                        if !urls.iter().any(|u| u == url) {
+                            urls.push(url.to_string());
+                        }
+                    }
+                }
+            }
+            urls
+        })
+    }
+    /// Returns the credential-harvesting signals of the HTML bodies, see
+    /// [`html::credential_forms()`].
+    ///
+    /// Password input fields and `data:` URI forms in mail are near-certain phishing.
+    pub fn credential_forms(&self) -> CredentialForms {
+        let mut forms = CredentialForms::default();
+        for part in self.msg.html_bodies().filter(|part| part.is_text_html()) {
+            if let Some(html) = part.text_contents() {
+                let part_forms = html::credential_forms(html);
+                forms.password_inputs += part_forms.password_inputs;
+                forms.data_uri_forms += part_forms.data_uri_forms;
+            }
+        }
+        forms
     }
     /// Returns all SMTP envelope recipients (RCPT TO addresses).
     pub fn get_recipients(&self) -> &[String] {
         &self.storage.recipients
     }
+    /// Returns the ESMTP parameters of the MAIL FROM command (e.g. `"SIZE=1234"`,
+    /// `"BODY=8BITMIME"`).
+    pub fn get_mail_parameters(&self) -> &[String] {
+        &self.storage.mail_parameters
+    }
+    /// Returns the value of the ESMTP parameter `name` of the MAIL FROM command.
+    ///
+    /// The name is compared case-insensitively. Returns `Some("")` for a parameter without
+    /// value (e.g. `SMTPUTF8`) and `None` if the parameter was not given.
+    pub fn get_mail_parameter(&self, name: &str) -> Option<&str> {
+        esmtp_parameter(&self.storage.mail_parameters, name)
+    }
+    /// Returns the ESMTP parameters of the RCPT TO command for `recipient` (e.g.
+    /// `"NOTIFY=NEVER"`).
+    pub fn get_recipient_parameters(&self, recipient: &str) -> &[String] {
+        self.storage
+            .recipients
+            .iter()
+            .position(|r| r == recipient)
+            .and_then(|i| self.storage.recipient_parameters.get(i))
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+    /// Returns the value of the ESMTP parameter `name` of the RCPT TO command for
+    /// `recipient`, see [`get_mail_parameter()`](Self::get_mail_parameter).
+    pub fn get_recipient_parameter(&self, recipient: &str, name: &str) -> Option<&str> {
+        esmtp_parameter(self.get_recipient_parameters(recipient), name)
+    }
+    /// Returns the recipients Postfix already rejected (e.g. unknown users).
+    ///
+    /// These are not included in [`get_recipients()`](Self::get_recipients). The list is
+    /// only filled when the daemon runs with `--rcpt-rej`; many rejected recipients may
+    /// indicate a dictionary attack.
+    pub fn get_rejected_recipients(&self) -> &[String] {
+        &self.storage.rejected_recipients
+    }
     /// Returns the single recipient if there is exactly one, otherwise `""`.
     pub fn get_only_recipient(&self) -> &str {
         if self.storage.recipients.len() == 1 {
@@ -117,10 +403,95 @@ impl MailInfo<'_> {
     pub fn get_id(&self) -> &str {
         &self.storage.id
     }
+    /// Returns the value of the milter macro `name` (e.g. `"{auth_authen}"`), or `""` if
+    /// Postfix did not send it.
+    ///
+    /// Which macros are sent is configured in Postfix with `milter_*_macros`. Macros of the
+    /// connect stage and of the current message are available.
+    pub fn get_macro(&self, name: &str) -> &str {
+        self.storage
+            .macros
+            .get(name)
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+    /// Returns the TLS protocol version of the SMTP connection (e.g. `"TLSv1.3"`), or `""` if
+    /// TLS was not used.
+    ///
+    /// Postfix sends the TLS macros at the HELO stage, which srmilter does not receive.
+    /// Add them to the MAIL stage in `main.cf`:
+    ///
+    /// ```text
+    /// milter_mail_macros = i {auth_type} {auth_authen} {auth_author} {mail_addr}
+    ///     {mail_host} {mail_mailer} {tls_version} {cipher} {cert_subject} {cert_issuer}
+    /// ```
+    pub fn get_tls_version(&self) -> &str {
+        self.get_macro("{tls_version}")
+    }
+    /// Returns the TLS cipher of the SMTP connection, or `""` if TLS was not used.
+    pub fn get_tls_cipher(&self) -> &str {
+        self.get_macro("{cipher}")
+    }
+    /// Returns `true` if the SMTP connection used TLS.
+    ///
+    /// See [`get_tls_version()`](Self::get_tls_version) for the required Postfix configuration.
+    pub fn is_tls(&self) -> bool {
+        !self.get_tls_version().is_empty()
+    }
+    /// Returns `true` if the SMTP client presented a certificate which Postfix verified.
+    ///
+    /// Postfix sends `{cert_subject}` only for verified client certificates.
+    pub fn is_client_cert_verified(&self) -> bool {
+        !self.get_macro("{cert_subject}").is_empty()
+    }
+    /// Checks the envelope sender domain against a list of domains which must use TLS.
+    ///
+    /// Returns `true` if the sender domain is in `domains` (or is a subdomain of an entry)
+    /// and the connection did not use TLS. `domains` is typically loaded with
+    /// [`read_array`].
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// if mail_info.violates_tls_policy(&ctx.tls_required_domains) {
+    ///     return mail_info.reject("partner domain without TLS");
+    /// }
+    /// ```
+    pub fn violates_tls_policy(&self, domains: &[String]) -> bool {
+        if self.is_tls() {
+            return false;
+        }
+        let Some((_, domain)) = self.get_sender().rsplit_once('@') else {
+            return false;
+        };
+        let domain = domain.as_bytes();
+        domains.iter().map(|d| d.as_bytes()).any(|d| {
+            domain.eq_ignore_ascii_case(d)
+                || (domain.len() > d.len()
+                    && domain[domain.len() - d.len()..].eq_ignore_ascii_case(d)
+                    && domain[domain.len() - d.len() - 1] == b'.')
+        })
+    }
     /// Returns the full parsed message for advanced access via `mail_parser`.
     pub fn get_message(&self) -> &mail_parser::Message<'_> {
         &self.msg
     }
+    /// Returns an iterator over the attachments of the message.
+    pub fn attachments(&self) -> impl Iterator<Item = Attachment<'_>> {
+        self.msg.attachments().map(Attachment::new)
+    }
+    /// Returns the number of attachments.
+    pub fn attachment_count(&self) -> usize {
+        self.attachments().count()
+    }
+    /// Returns the sum of the decoded sizes of all attachments in bytes.
+    pub fn total_attachment_size(&self) -> usize {
+        self.attachments().map(|a| a.size()).sum()
+    }
+    /// Returns the attachment with the largest decoded size, if any.
+    pub fn largest_attachment(&self) -> Option<Attachment<'_>> {
+        self.attachments().max_by_key(|a| a.size())
+    }
     /// Returns the value of any header by name.
     // Explicit lifetime required: HeaderName::Other takes Cow<'a, str> and the
     // lifetime propagates through the method chain, constraining the return type.
@@ -130,7 +501,11 @@ impl MailInfo<'_> {
             .and_then(|v| v.as_text())
             .unwrap_or("")
     }
-    /// Returns the parsed `X-Spam-Score` header value, or `0.0` if missing or invalid.
+    /// Returns the parsed value of the last `X-Spam-Score` header, or `0.0` if missing or
+    /// invalid.
+    ///
+    /// Use [`ScoreHeaders`](score::ScoreHeaders) to read other headers or to ignore forged
+    /// ones.
     pub fn get_spam_score(&self) -> f32 {
         self.msg
             .header(HeaderName::Other(Borrowed("X-Spam-Score")))
@@ -138,6 +513,131 @@ impl MailInfo<'_> {
             .and_then(|v| v.parse::<f32>().ok())
             .unwrap_or(0f32)
     }
+    // All values of header `name`, topmost first.
+    pub(crate) fn header_values(&self, name: &str) -> Vec<&str> {
+        self.msg
+            .headers()
+            .iter()
+            .filter(|h| h.name().eq_ignore_ascii_case(name))
+            .filter_map(|h| h.value.as_text())
+            .collect()
+    }
+    /// Returns the values of header `name` which were added by trusted hosts, topmost first.
+    ///
+    /// Only instances above the first trusted `Received:` header (see
+    /// [`get_trusted_received_header()`](Self::get_trusted_received_header) for
+    /// `good_domain`) are returned. All others were already present when the message
+    /// reached the trusted host and may be forged by the sender. Use this for upstream
+    /// verdict headers like `Authentication-Results:` or `X-Spam-Score:`. Returns an empty
+    /// list if there is no trusted `Received:` header.
+    pub fn get_trusted_headers(&self, name: &str, good_domain: &str) -> Vec<&str> {
+        let trusted = self.trusted_header_count(good_domain);
+        self.msg.headers()[..trusted]
+            .iter()
+            .filter(|h| h.name().eq_ignore_ascii_case(name))
+            .filter_map(|h| h.value.as_text())
+            .collect()
+    }
+    // Number of headers above the first trusted `Received:` header.
+    fn trusted_header_count(&self, good_domain: &str) -> usize {
+        self.msg
+            .headers()
+            .iter()
+            .position(|h| {
+                matches!(&h.value, mail_parser::HeaderValue::Received(r)
+                    if matches!(&r.by, Some(mail_parser::Host::Name(by)) if by.ends_with(good_domain)))
+            })
+            .unwrap_or(0)
+    }
+    // 1-based indexes among the instances of header `name` which are not trusted, see
+    // get_trusted_headers(), as used by SMFIR_CHGHEADER.
+    fn untrusted_header_indexes(&self, name: &str, good_domain: &str) -> Vec<u32> {
+        let trusted = self.trusted_header_count(good_domain);
+        self.msg
+            .headers()
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| h.name().eq_ignore_ascii_case(name))
+            .zip(1..)
+            .filter(|((pos, _), _)| *pos >= trusted)
+            .map(|(_, index)| index)
+            .collect()
+    }
+    /// Returns a summary of the mailing-list and bulk-mail indicators of the message.
+    ///
+    /// This allows policies to treat legitimate bulk mail (newsletters, mailing lists)
+    /// differently from personal mail.
+    pub fn bulk_profile(&self) -> BulkProfile {
+        bulk::bulk_profile(self)
+    }
+    /// Validates the RFC 8058 one-click unsubscribe headers of the message.
+    ///
+    /// Only the syntax of `List-Unsubscribe:` and `List-Unsubscribe-Post:` and their coverage by a
+    /// `DKIM-Signature:` header are checked. Neither the DKIM signature itself nor the
+    /// reachability of the unsubscribe URI are verified.
+    pub fn one_click_unsubscribe(&self) -> OneClickUnsubscribe {
+        bulk::one_click_unsubscribe(self)
+    }
+    /// Parses the message as an ARF feedback report (RFC 5965).
+    ///
+    /// Returns `None` if the message is not a `multipart/report` with a
+    /// `message/feedback-report` part.
+    pub fn feedback_report(&self) -> Option<FeedbackReport> {
+        arf::feedback_report(self)
+    }
+    /// Returns `true` if the message has an empty envelope sender (`MAIL FROM:<>`).
+    ///
+    /// Bounces, delivery status notifications and some auto-replies are sent with an empty
+    /// envelope sender.
+    pub fn is_bounce(&self) -> bool {
+        self.get_sender().is_empty()
+    }
+    /// Returns the original sender if the envelope sender was rewritten with SRS by a
+    /// forwarder, see [`srs`].
+    ///
+    /// The SRS hash can only be verified by the forwarder, so the result must not be
+    /// trusted more than the envelope sender itself.
+    pub fn srs_decode(&self) -> Option<String> {
+        srs::parse(self.get_sender())
+    }
+    /// Rewrites the envelope sender to an SRS address in `domain` with
+    /// [`change_sender()`](Self::change_sender), for messages forwarded to another domain.
+    ///
+    /// Bounces and senders in `domain` are not rewritten. `secret` must be the one used to
+    /// [decode](srs::decode) bounces to the rewritten addresses.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// if mail_info.get_recipients().iter().any(|r| ctx.forwarded.contains(r)) {
+    ///     mail_info.srs_forward(&ctx.srs_secret, "fwd.example.org");
+    /// }
+    /// ```
+    pub fn srs_forward(&self, secret: &[u8], domain: &str) {
+        let sender = self.get_sender();
+        let local = sender
+            .rsplit_once('@')
+            .is_some_and(|(_, d)| d.eq_ignore_ascii_case(domain));
+        if !sender.is_empty() && !local {
+            self.change_sender(&srs::encode(sender, secret, domain, batv::today()));
+        }
+    }
+    /// Returns the letter counts per script of subject, `From:` display name and text body,
+    /// see [`scripts`].
+    pub fn script_report(&self) -> ScriptReport {
+        ScriptReport {
+            subject: ScriptStats::of(self.get_subject()),
+            from_name: ScriptStats::of(self.get_from_name()),
+            body: ScriptStats::of(&self.get_text()),
+        }
+    }
+    /// Parses the message as a delivery status notification (RFC 3464).
+    ///
+    /// Returns `None` if the message is not a `multipart/report` with a
+    /// `message/delivery-status` part.
+    pub fn delivery_status(&self) -> Option<DeliveryStatus> {
+        dsn::delivery_status(self)
+    }
     /// Returns the email address from the `Sender:` header.
     pub fn get_header_sender_address(&self) -> &str {
         self.msg
@@ -242,16 +742,175 @@ impl MailInfo<'_> {
         self.get_trusted_received_header_iter(good_domain)
             .filter_map(|r| r.from_ip)
     }
+    /// Returns an iterator over `Received:` headers starting from the real origin of the message.
+    ///
+    /// Like [`get_trusted_received_header_iter`](Self::get_trusted_received_header_iter), but
+    /// additionally skips hops that were received from one of the known `forwarders`
+    /// (e.g. alumni forwarding services or mailing list hosts). A hop is considered to come
+    /// from a forwarder when the reverse DNS name of its client ends with one of the given
+    /// domain suffixes.
+    ///
+    /// The list of forwarders can be chosen per recipient domain by the classifier:
+    ///
+    /// ```ignore
+    /// let forwarders = ctx.forwarders.get(recipient_domain).map(Vec::as_slice).unwrap_or(&[]);
+    /// let origin_ips = mail_info.origin_ip_iter(".mx.example.com", forwarders);
+    /// ```
+    pub fn get_origin_received_header_iter<'a>(
+        &'a self,
+        good_domain: &'a str,
+        forwarders: &'a [String],
+    ) -> impl Iterator<Item = &'a mail_parser::Received<'a>> {
+        self.get_trusted_received_header_iter(good_domain)
+            .skip_while(move |r| {
+                if let Some(iprev) = &r.from_iprev {
+                    forwarders.iter().any(|f| iprev.ends_with(f.as_str()))
+                } else {
+                    false
+                }
+            })
+    }
+    /// Returns an iterator over IP addresses from `Received:` headers starting from the real
+    /// origin.
+    ///
+    /// See [`get_origin_received_header_iter`](Self::get_origin_received_header_iter) for the
+    /// semantics of `good_domain` and `forwarders`. The result can be passed to
+    /// [`spamhaus_zen::ip_in_spamhaus_zen`] instead of [`foreign_ip_iter`](Self::foreign_ip_iter).
+    pub fn origin_ip_iter<'a>(
+        &'a self,
+        good_domain: &'a str,
+        forwarders: &'a [String],
+    ) -> impl Iterator<Item = IpAddr> + 'a {
+        self.get_origin_received_header_iter(good_domain, forwarders)
+            .filter_map(|r| r.from_ip)
+    }
+
+    /// Returns the original client IP reported by a trusted relay in the header `name`.
+    ///
+    /// Some relays (e.g. a border MX forwarding to an internal Postfix) record the address of
+    /// the original client in a header like `X-Originating-IP:`. Such a header is only honored
+    /// if
+    ///
+    /// - the first trusted `Received:` header (see
+    ///   [`get_trusted_received_header_iter`](Self::get_trusted_received_header_iter)) shows
+    ///   that the message was received from a host whose reverse DNS name ends with one of the
+    ///   `relays` suffixes, and
+    /// - the header is located between that `Received:` header and the next one, i.e. it was
+    ///   added by the relay itself and not by the sender.
+    ///
+    /// The value may be a plain address or enclosed in brackets (`[192.0.2.1]`).
+    pub fn get_relayed_client_ip(
+        &self,
+        name: &str,
+        good_domain: &str,
+        relays: &[String],
+    ) -> Option<IpAddr> {
+        let name = HeaderName::Other(Borrowed(name));
+        let mut headers = self.msg.headers().iter().skip_while(|h| {
+            if let mail_parser::HeaderValue::Received(r) = &h.value
+                && let Some(mail_parser::Host::Name(by)) = &r.by
+                && by.ends_with(good_domain)
+            {
+                false
+            } else {
+                true
+            }
+        });
+        let mail_parser::HeaderValue::Received(trusted) = &headers.next()?.value else {
+            return None;
+        };
+        let from_relay = trusted
+            .from_iprev
+            .as_ref()
+            .map(|iprev| relays.iter().any(|r| iprev.ends_with(r.as_str())))
+            .unwrap_or(false);
+        if !from_relay {
+            return None;
+        }
+        headers
+            .take_while(|h| h.name != HeaderName::Received)
+            .filter(|h| h.name == name)
+            .find_map(|h| h.value.as_text())
+            .and_then(|v| {
+                v.trim()
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+                    .ok()
+            })
+    }
 
     /// Logs a message to stderr with the queue ID prefix.
+    ///
+    /// The message is also recorded for [`Decision::explain()`].
     pub fn log(&self, msg: &str) {
-        eprintln!("{}: {}", self.storage.id, msg);
+        if !self.quiet.load(Ordering::Relaxed) {
+            eprintln!("{}: {}", self.storage.id, msg);
+            self.logged.lock().unwrap().push(msg.to_string());
+        }
+    }
+
+    /// Adds `address` as a hidden envelope recipient, e.g. for compliance archiving.
+    ///
+    /// The recipient gets a copy of the message without being listed in its header. It is
+    /// only added if the message is accepted or quarantined. To hand the copies to an
+    /// archival SMTP endpoint, route the address there with a Postfix `transport_maps`
+    /// entry.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// if mail_info.get_recipients().iter().any(|r| r.ends_with("@legal.example.org")) {
+    ///     mail_info.add_recipient("archive@example.org");
+    /// }
+    /// ```
+    pub fn add_recipient(&self, address: &str) {
+        let added = &mut self.actions.lock().unwrap().added_recipients;
+        if !added.iter().any(|a| a == address) {
+            added.push(address.to_string());
+        }
+    }
+
+    /// Changes the envelope sender (MAIL FROM) to `address`.
+    ///
+    /// The sender is only changed if the message is accepted or quarantined. It takes
+    /// precedence over [`ConfigBuilder::batv_signing()`].
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// mail_info.change_sender("bounces@example.org");
+    /// ```
+    pub fn change_sender(&self, address: &str) {
+        self.actions.lock().unwrap().changed_sender = Some(address.to_string());
+    }
+
+    /// Delays the reply to Postfix by `delay`, at most 60 seconds, as a tarpit for
+    /// suspected spam clients.
+    ///
+    /// The SMTP client waits for the verdict while the delay lasts, which slows down
+    /// throwaway clients at little cost. The delay is only applied if connections are
+    /// served concurrently in threads or forked processes; with `--concurrency single` or
+    /// `prefork(N)`, where it would hold up other connections, it is logged and ignored.
+    /// Keep the delay well below Postfix's `milter_content_timeout`.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// if spamhaus_zen::ip_in_spamhaus_zen(mail_info.foreign_ip_iter(GOOD_DOMAIN)) {
+    ///     mail_info.delay_reply(Duration::from_secs(30));
+    ///     return mail_info.reject("listed in Spamhaus ZEN");
+    /// }
+    /// ```
+    pub fn delay_reply(&self, delay: Duration) {
+        self.actions.lock().unwrap().delay = delay.min(Duration::from_secs(60));
     }
 
     /// Logs an acceptance message and returns [`ClassifyResult::Accept`].
     #[must_use]
     pub fn accept(&self, msg: &str) -> ClassifyResult {
         self.log(&format!("{} ({})", ClassifyResult::Accept.uc(), msg));
+        msg.clone_into(&mut self.actions.lock().unwrap().reason);
         ClassifyResult::Accept
     }
 
@@ -259,6 +918,7 @@ impl MailInfo<'_> {
     #[must_use]
     pub fn quarantine(&self, msg: &str) -> ClassifyResult {
         self.log(&format!("{} ({})", ClassifyResult::Quarantine.uc(), msg));
+        msg.clone_into(&mut self.actions.lock().unwrap().reason);
         ClassifyResult::Quarantine
     }
 
@@ -266,8 +926,157 @@ impl MailInfo<'_> {
     #[must_use]
     pub fn reject(&self, msg: &str) -> ClassifyResult {
         self.log(&format!("{} ({})", ClassifyResult::Reject.uc(), msg));
+        msg.clone_into(&mut self.actions.lock().unwrap().reason);
         ClassifyResult::Reject
     }
+
+    /// Logs a temporary rejection message and returns [`ClassifyResult::Tempfail`].
+    #[must_use]
+    pub fn tempfail(&self, msg: &str) -> ClassifyResult {
+        self.log(&format!("{} ({})", ClassifyResult::Tempfail.uc(), msg));
+        msg.clone_into(&mut self.actions.lock().unwrap().reason);
+        ClassifyResult::Tempfail
+    }
+}
+
+// Returns the value of the `name=value` or `name` parameter in `parameters`.
+fn esmtp_parameter<'b>(parameters: &'b [String], name: &str) -> Option<&'b str> {
+    parameters.iter().find_map(|p| {
+        let (key, value) = p.split_once('=').unwrap_or((p, ""));
+        key.eq_ignore_ascii_case(name).then_some(value)
+    })
+}
+
+// Returns the `http://` and `https://` URLs in `text`.
+fn find_urls(text: &str) -> impl Iterator<Item = &str> {
+    text.match_indices("http").filter_map(|(pos, _)| {
+        let rest = &text[pos..];
+        if !rest.starts_with("http://") && !rest.starts_with("https://") {
+            return None;
+        }
+        let end = rest
+            .find(|c: char| c.is_whitespace() || "\"'<>()[]{}".contains(c))
+            .unwrap_or(rest.len());
+        let url = rest[..end].trim_end_matches(['.', ',', ';', ':', '!', '?']);
+        if url.ends_with("//") { None } else { Some(url) }
+    })
+}
+
+/// Envelope data and raw message of a message which could not be parsed.
+///
+/// Passed to the classifier registered with [`ConfigBuilder::fallback_classifier()`], so
+/// that policies which do not need a parsed message (e.g. a sender blocklist) still apply
+/// to malformed messages. Header fields are extracted from the raw bytes without decoding.
+pub struct RawMailInfo<'a> {
+    storage: &'a MailInfoStorage,
+    reason: Mutex<String>,
+    logged: Mutex<Vec<String>>,
+}
+
+impl<'a> RawMailInfo<'a> {
+    fn new(storage: &'a MailInfoStorage) -> Self {
+        RawMailInfo {
+            storage,
+            reason: Mutex::new(String::new()),
+            logged: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl RawMailInfo<'_> {
+    /// Returns the envelope sender (MAIL FROM).
+    pub fn get_sender(&self) -> &str {
+        &self.storage.sender
+    }
+    /// Returns the envelope recipients (RCPT TO).
+    pub fn get_recipients(&self) -> &[String] {
+        &self.storage.recipients
+    }
+    /// Returns the Postfix queue ID (from milter macro `i`).
+    pub fn get_id(&self) -> &str {
+        &self.storage.id
+    }
+    /// Returns the value of the milter macro `name`, or `""` if Postfix did not send it.
+    pub fn get_macro(&self, name: &str) -> &str {
+        self.storage
+            .macros
+            .get(name)
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+    /// Returns the raw message (header and body with CRLF line endings).
+    pub fn get_raw(&self) -> &[u8] {
+        &self.storage.mail_buffer
+    }
+    /// Returns the unfolded values of all header fields `name` (case-insensitive).
+    ///
+    /// Invalid UTF-8 is replaced, encoded words are not decoded.
+    pub fn get_headers(&self, name: &str) -> Vec<String> {
+        let mut values: Vec<String> = Vec::new();
+        let mut current = false;
+        for line in self.get_raw().split(|&c| c == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                break;
+            }
+            if line[0] == b' ' || line[0] == b'\t' {
+                if current && let Some(value) = values.last_mut() {
+                    value.push(' ');
+                    value.push_str(String::from_utf8_lossy(line).trim());
+                }
+                continue;
+            }
+            current = false;
+            if let Some(colon) = line.iter().position(|&c| c == b':')
+                && line[..colon]
+                    .trim_ascii()
+                    .eq_ignore_ascii_case(name.as_bytes())
+            {
+                current = true;
+                values.push(String::from_utf8_lossy(line[colon + 1..].trim_ascii()).into_owned());
+            }
+        }
+        values
+    }
+    /// Returns the unfolded value of the first header field `name`, or `""` if missing.
+    pub fn get_header(&self, name: &str) -> String {
+        self.get_headers(name)
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+    }
+    /// Logs a message prefixed with the queue ID.
+    ///
+    /// The message is also recorded for [`Decision::explain()`].
+    pub fn log(&self, msg: &str) {
+        eprintln!("{}: {}", self.storage.id, msg);
+        self.logged.lock().unwrap().push(msg.to_string());
+    }
+    fn decide(&self, result: ClassifyResult, msg: &str) -> ClassifyResult {
+        self.log(&format!("{} ({})", result.uc(), msg));
+        msg.clone_into(&mut self.reason.lock().unwrap());
+        result
+    }
+    /// Logs an acceptance message and returns [`ClassifyResult::Accept`].
+    #[must_use]
+    pub fn accept(&self, msg: &str) -> ClassifyResult {
+        self.decide(ClassifyResult::Accept, msg)
+    }
+    /// Logs a quarantine message and returns [`ClassifyResult::Quarantine`].
+    #[must_use]
+    pub fn quarantine(&self, msg: &str) -> ClassifyResult {
+        self.decide(ClassifyResult::Quarantine, msg)
+    }
+    /// Logs a rejection message and returns [`ClassifyResult::Reject`].
+    #[must_use]
+    pub fn reject(&self, msg: &str) -> ClassifyResult {
+        self.decide(ClassifyResult::Reject, msg)
+    }
+    /// Logs a temporary rejection message and returns [`ClassifyResult::Tempfail`].
+    #[must_use]
+    pub fn tempfail(&self, msg: &str) -> ClassifyResult {
+        self.decide(ClassifyResult::Tempfail, msg)
+    }
 }
 
 /// The result of classifying an email message.
@@ -279,28 +1088,72 @@ pub enum ClassifyResult {
     Reject,
     /// Accept but hold the email in Postfix quarantine.
     Quarantine,
+    /// Reject the email with a 4xx error, so that the sender retries later.
+    Tempfail,
 }
 
 impl ClassifyResult {
-    /// Returns the uppercase string representation (`"ACCEPT"`, `"REJECT"`, or `"QUARANTINE"`).
+    /// Returns the uppercase string representation (`"ACCEPT"`, `"REJECT"`, `"QUARANTINE"`
+    /// or `"TEMPFAIL"`).
     pub fn uc(self) -> &'static str {
         match self {
             ClassifyResult::Accept => "ACCEPT",
             ClassifyResult::Reject => "REJECT",
             ClassifyResult::Quarantine => "QUARANTINE",
+            ClassifyResult::Tempfail => "TEMPFAIL",
         }
     }
 }
 
+/// What to do with messages which can not be parsed, see
+/// [`ConfigBuilder::unparseable_message()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnparseablePolicy {
+    /// Accept the message without classification.
+    #[default]
+    Accept,
+    /// Reject the message temporarily.
+    Tempfail,
+    /// Reject the message.
+    Reject,
+}
+
 /// Configuration for the milter daemon.
 ///
 /// Use [`Config::builder()`] to create a new configuration.
 #[derive(Clone)]
 pub struct Config {
     full_mail_classifier: Option<Arc<dyn ClassifyEmail + Send + Sync>>,
+    #[cfg(feature = "daemon")]
     fork_mode_enabled: bool,
+    #[cfg(feature = "daemon")]
+    concurrency: Option<cli::Concurrency>,
+    sieve_dir: Option<PathBuf>,
+    slow_message_threshold: Option<Duration>,
+    #[cfg(feature = "daemon")]
+    decision_callback: Option<DecisionCallback>,
+    #[cfg(feature = "daemon")]
+    recipient_validator: Option<RecipientValidator>,
+    unparseable_policy: UnparseablePolicy,
+    fallback_classifier: Option<FallbackClassifier>,
+    html_limits: HtmlLimits,
+    always_deliver: Vec<String>,
+    untrusted_headers: Vec<String>,
+    trusted_domain: String,
+    #[cfg(feature = "daemon")]
+    trusted_networks: Vec<Network>,
+    #[cfg(feature = "daemon")]
+    trusted_client: Option<TrustedClient>,
+    shadow_classifier: Option<Arc<dyn ClassifyEmail + Send + Sync>>,
+    bypass_key: Option<(Vec<u8>, u32)>,
+    batv_signing: Option<(Vec<u8>, u32, Vec<String>)>,
 }
 
+type DecisionCallback = Arc<dyn Fn(&Decision) + Send + Sync>;
+type FallbackClassifier = Arc<dyn Fn(&RawMailInfo) -> ClassifyResult + Send + Sync>;
+type RecipientValidator = Arc<dyn Fn(&str) -> RecipientStatus + Send + Sync>;
+type TrustedClient = Arc<dyn Fn(IpAddr) -> Option<String> + Send + Sync>;
+
 impl Config {
     /// Creates a new [`ConfigBuilder`] for constructing a configuration.
     pub fn builder() -> ConfigBuilder {
@@ -322,6 +1175,22 @@ impl Config {
 pub struct ConfigBuilder {
     full_mail_classifier: Option<Arc<dyn ClassifyEmail + Send + Sync>>,
     fork_mode_enabled: bool,
+    concurrency: Option<cli::Concurrency>,
+    sieve_dir: Option<PathBuf>,
+    slow_message_threshold: Option<Duration>,
+    decision_callback: Option<DecisionCallback>,
+    recipient_validator: Option<RecipientValidator>,
+    unparseable_policy: UnparseablePolicy,
+    fallback_classifier: Option<FallbackClassifier>,
+    html_limits: HtmlLimits,
+    always_deliver: Vec<String>,
+    untrusted_headers: Vec<String>,
+    trusted_domain: String,
+    trusted_networks: Vec<Network>,
+    trusted_client: Option<TrustedClient>,
+    shadow_classifier: Option<Arc<dyn ClassifyEmail + Send + Sync>>,
+    bypass_key: Option<(Vec<u8>, u32)>,
+    batv_signing: Option<(Vec<u8>, u32, Vec<String>)>,
 }
 
 impl ConfigBuilder {
@@ -373,51 +1242,627 @@ impl ConfigBuilder {
         self.fork_mode_enabled = true;
         self
     }
-    /// Builds the final [`Config`].
-    pub fn build(self) -> Config {
-        Config {
-            full_mail_classifier: self.full_mail_classifier,
-            fork_mode_enabled: self.fork_mode_enabled,
-        }
+    /// Sets the concurrency model of the daemon. The default is [`cli::Concurrency::Auto`].
+    ///
+    /// `--concurrency`, `--fork` and `--threads` take precedence. The model is checked
+    /// before the daemon starts listening, e.g. [`cli::Concurrency::Prefork`] requires
+    /// [`enable_fork_mode()`](Self::enable_fork_mode).
+    pub fn concurrency(mut self, concurrency: cli::Concurrency) -> Self {
+        self.concurrency = Some(concurrency);
+        self
     }
-}
-
-fn classify_mail(config: &Config, storage: &MailInfoStorage) -> ClassifyResult {
-    if let Some(ref arg) = config.full_mail_classifier {
-        let classifier: &dyn ClassifyEmail = arg.as_ref();
-        let r = MessageParser::default().parse(&storage.mail_buffer);
-        if let Some(msg) = r {
-            let mail_info = MailInfo { storage, msg };
-            classifier.classify(&mail_info)
-        } else {
-            eprintln!(
-                "{}: ACCEPT (because of failure to parse message)",
-                storage.id,
-            );
-            ClassifyResult::Accept
-        }
-    } else {
-        eprintln!("{}: ACCEPT (no classifier configured)", storage.id);
-        ClassifyResult::Accept
+    /// Enables per-user Sieve scripts loaded from `dir`.
+    ///
+    /// When the classifier accepts a message with a single envelope recipient, the script
+    /// `<dir>/<recipient>.sieve` is evaluated, if it exists, with the recipient address
+    /// [normalized](addresses). See [`sieve`] for the supported language. Scripts are read
+    /// for every message, so changes take effect immediately.
+    pub fn sieve_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.sieve_dir = Some(dir.into());
+        self
     }
+    /// Logs a per-stage timing breakdown for messages which take longer than `threshold`.
+    ///
+    /// The time is measured from the envelope sender to the classification result and split
+    /// into receiving the message from the MTA, parsing and classification, e.g.
+    /// `took 4.2s: receive=0.1s parse=0.0s classify=4.1s`.
+    pub fn slow_message_log(mut self, threshold: Duration) -> Self {
+        self.slow_message_threshold = Some(threshold);
+        self
+    }
+    /// Registers a callback which is called with every [`Decision`] of the daemon.
+    ///
+    /// The callback runs after the reply has been sent to Postfix, so it does not delay
+    /// delivery, but it does delay the next message on the same connection. It can be used
+    /// to correlate queue IDs with delivery logs or to push decisions to external systems.
+    /// In fork mode it runs in the child process.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let config = Config::builder()
+    ///     .email_classifier(classifier)
+    ///     .on_decision(|d| println!("{} {} {}", d.queue_id, d.result.uc(), d.reason))
+    ///     .build();
+    /// ```
+    pub fn on_decision(mut self, f: impl Fn(&Decision) + Send + Sync + 'static) -> Self {
+        self.decision_callback = Some(Arc::new(f));
+        self
+    }
+    /// Registers a validator which is called for every envelope recipient at the RCPT stage.
+    ///
+    /// Recipients for which the validator returns [`RecipientStatus::Unknown`] are rejected
+    /// with `550 5.1.1`, [`RecipientStatus::Unavailable`] with `451 4.3.0`. See [`recipient`]
+    /// for the provided backends. Without a validator, the RCPT stage is negotiated without
+    /// reply.
+    pub fn recipient_validator(
+        mut self,
+        f: impl Fn(&str) -> RecipientStatus + Send + Sync + 'static,
+    ) -> Self {
+        self.recipient_validator = Some(Arc::new(f));
+        self
+    }
+    /// Sets what to do with messages which can not be parsed. The default is
+    /// [`UnparseablePolicy::Accept`].
+    ///
+    /// Unparseable messages are logged as `unparseable message` before the policy is
+    /// applied.
+    pub fn unparseable_message(mut self, policy: UnparseablePolicy) -> Self {
+        self.unparseable_policy = policy;
+        self
+    }
+    /// Registers a classifier for messages which can not be parsed.
+    ///
+    /// The classifier gets a [`RawMailInfo`] and its result replaces the
+    /// [`unparseable_message()`](Self::unparseable_message) policy.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let config = Config::builder()
+    ///     .email_classifier(classifier)
+    ///     .fallback_classifier(|raw| {
+    ///         if array_contains(&BLOCKLIST, raw.get_sender()) {
+    ///             return raw.reject("sender in blocklist");
+    ///         }
+    ///         raw.quarantine("unparseable message")
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn fallback_classifier(
+        mut self,
+        f: impl Fn(&RawMailInfo) -> ClassifyResult + Send + Sync + 'static,
+    ) -> Self {
+        self.fallback_classifier = Some(Arc::new(f));
+        self
+    }
+    /// Sets the limits for HTML to text conversion, see [`html`].
+    pub fn html_limits(mut self, limits: HtmlLimits) -> Self {
+        self.html_limits = limits;
+        self
+    }
+    /// Never rejects mail to `addresses`, whatever the classifier decides.
+    ///
+    /// RFC 5321 requires that mail to `postmaster` is accepted. If all envelope recipients
+    /// match, a [`ClassifyResult::Reject`] is turned into [`ClassifyResult::Accept`] and
+    /// the message is tagged with a header field `X-Srmilter-Override: REJECT (reason)`.
+    /// If only some of them match, the message is quarantined instead, so that adding
+    /// `postmaster` as a recipient does not deliver spam to the other recipients.
+    /// Addresses are compared [normalized](addresses); an entry ending with `@` (e.g.
+    /// `"postmaster@"`) matches the local part in any domain.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let config = Config::builder()
+    ///     .email_classifier(classifier)
+    ///     .always_deliver(&["postmaster@", "abuse@example.com"])
+    ///     .build();
+    /// ```
+    pub fn always_deliver<S: AsRef<str>>(mut self, addresses: &[S]) -> Self {
+        self.always_deliver
+            .extend(addresses.iter().map(|a| addresses::normalize(a.as_ref())));
+        self
+    }
+    /// Removes instances of the headers `names` which were not added by hosts in
+    /// `good_domain` from accepted and quarantined messages.
+    ///
+    /// This keeps forged upstream verdict headers away from later filters and mail
+    /// clients. Which instances are trusted is decided as in
+    /// [`MailInfo::get_trusted_headers()`]; without a trusted `Received:` header, all
+    /// instances are removed. Removed headers are logged.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let config = Config::builder()
+    ///     .email_classifier(classifier)
+    ///     .remove_untrusted_headers(&["X-Spam-Score", "Authentication-Results"], "example.org")
+    ///     .build();
+    /// ```
+    pub fn remove_untrusted_headers<S: AsRef<str>>(
+        mut self,
+        names: &[S],
+        good_domain: &str,
+    ) -> Self {
+        self.untrusted_headers
+            .extend(names.iter().map(|n| n.as_ref().to_string()));
+        self.trusted_domain = good_domain.to_string();
+        self
+    }
+    /// Accepts messages from clients in `networks` without classification.
+    ///
+    /// This is a fast path for high-volume trusted sources like an internal relay: the
+    /// message is neither buffered nor parsed, only the decision is logged. The client
+    /// address is taken from the milter macro `{client_addr}` or, if not available, from
+    /// `_`. Postfix sends `{client_addr}` only if it is added to `milter_mail_macros`.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let config = Config::builder()
+    ///     .email_classifier(classifier)
+    ///     .trusted_networks(&["10.0.0.0/8".parse()?, "2001:db8::/32".parse()?])
+    ///     .build();
+    /// ```
+    pub fn trusted_networks(mut self, networks: &[Network]) -> Self {
+        self.trusted_networks.extend_from_slice(networks);
+        self
+    }
+    /// Like [`trusted_networks()`](Self::trusted_networks), but decided by `f`, e.g. from
+    /// a country or ASN database.
+    ///
+    /// `f` returns a label for trusted clients, which is logged with the decision, and
+    /// `None` for all others.
+    pub fn trusted_client(
+        mut self,
+        f: impl Fn(IpAddr) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.trusted_client = Some(Arc::new(f));
+        self
+    }
+    /// Accepts messages with valid [`bypass`] tokens for all envelope recipients without
+    /// classification.
+    ///
+    /// Tokens must be made with `key` and may be valid for at most `max_valid_days`. All
+    /// `X-Srmilter-Bypass:` headers are removed from accepted and quarantined messages.
+    /// Load the key with [`secrets::resolve()`].
+    pub fn bypass_key(mut self, key: &[u8], max_valid_days: u32) -> Self {
+        self.bypass_key = Some((key.to_vec(), max_valid_days));
+        self
+    }
+    /// Signs envelope senders in `domains` with a [`batv`] tag valid for `valid_days`.
+    ///
+    /// Accepted and quarantined messages with such a sender get the tagged sender with the
+    /// change-from milter action. This includes messages from
+    /// [trusted clients](Self::trusted_networks), typically the outgoing mail. Senders
+    /// which are already tagged or were changed with [`MailInfo::change_sender()`] are
+    /// left alone. Verify incoming bounces with [`batv::verify()`] and the same key, which
+    /// can be loaded with [`secrets::resolve()`].
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let config = Config::builder()
+    ///     .email_classifier(classifier)
+    ///     .trusted_networks(&["10.0.0.0/8".parse()?])
+    ///     .batv_signing(&batv_key, 7, &["example.org"])
+    ///     .build();
+    /// ```
+    pub fn batv_signing<S: AsRef<str>>(
+        mut self,
+        key: &[u8],
+        valid_days: u32,
+        domains: &[S],
+    ) -> Self {
+        let domains = domains
+            .iter()
+            .map(|d| d.as_ref().to_ascii_lowercase())
+            .collect();
+        self.batv_signing = Some((key.to_vec(), valid_days, domains));
+        self
+    }
+    /// Builds the final [`Config`].
+    pub fn build(self) -> Config {
+        // the options of the daemon are ignored without it
+        #[cfg(not(feature = "daemon"))]
+        let _ = (
+            self.fork_mode_enabled,
+            self.concurrency,
+            self.decision_callback,
+            self.recipient_validator,
+            self.trusted_networks,
+            self.trusted_client,
+        );
+        Config {
+            full_mail_classifier: self.full_mail_classifier,
+            #[cfg(feature = "daemon")]
+            fork_mode_enabled: self.fork_mode_enabled,
+            #[cfg(feature = "daemon")]
+            concurrency: self.concurrency,
+            sieve_dir: self.sieve_dir,
+            slow_message_threshold: self.slow_message_threshold,
+            #[cfg(feature = "daemon")]
+            decision_callback: self.decision_callback,
+            #[cfg(feature = "daemon")]
+            recipient_validator: self.recipient_validator,
+            unparseable_policy: self.unparseable_policy,
+            fallback_classifier: self.fallback_classifier,
+            html_limits: self.html_limits,
+            always_deliver: self.always_deliver,
+            untrusted_headers: self.untrusted_headers,
+            trusted_domain: self.trusted_domain,
+            #[cfg(feature = "daemon")]
+            trusted_networks: self.trusted_networks,
+            #[cfg(feature = "daemon")]
+            trusted_client: self.trusted_client,
+            shadow_classifier: self.shadow_classifier,
+            bypass_key: self.bypass_key,
+            batv_signing: self.batv_signing,
+        }
+    }
+}
+
+fn log_slow_message(
+    config: &Config,
+    storage: &MailInfoStorage,
+    parse_start: Instant,
+    classify_start: Instant,
+) {
+    let Some(threshold) = config.slow_message_threshold else {
+        return;
+    };
+    let end = Instant::now();
+    let start = storage.received_at.unwrap_or(parse_start);
+    let total = end - start;
+    if total >= threshold {
+        eprintln!(
+            "{}: took {:.1}s: receive={:.1}s parse={:.1}s classify={:.1}s",
+            storage.id,
+            total.as_secs_f32(),
+            (parse_start - start).as_secs_f32(),
+            (classify_start - parse_start).as_secs_f32(),
+            (end - classify_start).as_secs_f32(),
+        );
+    }
+}
+
+/// Final result of [`classify_mail()`].
+#[derive(Debug, PartialEq)]
+struct Verdict {
+    result: ClassifyResult,
+    // reason given by the classifier
+    reason: String,
+    // header fields to add to the message
+    headers: Vec<(String, String)>,
+    // header fields to remove from the message, by name and 1-based index
+    removed_headers: Vec<(String, u32)>,
+    // envelope recipients to add, see MailInfo::add_recipient()
+    added_recipients: Vec<String>,
+    // envelope sender to set, see MailInfo::change_sender()
+    changed_sender: Option<String>,
+    // delay before the reply, see MailInfo::delay_reply()
+    delay: Duration,
+    // messages logged for the message, see Decision::log
+    log: Vec<String>,
+}
+
+impl Verdict {
+    fn new(result: ClassifyResult, reason: String) -> Self {
+        Verdict {
+            result,
+            reason,
+            headers: Vec::new(),
+            removed_headers: Vec::new(),
+            added_recipients: Vec::new(),
+            changed_sender: None,
+            delay: Duration::ZERO,
+            log: Vec::new(),
+        }
+    }
+    // Logs `msg` for the message and records it for Decision::explain().
+    fn log(&mut self, id: &str, msg: String) {
+        eprintln!("{id}: {msg}");
+        self.log.push(msg);
+    }
+}
+
+// Checks if `recipient` matches an always-deliver entry. Entries ending with `@` match the
+// local part in any domain.
+fn is_always_deliver(config: &Config, recipient: &str) -> bool {
+    let recipient = addresses::normalize(recipient);
+    config.always_deliver.iter().any(|entry| {
+        if entry.ends_with('@') {
+            recipient.starts_with(entry.as_str())
+        } else {
+            *entry == recipient
+        }
+    })
+}
+
+// Returns `reason` with control characters replaced, for use in a header field value.
+fn header_safe(reason: &str) -> String {
+    reason
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+// Returns the sender with a BATV tag if it is in a signing domain, see
+// ConfigBuilder::batv_signing().
+fn batv_signed_sender(config: &Config, sender: &str) -> Option<String> {
+    let (key, valid_days, domains) = config.batv_signing.as_ref()?;
+    let (_, domain) = sender.rsplit_once('@')?;
+    if !domains.iter().any(|d| d.eq_ignore_ascii_case(domain))
+        || batv::strip(sender).len() != sender.len()
+    {
+        return None;
+    }
+    Some(batv::sign(sender, key, batv::today(), *valid_days))
+}
+
+// Returns why a message from `client` is accepted without classification, if it is.
+#[cfg(feature = "daemon")]
+fn trusted_client_reason(config: &Config, client: Option<IpAddr>) -> Option<String> {
+    let client = client?;
+    if let Some(network) = config.trusted_networks.iter().find(|n| n.contains(client)) {
+        return Some(format!("trusted client {client} in {network}"));
+    }
+    let label = config.trusted_client.as_ref()?(client)?;
+    Some(format!("trusted client {client}: {label}"))
+}
+
+// Accepts the message if it has valid bypass tokens for all recipients.
+fn check_bypass(config: &Config, storage: &MailInfoStorage) -> Option<Verdict> {
+    let (key, max_valid_days) = config.bypass_key.as_ref()?;
+    if storage.recipients.is_empty() {
+        return None;
+    }
+    let msg = MessageParser::default().parse_headers(&storage.mail_buffer)?;
+    let mail_info = MailInfo::new(storage, msg);
+    let tokens = mail_info.header_values(bypass::HEADER);
+    let today = batv::today();
+    let valid = storage.recipients.iter().all(|recipient| {
+        tokens
+            .iter()
+            .any(|token| bypass::verify(token, recipient, key, today, *max_valid_days))
+    });
+    if !valid {
+        return None;
+    }
+    eprintln!("{}: ACCEPT (bypass token)", storage.id);
+    Some(Verdict::new(ClassifyResult::Accept, "bypass token".into()))
+}
+
+fn classify_mail(config: &Config, storage: &MailInfoStorage) -> Verdict {
+    let mut verdict =
+        check_bypass(config, storage).unwrap_or_else(|| run_classifier(config, storage));
+    if verdict.result == ClassifyResult::Reject
+        && let Some(recipient) = storage
+            .recipients
+            .iter()
+            .find(|r| is_always_deliver(config, r))
+    {
+        verdict.result = if storage
+            .recipients
+            .iter()
+            .all(|r| is_always_deliver(config, r))
+        {
+            ClassifyResult::Accept
+        } else {
+            ClassifyResult::Quarantine
+        };
+        verdict.log(
+            &storage.id,
+            format!(
+                "{} (always deliver to {recipient}, overrides REJECT)",
+                verdict.result.uc()
+            ),
+        );
+        verdict.headers.push((
+            "X-Srmilter-Override".into(),
+            format!("REJECT ({})", header_safe(&verdict.reason)),
+        ));
+    }
+    if !matches!(
+        verdict.result,
+        ClassifyResult::Accept | ClassifyResult::Quarantine
+    ) {
+        verdict.added_recipients.clear();
+        verdict.changed_sender = None;
+    } else if verdict.changed_sender.is_none() {
+        verdict.changed_sender = batv_signed_sender(config, &storage.sender);
+    }
+    for recipient in verdict.added_recipients.clone() {
+        verdict.log(&storage.id, format!("adding recipient <{recipient}>"));
+    }
+    if let Some(sender) = verdict.changed_sender.clone() {
+        verdict.log(&storage.id, format!("changing sender to <{sender}>"));
+    }
+    if matches!(
+        verdict.result,
+        ClassifyResult::Accept | ClassifyResult::Quarantine
+    ) && (!config.untrusted_headers.is_empty() || config.bypass_key.is_some())
+        && let Some(msg) = MessageParser::default().parse_headers(&storage.mail_buffer)
+    {
+        let mail_info = MailInfo::new(storage, msg);
+        for name in &config.untrusted_headers {
+            let indexes = mail_info.untrusted_header_indexes(name, &config.trusted_domain);
+            if !indexes.is_empty() {
+                verdict.log(
+                    &storage.id,
+                    format!("removing {} untrusted {name} header(s)", indexes.len()),
+                );
+            }
+            // from the bottom, so that the remaining indexes stay valid
+            for index in indexes.into_iter().rev() {
+                verdict.removed_headers.push((name.clone(), index));
+            }
+        }
+        if config.bypass_key.is_some() {
+            let count = mail_info.header_values(bypass::HEADER).len() as u32;
+            for index in (1..=count).rev() {
+                verdict
+                    .removed_headers
+                    .push((bypass::HEADER.to_string(), index));
+            }
+        }
+    }
+    verdict
+}
+
+// Returns the result, the reason and the added recipients from the classifier.
+fn run_classifier(config: &Config, storage: &MailInfoStorage) -> Verdict {
+    if let Some(ref arg) = config.full_mail_classifier {
+        let classifier: &dyn ClassifyEmail = arg.as_ref();
+        let parse_start = Instant::now();
+        let Some(msg) = MessageParser::default().parse(&storage.mail_buffer) else {
+            eprintln!("{}: unparseable message", storage.id);
+            if let Some(ref fallback) = config.fallback_classifier {
+                let raw_info = RawMailInfo::new(storage);
+                let result = fallback(&raw_info);
+                return Verdict {
+                    log: raw_info.logged.into_inner().unwrap(),
+                    ..Verdict::new(result, raw_info.reason.into_inner().unwrap())
+                };
+            }
+            let result = match config.unparseable_policy {
+                UnparseablePolicy::Accept => ClassifyResult::Accept,
+                UnparseablePolicy::Tempfail => ClassifyResult::Tempfail,
+                UnparseablePolicy::Reject => ClassifyResult::Reject,
+            };
+            eprintln!(
+                "{}: {} (because of failure to parse message)",
+                storage.id,
+                result.uc()
+            );
+            return Verdict::new(result, "failure to parse message".into());
+        };
+        let mail_info = MailInfo::new(storage, msg);
+        let classify_start = Instant::now();
+        let mut result = classifier.classify(&mail_info);
+        if let Some(ref shadow) = config.shadow_classifier {
+            run_shadow(shadow.as_ref(), &mail_info, result);
+        }
+        if result == ClassifyResult::Accept
+            && let Some(ref dir) = config.sieve_dir
+            && let Some(user_result) = sieve::classify_user(dir, &mail_info)
+        {
+            result = user_result;
+        }
+        log_slow_message(config, storage, parse_start, classify_start);
+        mail_info.into_verdict(result)
+    } else {
+        eprintln!("{}: ACCEPT (no classifier configured)", storage.id);
+        Verdict::new(ClassifyResult::Accept, "no classifier configured".into())
+    }
+}
+
+// Returns the message of a caught panic.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown")
+}
+
+// Runs the shadow classifier and logs if its result differs from `result`. A panic of the
+// shadow classifier is logged and does not affect the message.
+fn run_shadow(shadow: &dyn ClassifyEmail, mail_info: &MailInfo, result: ClassifyResult) {
+    let actions = std::mem::take(&mut *mail_info.actions.lock().unwrap());
+    mail_info.quiet.store(true, Ordering::Relaxed);
+    let shadow_result = catch_unwind(AssertUnwindSafe(|| shadow.classify(mail_info)));
+    mail_info.quiet.store(false, Ordering::Relaxed);
+    let shadow_actions = std::mem::replace(&mut *mail_info.actions.lock().unwrap(), actions);
+    match shadow_result {
+        Ok(shadow_result) if shadow_result != result => {
+            let reason = mail_info.actions.lock().unwrap().reason.clone();
+            mail_info.log(&format!(
+                "shadow disagrees: {} ({reason}) vs {} ({})",
+                result.uc(),
+                shadow_result.uc(),
+                shadow_actions.reason,
+            ));
+        }
+        Ok(_) => (),
+        Err(panic) => mail_info.log(&format!(
+            "shadow classifier panicked: {}",
+            panic_message(&*panic)
+        )),
+    }
+}
+
+/// A classification result, passed to the callback registered with
+/// [`ConfigBuilder::on_decision()`].
+#[derive(Debug)]
+pub struct Decision<'a> {
+    /// Postfix queue ID (from milter macro `i`), or `"-"` if not available.
+    pub queue_id: &'a str,
+    /// SMTP envelope sender.
+    pub sender: &'a str,
+    /// SMTP envelope recipients.
+    pub recipients: &'a [String],
+    /// The result sent to Postfix.
+    pub result: ClassifyResult,
+    /// The reason given by the classifier.
+    pub reason: &'a str,
+    /// The messages logged for the message by the classifier (with
+    /// [`MailInfo::log()`] and the decision methods) and by srmilter, e.g. for an
+    /// always-deliver override, in order.
+    pub log: &'a [String],
+}
+
+impl Decision<'_> {
+    /// Returns a human-readable, multi-line explanation of the decision.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// REJECT (listed in Spamhaus ZEN)
+    /// queue id: 4Xk2Lq0Wz1z9sG
+    /// sender: bulk@example.net
+    /// recipients: ceo@example.org, cfo@example.org
+    /// log:
+    ///   Spamhaus zen: 192.0.2.7: 127.0.0.2
+    ///   REJECT (listed in Spamhaus ZEN)
+    /// ```
+    pub fn explain(&self) -> String {
+        let mut out = format!(
+            "{} ({})\nqueue id: {}\nsender: {}\nrecipients: {}",
+            self.result.uc(),
+            self.reason,
+            self.queue_id,
+            self.sender,
+            self.recipients.join(", "),
+        );
+        if !self.log.is_empty() {
+            out.push_str("\nlog:");
+            for line in self.log {
+                out.push_str("\n  ");
+                out.push_str(line);
+            }
+        }
+        out
+    }
+}
+
+type ClassifyFunctionWithCtx<C> = fn(&C, &MailInfo) -> ClassifyResult;
+
+/// Trait for implementing email classifiers.
+pub trait ClassifyEmail {
+    /// Classifies the given email and returns the classification decision.
+    fn classify(&self, mail_info: &MailInfo) -> ClassifyResult;
+}
+
+/// General purpose classifier
+///
+/// Use [`EmailClassifier::builder()`] to construct a new classifier.
+pub struct EmailClassifier<C> {
+    user_ctx: C,
+    f: Option<ClassifyFunctionWithCtx<C>>,
 }
 
-type ClassifyFunctionWithCtx<C> = fn(&C, &MailInfo) -> ClassifyResult;
-
-/// Trait for implementing email classifiers.
-pub trait ClassifyEmail {
-    /// Classifies the given email and returns the classification decision.
-    fn classify(&self, mail_info: &MailInfo) -> ClassifyResult;
-}
-
-/// General purpose classifier
-///
-/// Use [`EmailClassifier::builder()`] to construct a new classifier.
-pub struct EmailClassifier<C> {
-    user_ctx: C,
-    f: Option<ClassifyFunctionWithCtx<C>>,
-}
-
 impl<C> ClassifyEmail for EmailClassifier<C> {
     fn classify(&self, mail_info: &MailInfo) -> ClassifyResult {
         if let Some(f) = self.f {
@@ -473,6 +1918,64 @@ impl ConfigBuilder {
         self.full_mail_classifier = Some(Arc::new(classifier));
         self
     }
+    /// Runs `classifier` in the shadow of the email classifier, e.g. a new version of the
+    /// policy to be validated on live traffic.
+    ///
+    /// The shadow classifier gets the same [`MailInfo`] after the email classifier. Its
+    /// result is not used and its log messages are suppressed. If its result differs from
+    /// that of the email classifier, both are logged as
+    /// `shadow disagrees: ACCEPT (reason) vs REJECT (shadow reason)`.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let config = Config::builder()
+    ///     .email_classifier(EmailClassifier::builder(ctx.clone()).classify_fn(classify).build())
+    ///     .shadow_classifier(EmailClassifier::builder(ctx).classify_fn(classify_v2).build())
+    ///     .build();
+    /// ```
+    pub fn shadow_classifier<T>(mut self, classifier: T) -> Self
+    where
+        T: ClassifyEmail + Send + Sync + 'static,
+    {
+        self.shadow_classifier = Some(Arc::new(classifier));
+        self
+    }
+}
+
+/// Runs the milter service on `listener` until `shutdown` is set.
+///
+/// This is the entry point for applications which set up the listening socket themselves,
+/// e.g. to bind it before dropping privileges or to run the service in tests. `listener`
+/// is a bound and listening [`std::net::TcpListener`] or `socket2::Socket`. Connections are
+/// served as configured in `args` and checked like by [`cli::daemon()`]; the address and
+/// `--health-listen` are ignored. No signal handlers are installed. `shutdown` is checked
+/// at least once per second: idle connections are closed and the function returns when
+/// the connections being served are done.
+///
+/// # Example
+///
+/// ```ignore
+/// let listener = TcpListener::bind("127.0.0.1:7044")?;
+/// let args = DaemonArgs::builder().threads(8).build();
+/// let shutdown = Arc::new(AtomicBool::new(false));
+/// let service = thread::spawn({
+///     let shutdown = shutdown.clone();
+///     move || srmilter::serve(listener, &config, &args, shutdown)
+/// });
+/// // ... later
+/// shutdown.store(true, Ordering::Relaxed);
+/// service.join().unwrap()?;
+/// ```
+#[cfg(feature = "daemon")]
+pub fn serve(
+    listener: impl Into<socket2::Socket>,
+    config: &Config,
+    args: &cli::DaemonArgs,
+    shutdown: Arc<AtomicBool>,
+) -> Result<(), Box<dyn Error>> {
+    args.check(config)?;
+    daemon::DaemonRuntime::new(shutdown).run(config, &args.resolved(config), listener.into())
 }
 
 /// Reads lines from a file, stripping comments and whitespace.
@@ -507,6 +2010,8 @@ pub fn read_array(filename: &str) -> Result<Vec<String>, Box<dyn Error>> {
 }
 
 /// Checks if an exact match for `needle` exists in `haystack`.
+///
+/// Use [`addresses::contains()`] to compare normalized addresses.
 pub fn array_contains(haystack: &[String], needle: &str) -> bool {
     haystack.iter().any(|s| s == needle)
 }
@@ -525,12 +2030,12 @@ mod tests {
             ..Default::default()
         };
 
-        let mail_info = MailInfo {
-            storage: &storage,
-            msg: MessageParser::default()
+        let mail_info = MailInfo::new(
+            &storage,
+            MessageParser::default()
                 .parse(&storage.mail_buffer)
                 .unwrap(),
-        };
+        );
 
         assert_eq!(mail_info.get_sender(), "sender");
         assert_eq!(mail_info.get_only_recipient(), "recipient");
@@ -568,43 +2073,672 @@ mod tests {
             id: "test".to_string(),
             ..Default::default()
         };
-        let mail_info = MailInfo {
-            storage: &storage,
-            msg: MessageParser::default()
+        let mail_info = MailInfo::new(
+            &storage,
+            MessageParser::default()
                 .parse(&storage.mail_buffer)
                 .unwrap(),
-        };
+        );
         assert_eq!(
             mail_info.get_subject(),
             "New privacy policy at codeberg.org"
         );
     }
 
+    #[test]
+    fn parse_003_forwarders() {
+        let storage = MailInfoStorage {
+            mail_buffer: std::fs::read("tests/parse_003.eml").unwrap(),
+            ..Default::default()
+        };
+        let mail_info = MailInfo::new(
+            &storage,
+            MessageParser::default()
+                .parse(&storage.mail_buffer)
+                .unwrap(),
+        );
+        let ips: Vec<IpAddr> = mail_info.origin_ip_iter(".mx.srv.dfn.de", &[]).collect();
+        assert_eq!(
+            ips,
+            mail_info
+                .foreign_ip_iter(".mx.srv.dfn.de")
+                .collect::<Vec<_>>()
+        );
+        let forwarders = [".isp.belgacom.be".to_string()];
+        let ip = mail_info
+            .origin_ip_iter(".mx.srv.dfn.de", &forwarders)
+            .next()
+            .unwrap();
+        assert_eq!(ip.to_string(), "109.140.171.148");
+    }
+
+    #[test]
+    fn test_relayed_client_ip() {
+        let storage = MailInfoStorage {
+            mail_buffer: b"Received: from border.example.com (border.example.com [192.0.2.10])\r\n\
+                \tby mx.int.example.com (Postfix) with ESMTP id 1234\r\n\
+                X-Originating-IP: [198.51.100.7]\r\n\
+                Received: from client.example.net (client.example.net [198.51.100.7])\r\n\
+                \tby border.example.com (Postfix) with ESMTP id 5678\r\n\
+                X-Originating-IP: 203.0.113.66\r\n\
+                From: a@example.net\r\n\r\nText"
+                .to_vec(),
+            ..Default::default()
+        };
+        let mail_info = MailInfo::new(
+            &storage,
+            MessageParser::default()
+                .parse(&storage.mail_buffer)
+                .unwrap(),
+        );
+        let relays = ["border.example.com".to_string()];
+        assert_eq!(
+            mail_info.get_relayed_client_ip("X-Originating-IP", ".int.example.com", &relays),
+            Some("198.51.100.7".parse().unwrap())
+        );
+        assert_eq!(
+            mail_info.get_relayed_client_ip("X-Originating-IP", ".int.example.com", &[]),
+            None
+        );
+        assert_eq!(
+            mail_info.get_relayed_client_ip("X-Originating-IP", ".junk", &relays),
+            None
+        );
+    }
+
+    #[test]
+    fn test_cached_accessors() {
+        let storage = MailInfoStorage {
+            mail_buffer: b"From: a@example.org\r\n\
+                Subject: Re: AW:  Fwd: Your   Invoice\r\n\
+                Content-Type: text/html\r\n\r\n\
+                <p>See <a href=\"https://example.org/invoice?id=1\">here</a>\
+                or http://example.net/x. Again: https://example.org/invoice?id=1</p>"
+                .to_vec(),
+            ..Default::default()
+        };
+        let mail_info = MailInfo::new(
+            &storage,
+            MessageParser::default()
+                .parse(&storage.mail_buffer)
+                .unwrap(),
+        );
+        assert_eq!(mail_info.get_normalized_subject(), "your invoice");
+        assert_eq!(
+            mail_info.get_urls(),
+            ["https://example.org/invoice?id=1", "http://example.net/x"]
+        );
+        let text = mail_info.get_text();
+        assert!(text.contains("See here"));
+        assert!(std::ptr::eq(text.as_ptr(), mail_info.get_text().as_ptr()));
+    }
+
+    #[test]
+    fn test_esmtp_parameters() {
+        let storage = MailInfoStorage {
+            mail_parameters: vec!["SIZE=1234".into(), "SMTPUTF8".into()],
+            recipients: vec!["a@example.com".into(), "b@example.com".into()],
+            recipient_parameters: vec![vec![], vec!["NOTIFY=NEVER".into()]],
+            ..Default::default()
+        };
+        let mail_info = MailInfo::new(&storage, mail_parser::Message::default());
+        assert_eq!(mail_info.get_mail_parameter("size"), Some("1234"));
+        assert_eq!(mail_info.get_mail_parameter("SMTPUTF8"), Some(""));
+        assert_eq!(mail_info.get_mail_parameter("BODY"), None);
+        assert_eq!(
+            mail_info.get_recipient_parameter("b@example.com", "NOTIFY"),
+            Some("NEVER")
+        );
+        assert_eq!(
+            mail_info.get_recipient_parameter("a@example.com", "NOTIFY"),
+            None
+        );
+        assert!(
+            mail_info
+                .get_recipient_parameters("c@example.com")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_tls_policy() {
+        let mut storage = MailInfoStorage {
+            sender: "billing@mx.partner.example".into(),
+            ..Default::default()
+        };
+        let domains = ["partner.example".to_string()];
+        {
+            let mail_info = MailInfo::new(&storage, mail_parser::Message::default());
+            assert!(!mail_info.is_tls());
+            assert!(mail_info.violates_tls_policy(&domains));
+            assert!(!mail_info.violates_tls_policy(&["artner.example".to_string()]));
+        }
+        storage
+            .macros
+            .insert("{tls_version}".into(), "TLSv1.3".into());
+        let mail_info = MailInfo::new(&storage, mail_parser::Message::default());
+        assert!(mail_info.is_tls());
+        assert!(!mail_info.is_client_cert_verified());
+        assert!(!mail_info.violates_tls_policy(&domains));
+    }
+
+    #[test]
+    fn test_lookalike_recipients() {
+        let storage = MailInfoStorage {
+            recipients: vec!["cfo@example.com".into()],
+            ..Default::default()
+        };
+        let msg = MessageParser::default()
+            .parse(
+                b"To: CFO <cfo@example.com>\r\nCc: ceo@examp1e.com, cfo@exampel.com, \
+                  ceo@examp1e.com\r\n\r\nbody\r\n",
+            )
+            .unwrap();
+        let mail_info = MailInfo::new(&storage, msg);
+        let protected = ["ceo@example.com".to_string(), "cfo@example.com".to_string()];
+        assert_eq!(
+            mail_info.get_lookalike_recipients(&protected),
+            ["ceo@examp1e.com", "cfo@exampel.com"]
+        );
+    }
+
+    #[test]
+    fn test_thread_hijack() {
+        let storage = MailInfoStorage::default();
+        let thread_domains = |id: &str| match id {
+            "1@partner.example" => vec!["partner.example".to_string()],
+            "2@example.com" => vec!["example.com".to_string()],
+            _ => vec![],
+        };
+        let parse = |from: &str, references: &str| {
+            let raw = format!(
+                "From: {from}\r\nReferences: {references}\r\n\
+                 In-Reply-To: <2@example.com>\r\n\r\nbody\r\n"
+            );
+            MessageParser::default()
+                .parse(raw.as_bytes())
+                .unwrap()
+                .into_owned()
+        };
+        let mail_info = MailInfo::new(
+            &storage,
+            parse("a@partner.example", "<1@partner.example> <2@example.com>"),
+        );
+        assert_eq!(
+            mail_info.references(),
+            ["1@partner.example", "2@example.com"]
+        );
+        assert!(!mail_info.is_thread_hijack(thread_domains));
+        let mail_info = MailInfo::new(&storage, parse("a@mail.Example.com", "<0@x>"));
+        assert_eq!(mail_info.references(), ["0@x", "2@example.com"]);
+        assert!(!mail_info.is_thread_hijack(thread_domains));
+        let mail_info = MailInfo::new(&storage, parse("a@partner-example.net", "<1@x>"));
+        assert!(mail_info.is_thread_hijack(thread_domains));
+        assert!(!mail_info.is_thread_hijack(|_| vec![]));
+        let msg = MessageParser::default()
+            .parse(b"Subject: x\r\n\r\n")
+            .unwrap();
+        let mail_info = MailInfo::new(&storage, msg);
+        assert!(mail_info.references().is_empty());
+        assert!(!mail_info.is_thread_hijack(thread_domains));
+    }
+
     #[test]
     fn test_only_recipients() {
         let mut storage = MailInfoStorage::default();
         {
-            let mail_info = MailInfo {
-                storage: &storage,
-                msg: mail_parser::Message::default(),
-            };
+            let mail_info = MailInfo::new(&storage, mail_parser::Message::default());
             assert_eq!(mail_info.get_only_recipient(), "");
         }
         storage.recipients.push("foobar1".to_string());
         {
-            let mail_info = MailInfo {
-                storage: &storage,
-                msg: mail_parser::Message::default(),
-            };
+            let mail_info = MailInfo::new(&storage, mail_parser::Message::default());
             assert_eq!(mail_info.get_only_recipient(), "foobar1");
         }
         storage.recipients.push("foobar2".to_string());
         {
-            let mail_info = MailInfo {
-                storage: &storage,
-                msg: mail_parser::Message::default(),
-            };
+            let mail_info = MailInfo::new(&storage, mail_parser::Message::default());
             assert_eq!(mail_info.get_only_recipient(), "");
         }
     }
+
+    #[test]
+    fn test_unparseable_policy() {
+        fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+            mail_info.reject("classified")
+        }
+        let storage = MailInfoStorage::default();
+        for (policy, expected) in [
+            (UnparseablePolicy::Accept, ClassifyResult::Accept),
+            (UnparseablePolicy::Tempfail, ClassifyResult::Tempfail),
+            (UnparseablePolicy::Reject, ClassifyResult::Reject),
+        ] {
+            let config = Config::builder()
+                .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+                .unparseable_message(policy)
+                .build();
+            assert_eq!(
+                run_classifier(&config, &storage),
+                Verdict::new(expected, "failure to parse message".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_mail_info_sync() {
+        // classifiers may run checks in scoped threads
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<MailInfo>();
+        assert_sync::<RawMailInfo>();
+    }
+
+    #[test]
+    fn test_raw_mail_info() {
+        let storage = MailInfoStorage {
+            mail_buffer: std::fs::read("tests/parse_001.eml").unwrap(),
+            sender: "sender@example.com".to_string(),
+            ..Default::default()
+        };
+        let raw_info = RawMailInfo::new(&storage);
+        assert_eq!(
+            raw_info.get_header("return-path"),
+            "<donald.buczek@gmail.com>"
+        );
+        assert_eq!(
+            raw_info.get_header("Authentication-Results"),
+            "mgw6-tub.srv.dfn.de (amavis); dkim=pass (2048-bit key) header.d=gmail.com"
+        );
+        assert_eq!(raw_info.get_headers("Received").len(), 4);
+        assert_eq!(raw_info.get_header("X-Missing"), "");
+
+        let config = Config::builder()
+            .unparseable_message(UnparseablePolicy::Tempfail)
+            .fallback_classifier(|raw| raw.reject(&format!("sender {}", raw.get_sender())))
+            .email_classifier(EmailClassifier::builder(()).build())
+            .build();
+        let storage = MailInfoStorage {
+            sender: "sender@example.com".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            run_classifier(&config, &storage),
+            Verdict {
+                log: vec!["REJECT (sender sender@example.com)".to_string()],
+                ..Verdict::new(
+                    ClassifyResult::Reject,
+                    "sender sender@example.com".to_string()
+                )
+            }
+        );
+    }
+
+    #[test]
+    fn test_always_deliver() {
+        let config = Config::builder()
+            .fallback_classifier(|raw| raw.reject("spam"))
+            .email_classifier(EmailClassifier::builder(()).build())
+            .always_deliver(&["Postmaster@", "abuse@example.com"])
+            .build();
+        let mut storage = MailInfoStorage {
+            recipients: vec!["user@example.com".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            classify_mail(&config, &storage).result,
+            ClassifyResult::Reject
+        );
+        storage.recipients = vec!["postmaster@example.org".to_string()];
+        assert_eq!(
+            classify_mail(&config, &storage),
+            Verdict {
+                result: ClassifyResult::Accept,
+                reason: "spam".to_string(),
+                headers: vec![(
+                    "X-Srmilter-Override".to_string(),
+                    "REJECT (spam)".to_string()
+                )],
+                removed_headers: vec![],
+                added_recipients: vec![],
+                changed_sender: None,
+                delay: Duration::ZERO,
+                log: vec![
+                    "REJECT (spam)".to_string(),
+                    "ACCEPT (always deliver to postmaster@example.org, overrides REJECT)"
+                        .to_string()
+                ],
+            }
+        );
+        storage.recipients = vec!["Abuse+x@example.com".to_string()];
+        assert_eq!(
+            classify_mail(&config, &storage).result,
+            ClassifyResult::Accept
+        );
+        // other recipients do not get the message through the override
+        storage.recipients.push("user@example.com".to_string());
+        assert_eq!(
+            classify_mail(&config, &storage).result,
+            ClassifyResult::Quarantine
+        );
+        storage.recipients = vec!["abuse@example.org".to_string()];
+        assert_eq!(
+            classify_mail(&config, &storage).result,
+            ClassifyResult::Reject
+        );
+    }
+
+    #[test]
+    fn test_always_deliver_header_injection() {
+        let config = Config::builder()
+            .fallback_classifier(|raw| raw.reject("spam\r\nX-Injected: yes"))
+            .email_classifier(EmailClassifier::builder(()).build())
+            .always_deliver(&["postmaster@"])
+            .build();
+        let storage = MailInfoStorage {
+            recipients: vec!["postmaster@example.org".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            classify_mail(&config, &storage).headers,
+            vec![(
+                "X-Srmilter-Override".to_string(),
+                "REJECT (spam  X-Injected: yes)".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_get_spam_score() {
+        let score = |headers: &str| {
+            let storage = MailInfoStorage {
+                mail_buffer: format!("{headers}Subject: test\r\n\r\nbody\r\n").into_bytes(),
+                ..Default::default()
+            };
+            let msg = MessageParser::default()
+                .parse(&storage.mail_buffer)
+                .unwrap();
+            MailInfo::new(&storage, msg).get_spam_score()
+        };
+        assert_eq!(score(""), 0.0);
+        // the bottom-most instance is used
+        assert_eq!(score("X-Spam-Score: 1.0\r\nX-Spam-Score: 7.5\r\n"), 7.5);
+        assert_eq!(score("X-Spam-Score: 5.0garbage\r\n"), 0.0);
+    }
+
+    #[test]
+    fn test_untrusted_headers() {
+        let storage = MailInfoStorage {
+            mail_buffer: b"X-Spam-Score: 1.0\r\n\
+                           Received: from a.example.net by mx.example.org; \
+                           Mon, 1 Jan 2024 00:00:00 +0000\r\n\
+                           x-spam-score: -10\r\n\
+                           Subject: test\r\n\
+                           X-Spam-Score: -20\r\n\r\nbody\r\n"
+                .to_vec(),
+            ..Default::default()
+        };
+        let msg = MessageParser::default()
+            .parse(&storage.mail_buffer)
+            .unwrap();
+        let mail_info = MailInfo::new(&storage, msg);
+        assert_eq!(
+            mail_info.get_trusted_headers("X-Spam-Score", "example.org"),
+            ["1.0"]
+        );
+        assert!(
+            mail_info
+                .get_trusted_headers("X-Spam-Score", "example.com")
+                .is_empty()
+        );
+        assert_eq!(
+            mail_info.untrusted_header_indexes("X-Spam-Score", "example.org"),
+            [2, 3]
+        );
+        assert_eq!(
+            mail_info.untrusted_header_indexes("X-Spam-Score", "example.com"),
+            [1, 2, 3]
+        );
+        let config = Config::builder()
+            .email_classifier(EmailClassifier::builder(()).build())
+            .remove_untrusted_headers(&["X-Spam-Score", "Authentication-Results"], "example.org")
+            .build();
+        assert_eq!(
+            classify_mail(&config, &storage).removed_headers,
+            [
+                ("X-Spam-Score".to_string(), 3),
+                ("X-Spam-Score".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decision_explain() {
+        let recipients = ["a@example.org".to_string(), "b@example.org".to_string()];
+        let log = ["listed".to_string(), "REJECT (spam)".to_string()];
+        let decision = Decision {
+            queue_id: "ABC",
+            sender: "x@example.net",
+            recipients: &recipients,
+            result: ClassifyResult::Reject,
+            reason: "spam",
+            log: &log,
+        };
+        assert_eq!(
+            decision.explain(),
+            "REJECT (spam)\nqueue id: ABC\nsender: x@example.net\n\
+             recipients: a@example.org, b@example.org\nlog:\n  listed\n  REJECT (spam)"
+        );
+        let decision = Decision {
+            log: &[],
+            ..decision
+        };
+        assert!(!decision.explain().contains("log:"));
+    }
+
+    #[test]
+    fn test_shadow_classifier() {
+        fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+            mail_info.accept("old policy")
+        }
+        fn classify_v2(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+            mail_info.add_recipient("archive@example.org");
+            mail_info.reject("new policy")
+        }
+        let config = Config::builder()
+            .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+            .shadow_classifier(
+                EmailClassifier::builder(())
+                    .classify_fn(classify_v2)
+                    .build(),
+            )
+            .build();
+        let storage = MailInfoStorage {
+            mail_buffer: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(
+            run_classifier(&config, &storage),
+            Verdict {
+                log: vec![
+                    "ACCEPT (old policy)".to_string(),
+                    "shadow disagrees: ACCEPT (old policy) vs REJECT (new policy)".to_string()
+                ],
+                ..Verdict::new(ClassifyResult::Accept, "old policy".to_string())
+            }
+        );
+
+        fn classify_panic(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+            mail_info.add_recipient("archive@example.org");
+            panic!("work in progress")
+        }
+        for (shadow, logged) in [
+            (classify as fn(&(), &MailInfo) -> ClassifyResult, None),
+            (
+                classify_panic,
+                Some("shadow classifier panicked: work in progress"),
+            ),
+        ] {
+            let config = Config::builder()
+                .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+                .shadow_classifier(EmailClassifier::builder(()).classify_fn(shadow).build())
+                .build();
+            let mut log = vec!["ACCEPT (old policy)".to_string()];
+            log.extend(logged.map(str::to_string));
+            assert_eq!(
+                run_classifier(&config, &storage),
+                Verdict {
+                    log,
+                    ..Verdict::new(ClassifyResult::Accept, "old policy".to_string())
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_recipient() {
+        fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+            mail_info.add_recipient("archive@example.org");
+            mail_info.add_recipient("archive@example.org");
+            if mail_info.get_subject() == "spam" {
+                return mail_info.reject("spam");
+            }
+            mail_info.accept("archived")
+        }
+        let config = Config::builder()
+            .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+            .build();
+        let mut storage = MailInfoStorage {
+            mail_buffer: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(
+            classify_mail(&config, &storage).added_recipients,
+            ["archive@example.org"]
+        );
+        storage.mail_buffer = b"Subject: spam\r\n\r\nbody\r\n".to_vec();
+        assert!(classify_mail(&config, &storage).added_recipients.is_empty());
+    }
+
+    #[test]
+    fn test_batv_signing() {
+        fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+            match mail_info.get_subject() {
+                "spam" => mail_info.reject("spam"),
+                "list" => {
+                    mail_info.change_sender("list-bounces@example.org");
+                    mail_info.accept("list")
+                }
+                _ => mail_info.accept("ok"),
+            }
+        }
+        let config = Config::builder()
+            .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+            .batv_signing(b"key", 7, &["Example.org"])
+            .build();
+        let mut storage = MailInfoStorage {
+            sender: "alice@example.org".to_string(),
+            mail_buffer: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        let sender = classify_mail(&config, &storage).changed_sender.unwrap();
+        assert_eq!(
+            batv::verify(&sender, b"key", batv::today(), 7),
+            Some("alice@example.org")
+        );
+        // already tagged
+        storage.sender = sender;
+        assert_eq!(classify_mail(&config, &storage).changed_sender, None);
+        storage.sender = "alice@example.net".to_string();
+        assert_eq!(classify_mail(&config, &storage).changed_sender, None);
+        storage.sender = "alice@example.org".to_string();
+        storage.mail_buffer = b"Subject: list\r\n\r\nbody\r\n".to_vec();
+        assert_eq!(
+            classify_mail(&config, &storage).changed_sender.as_deref(),
+            Some("list-bounces@example.org")
+        );
+        storage.mail_buffer = b"Subject: spam\r\n\r\nbody\r\n".to_vec();
+        assert_eq!(classify_mail(&config, &storage).changed_sender, None);
+    }
+
+    #[test]
+    fn test_srs_forward() {
+        fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+            mail_info.srs_forward(b"secret", "fwd.example");
+            mail_info.accept("forwarded")
+        }
+        let config = Config::builder()
+            .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+            .build();
+        let mut storage = MailInfoStorage {
+            sender: "alice@example.com".to_string(),
+            mail_buffer: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        let sender = classify_mail(&config, &storage).changed_sender.unwrap();
+        assert!(sender.ends_with("=example.com=alice@fwd.example"));
+        assert_eq!(
+            srs::decode(&sender, b"secret", batv::today(), 21).as_deref(),
+            Some("alice@example.com")
+        );
+        storage.sender = "bob@Fwd.example".to_string();
+        assert_eq!(classify_mail(&config, &storage).changed_sender, None);
+        storage.sender = String::new();
+        assert_eq!(classify_mail(&config, &storage).changed_sender, None);
+    }
+
+    #[test]
+    fn test_bypass_key() {
+        fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+            mail_info.reject("content")
+        }
+        let config = Config::builder()
+            .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+            .bypass_key(b"site key", 1)
+            .build();
+        let token = bypass::sign("oncall@example.org", b"site key", batv::today(), 1);
+        let mut storage = MailInfoStorage {
+            recipients: vec!["oncall@example.org".to_string()],
+            mail_buffer: format!(
+                "X-Srmilter-Bypass: 1-00\r\nX-Srmilter-Bypass: {token}\r\n\r\nbody\r\n"
+            )
+            .into_bytes(),
+            ..Default::default()
+        };
+        assert_eq!(
+            classify_mail(&config, &storage),
+            Verdict {
+                removed_headers: vec![
+                    ("X-Srmilter-Bypass".to_string(), 2),
+                    ("X-Srmilter-Bypass".to_string(), 1)
+                ],
+                ..Verdict::new(ClassifyResult::Accept, "bypass token".to_string())
+            }
+        );
+        storage.recipients.push("other@example.org".to_string());
+        assert_eq!(
+            classify_mail(&config, &storage),
+            Verdict {
+                log: vec!["REJECT (content)".to_string()],
+                ..Verdict::new(ClassifyResult::Reject, "content".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn test_delay_reply() {
+        fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+            mail_info.delay_reply(Duration::from_secs(120));
+            mail_info.reject("tarpit")
+        }
+        let config = Config::builder()
+            .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+            .build();
+        let storage = MailInfoStorage {
+            mail_buffer: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(
+            classify_mail(&config, &storage).delay,
+            Duration::from_secs(60)
+        );
+    }
 }
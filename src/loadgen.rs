@@ -0,0 +1,315 @@
+//! `loadgen` subcommand: a milter client acting like Postfix, replaying messages to a milter
+//! for capacity planning.
+//!
+//! Each worker opens one connection, negotiates options and sends the messages assigned to
+//! it, skipping the stages the milter disabled and waiting for replies only where the
+//! milter expects to send one.
+
+use crate::cli::LoadgenArgs;
+use crate::daemon::extend_crlf;
+use crate::milter::PacketBuffer;
+use crate::milter::constants::*;
+use crate::reader_extention::ReadExt as _;
+use crate::simulate::{pace, read_messages, report};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{BufReader, BufWriter};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BODY_CHUNK: usize = 65535;
+
+// latencies and number of messages per verdict
+type Results = (Vec<Duration>, BTreeMap<&'static str, usize>);
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    packets: PacketBuffer,
+    protocol: u32,
+    read_buffer: Vec<u8>,
+}
+
+impl Connection {
+    fn open(target: &str) -> Result<Self, Box<dyn Error>> {
+        let stream = TcpStream::connect(target).map_err(|e| format!("{target}: {e}"))?;
+        stream.set_nodelay(true)?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            packets: PacketBuffer::default(),
+            protocol: 0,
+            read_buffer: Vec::new(),
+        };
+        let mut payload = Vec::with_capacity(12);
+        payload.extend_from_slice(&SMFIF_VERSION.to_be_bytes());
+        payload.extend_from_slice(&0x1ffu32.to_be_bytes()); // all actions
+        payload.extend_from_slice(&0x001fffffu32.to_be_bytes()); // all protocol steps
+        connection.packets.push(b'O', &payload);
+        connection.packets.send(&mut connection.writer)?;
+        let (cmd, data) = connection.read_packet()?;
+        if cmd != b'O' || data.len() < 12 {
+            return Err(format!("unexpected option negotiation reply {:?}", cmd as char).into());
+        }
+        connection.protocol = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+        Ok(connection)
+    }
+
+    fn read_packet(&mut self) -> Result<(u8, &[u8]), Box<dyn Error>> {
+        let len = self.reader.read_u32_be()? as usize;
+        if len == 0 {
+            return Err("empty reply packet".into());
+        }
+        self.reader.read_bytes(len, &mut self.read_buffer)?;
+        Ok((self.read_buffer[0], &self.read_buffer[1..]))
+    }
+
+    /// Sends a command of a stage which may be disabled by `no_flag`, and waits for a reply
+    /// unless disabled by `nr_flag`.
+    ///
+    /// Commands without reply are only queued and sent together with the next command
+    /// which expects one. Returns the reply command, if one was received.
+    fn stage(
+        &mut self,
+        cmd: u8,
+        payload: &[u8],
+        no_flag: u32,
+        nr_flag: u32,
+    ) -> Result<Option<u8>, Box<dyn Error>> {
+        if self.protocol & no_flag != 0 {
+            return Ok(None);
+        }
+        self.packets.push(cmd, payload);
+        if self.protocol & nr_flag != 0 {
+            return Ok(None);
+        }
+        self.packets.send(&mut self.writer)?;
+        Ok(Some(self.read_packet()?.0))
+    }
+
+    /// Sends one message and returns its verdict.
+    fn send_message(
+        &mut self,
+        args: &LoadgenArgs,
+        id: &str,
+        message: &[u8],
+    ) -> Result<&'static str, Box<dyn Error>> {
+        let verdict = match self.send_stages(args, id, message)? {
+            Some(verdict) => verdict,
+            None => self.end_of_message()?,
+        };
+        Ok(verdict)
+    }
+
+    // Sends all stages before end of message. Returns a verdict, if the milter gave one early.
+    fn send_stages(
+        &mut self,
+        args: &LoadgenArgs,
+        id: &str,
+        message: &[u8],
+    ) -> Result<Option<&'static str>, Box<dyn Error>> {
+        let mut macros = b"M".to_vec();
+        for s in ["i", id] {
+            macros.extend_from_slice(s.as_bytes());
+            macros.push(0);
+        }
+        self.packets.push(b'D', &macros);
+        let mut stages: Vec<(u8, Vec<u8>, u32, u32)> = Vec::new();
+        stages.push((
+            b'C',
+            b"localhost\0U".to_vec(),
+            SMFIP_NOCONNECT,
+            SMFIP_NR_CONN,
+        ));
+        stages.push((b'H', b"localhost\0".to_vec(), SMFIP_NOHELO, SMFIP_NR_HELO));
+        stages.push((
+            b'M',
+            zstring(&format!("<{}>", args.sender)),
+            SMFIP_NOMAIL,
+            SMFIP_NR_MAIL,
+        ));
+        for recipient in &args.recipients {
+            stages.push((
+                b'R',
+                zstring(&format!("<{recipient}>")),
+                SMFIP_NORCPT,
+                SMFIP_NR_RCPT,
+            ));
+        }
+        stages.push((b'T', Vec::new(), SMFIP_NODATA, SMFIP_NR_DATA));
+        let (header, body) = split_message(message);
+        for (name, value) in header_fields(header) {
+            let value = match self.protocol & SMFIP_HDR_LEADSPC {
+                0 => value.trim_ascii_start(),
+                _ => &value,
+            };
+            let mut payload = name.to_vec();
+            payload.push(0);
+            payload.extend_from_slice(value);
+            payload.push(0);
+            stages.push((b'L', payload, SMFIP_NOHDRS, SMFIP_NR_HDR));
+        }
+        stages.push((b'N', Vec::new(), SMFIP_NOEOH, SMFIP_NR_EOH));
+        for (cmd, payload, no_flag, nr_flag) in stages {
+            if let Some(reply) = self.stage(cmd, &payload, no_flag, nr_flag)?
+                && let Some(verdict) = early_verdict(reply)
+            {
+                self.packets.push(b'A', b"");
+                return Ok(Some(verdict));
+            }
+        }
+        let mut crlf_body = Vec::with_capacity(body.len() + body.len() / 32);
+        extend_crlf(&mut crlf_body, body);
+        for chunk in crlf_body.chunks(BODY_CHUNK) {
+            match self.stage(b'B', chunk, SMFIP_NOBODY, SMFIP_NR_BODY)? {
+                Some(b's') => break,
+                Some(reply) => {
+                    if let Some(verdict) = early_verdict(reply) {
+                        self.packets.push(b'A', b"");
+                        return Ok(Some(verdict));
+                    }
+                }
+                None => (),
+            }
+        }
+        Ok(None)
+    }
+
+    fn end_of_message(&mut self) -> Result<&'static str, Box<dyn Error>> {
+        self.packets.push(b'E', b"");
+        self.packets.send(&mut self.writer)?;
+        let mut quarantined = false;
+        loop {
+            let (cmd, _) = self.read_packet()?;
+            let verdict = match cmd {
+                b'q' => {
+                    quarantined = true;
+                    continue;
+                }
+                b'a' | b'c' if quarantined => "quarantine",
+                b'a' | b'c' => "accept",
+                b'r' => "reject",
+                b't' => "tempfail",
+                b'd' => "discard",
+                b'y' => "replycode",
+                _ => continue, // modifications and progress
+            };
+            return Ok(verdict);
+        }
+    }
+
+    fn quit(mut self) -> Result<(), Box<dyn Error>> {
+        self.packets.push(b'Q', b"");
+        self.packets.send(&mut self.writer)?;
+        Ok(())
+    }
+}
+
+// Returns the verdict of a reply before end of message, or `None` for continue.
+fn early_verdict(reply: u8) -> Option<&'static str> {
+    match reply {
+        b'a' => Some("accept"),
+        b'r' => Some("reject"),
+        b't' => Some("tempfail"),
+        b'd' => Some("discard"),
+        b'y' => Some("replycode"),
+        _ => None,
+    }
+}
+
+fn zstring(s: &str) -> Vec<u8> {
+    let mut v = s.as_bytes().to_vec();
+    v.push(0);
+    v
+}
+
+// Splits a message at the first empty line.
+fn split_message(message: &[u8]) -> (&[u8], &[u8]) {
+    for (pos, _) in message.iter().enumerate().filter(|&(_, &c)| c == b'\n') {
+        let rest = &message[pos + 1..];
+        if rest.starts_with(b"\r\n") {
+            return (&message[..pos + 1], &rest[2..]);
+        }
+        if rest.starts_with(b"\n") {
+            return (&message[..pos + 1], &rest[1..]);
+        }
+    }
+    (message, b"")
+}
+
+// Returns the header fields as name and value, continuation lines joined with LF as
+// Postfix does.
+fn header_fields(header: &[u8]) -> Vec<(&[u8], Vec<u8>)> {
+    let mut fields: Vec<(&[u8], Vec<u8>)> = Vec::new();
+    for line in header.split(|&c| c == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b" ") || line.starts_with(b"\t") {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(b'\n');
+                value.extend_from_slice(line);
+            }
+        } else if let Some(colon) = line.iter().position(|&c| c == b':') {
+            fields.push((&line[..colon], line[colon + 1..].to_vec()));
+        }
+    }
+    fields
+}
+
+pub fn loadgen(args: &LoadgenArgs) -> Result<(), Box<dyn Error>> {
+    let messages = Arc::new(read_messages(&args.mbox)?);
+    let next = Arc::new(AtomicUsize::new(0));
+    let results: Arc<Mutex<Results>> = Arc::default();
+    let start = Instant::now();
+    let workers: Vec<_> = (0..args.concurrency.max(1))
+        .map(|_| {
+            let (messages, next, results) = (messages.clone(), next.clone(), results.clone());
+            let args = args.clone();
+            thread::spawn(move || -> Result<(), String> {
+                let mut connection = Connection::open(&args.target).map_err(|e| e.to_string())?;
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(message) = messages.get(i) else {
+                        break;
+                    };
+                    pace(start, i, args.rate);
+                    let t = Instant::now();
+                    let verdict = connection
+                        .send_message(&args, &format!("LOADGEN{i}"), message)
+                        .map_err(|e| format!("message {i}: {e}"))?;
+                    let mut results = results.lock().unwrap();
+                    results.0.push(t.elapsed());
+                    *results.1.entry(verdict).or_default() += 1;
+                }
+                connection.quit().map_err(|e| e.to_string())
+            })
+        })
+        .collect();
+    let mut errors = 0;
+    for worker in workers {
+        if let Err(e) = worker.join().unwrap() {
+            eprintln!("{e}");
+            errors += 1;
+        }
+    }
+    let (latencies, verdicts) = std::mem::take(&mut *results.lock().unwrap());
+    report(latencies, start.elapsed());
+    let verdicts: Vec<String> = verdicts.iter().map(|(v, n)| format!("{v}={n}")).collect();
+    println!("verdicts: {}", verdicts.join(" "));
+    if errors > 0 {
+        return Err(format!("{errors} connections failed").into());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_message_split() {
+    let (header, body) = split_message(b"Subject: a\r\n b\r\nFrom:x@y\r\n\r\nText\r\n");
+    assert_eq!(body, b"Text\r\n");
+    let fields = header_fields(header);
+    assert_eq!(fields.len(), 2);
+    assert_eq!(fields[0], (&b"Subject"[..], b" a\n b".to_vec()));
+    assert_eq!(fields[1], (&b"From"[..], b"x@y".to_vec()));
+}
@@ -40,3 +40,41 @@ pub mod constants {
     pub const SMFIP_MDS_256K: u32 = 0x10000000;
     pub const SMFIP_MDS_1M: u32 = 0x20000000;
 }
+
+/// Encodes milter packets (length prefix, command, payload) into one contiguous buffer.
+///
+/// Replies collected for a stage are sent with a single write, e.g. the quarantine reply
+/// and the accept reply at end of message. `loadgen` uses it for the commands it sends.
+#[derive(Default)]
+pub(crate) struct PacketBuffer {
+    buf: Vec<u8>,
+}
+
+impl PacketBuffer {
+    /// Appends a packet with command `cmd` and `payload`.
+    pub(crate) fn push(&mut self, cmd: u8, payload: &[u8]) {
+        self.buf
+            .extend_from_slice(&((payload.len() as u32 + 1).to_be_bytes()));
+        self.buf.push(cmd);
+        self.buf.extend_from_slice(payload);
+    }
+    /// Writes all pending packets with one `write_all` and flushes the writer.
+    pub(crate) fn send(&mut self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        writer.write_all(&self.buf)?;
+        writer.flush()?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_packet_buffer() {
+    let mut replies = PacketBuffer::default();
+    replies.push(b'q', b"milter\0");
+    replies.push(b'a', b"");
+    let mut out = Vec::new();
+    replies.send(&mut out).unwrap();
+    assert_eq!(out, b"\0\0\0\x08qmilter\0\0\0\0\x01a");
+    replies.send(&mut out).unwrap();
+    assert_eq!(out.len(), 17);
+}
@@ -0,0 +1,98 @@
+//! IP networks in CIDR notation.
+//!
+//! Used for the trusted client fast path, see
+//! [`ConfigBuilder::trusted_networks()`](crate::ConfigBuilder::trusted_networks).
+//!
+//! # Example
+//!
+//! ```ignore
+//! let network: Network = "192.0.2.0/24".parse()?;
+//! assert!(network.contains("192.0.2.17".parse()?));
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network, e.g. `192.0.2.0/24` or `2001:db8::/32`.
+///
+/// An address without prefix length is a network of this single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// Checks whether `ip` is in the network. IPv4-mapped IPv6 addresses are treated as
+    /// IPv4 addresses.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid network address: {s}"))?;
+        let addr = addr.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("invalid prefix length: {s}"))?,
+            None => max,
+        };
+        Ok(Network { addr, prefix })
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[test]
+fn test_network() {
+    let net: Network = "192.0.2.0/24".parse().unwrap();
+    assert!(net.contains("192.0.2.255".parse().unwrap()));
+    assert!(net.contains("::ffff:192.0.2.1".parse().unwrap()));
+    assert!(!net.contains("192.0.3.1".parse().unwrap()));
+    assert!(!net.contains("2001:db8::1".parse().unwrap()));
+    let net: Network = "2001:db8::/32".parse().unwrap();
+    assert!(net.contains("2001:db8:ffff::1".parse().unwrap()));
+    assert!(!net.contains("2001:db9::1".parse().unwrap()));
+    let any: Network = "0.0.0.0/0".parse().unwrap();
+    assert!(any.contains("198.51.100.1".parse().unwrap()));
+    let host: Network = "198.51.100.7".parse().unwrap();
+    assert_eq!(host.to_string(), "198.51.100.7/32");
+    assert!(host.contains("198.51.100.7".parse().unwrap()));
+    assert!(!host.contains("198.51.100.8".parse().unwrap()));
+    assert!("192.0.2.0/33".parse::<Network>().is_err());
+    assert!("example.org/24".parse::<Network>().is_err());
+}
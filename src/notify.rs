@@ -0,0 +1,167 @@
+//! Notification of the postmaster about selected decisions.
+//!
+//! A [`PostmasterNotifier`] mails the [explanation](crate::Decision::explain) of decisions
+//! with selected verdicts to a postmaster address, e.g. to review the hits of a new policy
+//! which only quarantines while it is being tested. It is meant to be called from the
+//! [`ConfigBuilder::on_decision()`](crate::ConfigBuilder::on_decision) callback. The
+//! message is submitted with `sendmail` with a null envelope sender.
+//!
+//! The number of notifications per hour is limited, so that a spam wave does not turn into
+//! a mail storm to the postmaster. Like a
+//! [`CircuitBreaker`](crate::circuit_breaker::CircuitBreaker), the notifier keeps its state
+//! in memory: in fork mode each child process starts with the count the parent had, so the
+//! limit applies per connection only.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let notifier = PostmasterNotifier::new("postmaster@example.org", &[ClassifyResult::Quarantine])
+//!     .reason_contains("dlp:")
+//!     .max_per_hour(20);
+//! let config = Config::builder()
+//!     .email_classifier(classifier)
+//!     .on_decision(move |d| notifier.notify(d))
+//!     .build();
+//! ```
+
+use crate::{ClassifyResult, Decision};
+use std::error::Error;
+use std::io::Write as _;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// Mails explanations of selected decisions to a postmaster address.
+pub struct PostmasterNotifier {
+    address: String,
+    verdicts: Vec<ClassifyResult>,
+    reason_contains: String,
+    max_per_hour: u32,
+    sendmail: String,
+    // start of the current hour and notifications sent in it
+    window: Mutex<Option<(Instant, u32)>>,
+}
+
+impl PostmasterNotifier {
+    /// Creates a notifier which mails decisions with one of `verdicts` to `address`.
+    ///
+    /// By default at most 10 notifications are sent per hour with `/usr/sbin/sendmail`.
+    pub fn new(address: &str, verdicts: &[ClassifyResult]) -> Self {
+        PostmasterNotifier {
+            address: address.to_string(),
+            verdicts: verdicts.to_vec(),
+            reason_contains: String::new(),
+            max_per_hour: 10,
+            sendmail: "/usr/sbin/sendmail".to_string(),
+            window: Mutex::new(None),
+        }
+    }
+    /// Only notifies about decisions whose reason contains `text`, e.g. a prefix used by
+    /// the rules to be reviewed.
+    pub fn reason_contains(mut self, text: &str) -> Self {
+        self.reason_contains = text.to_string();
+        self
+    }
+    /// Limits the number of notifications per hour. Further decisions within the hour are
+    /// only logged.
+    pub fn max_per_hour(mut self, max: u32) -> Self {
+        self.max_per_hour = max;
+        self
+    }
+    /// Sets the path of the `sendmail` program used to submit notifications.
+    pub fn sendmail(mut self, path: &str) -> Self {
+        self.sendmail = path.to_string();
+        self
+    }
+    /// Mails the explanation of `decision` to the postmaster if its verdict and reason are
+    /// selected and the hourly limit is not reached.
+    ///
+    /// Failures are logged, not returned, so that this can be called from the decision
+    /// callback directly.
+    pub fn notify(&self, decision: &Decision) {
+        if !self.verdicts.contains(&decision.result)
+            || !decision.reason.contains(&self.reason_contains)
+        {
+            return;
+        }
+        if !self.take(Instant::now()) {
+            eprintln!(
+                "{}: postmaster notification suppressed, limit of {} per hour reached",
+                decision.queue_id, self.max_per_hour
+            );
+            return;
+        }
+        if let Err(e) = self.send(decision) {
+            eprintln!("{}: postmaster notification: {e}", decision.queue_id);
+        }
+    }
+    // Counts a notification at `now`, returns false if the limit is reached.
+    fn take(&self, now: Instant) -> bool {
+        let mut window = self.window.lock().unwrap();
+        match *window {
+            Some((start, ref mut count)) if now.duration_since(start) < HOUR => {
+                if *count >= self.max_per_hour {
+                    return false;
+                }
+                *count += 1;
+            }
+            _ => *window = Some((now, 1)),
+        }
+        self.max_per_hour > 0
+    }
+    fn message(&self, decision: &Decision) -> String {
+        format!(
+            "From: {address}\r\nTo: {address}\r\nSubject: srmilter: {} {}\r\n\
+             Auto-Submitted: auto-generated\r\nMIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\
+             \r\n{}\r\n",
+            decision.result.uc(),
+            decision.queue_id,
+            decision.explain().replace('\n', "\r\n"),
+            address = self.address,
+        )
+    }
+    fn send(&self, decision: &Decision) -> Result<(), Box<dyn Error>> {
+        let mut child = Command::new(&self.sendmail)
+            .args(["-i", "-f", "<>", "--", &self.address])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("{}: {e}", self.sendmail))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(self.message(decision).as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(format!("{}: {status}", self.sendmail).into());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_postmaster_notifier() {
+    let notifier = PostmasterNotifier::new("postmaster@example.org", &[ClassifyResult::Reject])
+        .max_per_hour(2);
+    let now = Instant::now();
+    assert!(notifier.take(now));
+    assert!(notifier.take(now + Duration::from_secs(10)));
+    assert!(!notifier.take(now + Duration::from_secs(20)));
+    assert!(notifier.take(now + HOUR));
+    assert!(!PostmasterNotifier::new("x", &[]).max_per_hour(0).take(now));
+
+    let recipients = ["user@example.org".to_string()];
+    let decision = Decision {
+        queue_id: "ABC",
+        sender: "x@example.net",
+        recipients: &recipients,
+        result: ClassifyResult::Reject,
+        reason: "spam",
+        log: &[],
+    };
+    let message = notifier.message(&decision);
+    assert!(message.starts_with("From: postmaster@example.org\r\nTo: postmaster@example.org\r\n"));
+    assert!(message.contains("Subject: srmilter: REJECT ABC\r\n"));
+    assert!(message.ends_with("\r\n\r\nREJECT (spam)\r\nqueue id: ABC\r\nsender: x@example.net\r\nrecipients: user@example.org\r\n"));
+}
@@ -0,0 +1,95 @@
+//! Multilingual credential phishing phrases (feature `phishing`).
+//!
+//! A versioned list of phrases typical for credential phishing ("verify your account",
+//! "ihr Postfach ist voll", ...) in several languages is compiled into the library.
+//! [`score_text()`] sums the weights of the phrases found in a text, so sites do not have
+//! to maintain their own multilingual regular expressions. The list is in
+//! `data/phishing.txt` of the source distribution; [`PACK_VERSION`] is its version.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let text = format!("{} {}", mail_info.get_subject(), mail_info.get_text());
+//! let score = phishing::score_text(&text);
+//! if score.score >= 5 {
+//!     return mail_info.quarantine(&format!("phishing phrases {:?}", score.matches));
+//! }
+//! ```
+
+use std::sync::OnceLock;
+
+const PACK: &str = include_str!("../data/phishing.txt");
+
+/// Version of the phrase list.
+pub const PACK_VERSION: &str = "2025.10.1";
+
+/// A phrase of the list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phrase {
+    /// ISO 639-1 language code (e.g. `"de"`).
+    pub language: String,
+    /// Weight added to the score if the phrase is found.
+    pub weight: u32,
+    /// The phrase, lowercase.
+    pub phrase: String,
+}
+
+/// Result of [`score_text()`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PhishingScore {
+    /// Sum of the weights of the phrases found.
+    pub score: u32,
+    /// The phrases found.
+    pub matches: Vec<&'static Phrase>,
+}
+
+/// Returns the phrases of the list.
+pub fn phrases() -> &'static [Phrase] {
+    static PHRASES: OnceLock<Vec<Phrase>> = OnceLock::new();
+    PHRASES.get_or_init(|| {
+        PACK.lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, ' ');
+                Some(Phrase {
+                    language: fields.next()?.to_string(),
+                    weight: fields.next()?.parse().ok()?,
+                    phrase: fields.next()?.to_lowercase(),
+                })
+            })
+            .collect()
+    })
+}
+
+/// Scores `text` against the phrase list.
+///
+/// The text is lowercased and runs of whitespace are collapsed, so phrases also match
+/// across line breaks.
+pub fn score_text(text: &str) -> PhishingScore {
+    let text = text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    let mut score = PhishingScore::default();
+    for phrase in phrases() {
+        if text.contains(&phrase.phrase) {
+            score.score += phrase.weight;
+            score.matches.push(phrase);
+        }
+    }
+    score
+}
+
+#[test]
+fn test_score_text() {
+    assert!(PACK.contains(PACK_VERSION));
+    assert!(phrases().len() > 40);
+    let score = score_text(
+        "Sehr geehrter Kunde,\nIhr Postfach\n ist VOLL. Bitte bestätigen Sie Ihr Konto.",
+    );
+    assert_eq!(score.score, 6);
+    assert!(score.matches.iter().all(|p| p.language == "de"));
+    assert_eq!(score_text("[Armoni Scans] Oturum açma bilgileri").score, 1);
+    assert_eq!(score_text("Meeting notes"), PhishingScore::default());
+}
@@ -0,0 +1,182 @@
+//! Recipient verification at the RCPT stage.
+//!
+//! A validator registered with
+//! [`ConfigBuilder::recipient_validator()`](crate::ConfigBuilder::recipient_validator) is
+//! called for every envelope recipient. Unknown recipients are rejected with
+//! `550 5.1.1` while the client is still in the SMTP dialog, so no bounce is generated
+//! for them. If the validator can not decide, the recipient is rejected temporarily with
+//! `451 4.3.0`.
+//!
+//! Besides custom closures, two backends are provided: [`static_list()`] for a fixed set
+//! of addresses and [`smtp_callout()`], which asks the internal mailstore. There is no
+//! LDAP backend; a closure using an LDAP client of your choice can be used instead.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let config = Config::builder()
+//!     .email_classifier(classifier)
+//!     .recipient_validator(recipient::smtp_callout("mailstore.internal:25", "mx.example.com"))
+//!     .build();
+//! ```
+
+use crate::addresses::normalize;
+use std::collections::HashSet;
+use std::io::{BufRead as _, BufReader, Write as _};
+use std::net::{TcpStream, ToSocketAddrs as _};
+use std::time::Duration;
+
+/// Result of a recipient validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecipientStatus {
+    /// The recipient exists.
+    Valid,
+    /// The recipient does not exist and is rejected permanently.
+    Unknown,
+    /// The recipient could not be verified and is rejected temporarily.
+    Unavailable,
+}
+
+/// Returns a validator which accepts the recipients in `recipients`.
+///
+/// Addresses are compared in their [normalized](crate::addresses) form. An entry `@domain`
+/// accepts all recipients of the domain (catch-all).
+pub fn static_list(recipients: &[String]) -> impl Fn(&str) -> RecipientStatus + use<> {
+    let recipients: HashSet<String> = recipients.iter().map(|r| normalize(r)).collect();
+    move |recipient| {
+        let recipient = normalize(recipient);
+        let catch_all = recipient.rfind('@').map(|at| &recipient[at..]);
+        if recipients.contains(&recipient) || catch_all.is_some_and(|d| recipients.contains(d)) {
+            RecipientStatus::Valid
+        } else {
+            RecipientStatus::Unknown
+        }
+    }
+}
+
+const CALLOUT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reads a (possibly multiline) SMTP reply and returns its code.
+fn read_reply(reader: &mut BufReader<&TcpStream>) -> std::io::Result<u16> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .ok_or_else(|| std::io::Error::other(format!("invalid SMTP reply {line:?}")));
+        }
+    }
+}
+
+fn callout(server: &str, helo: &str, recipient: &str) -> std::io::Result<RecipientStatus> {
+    let address = server
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::other(format!("{server}: no address")))?;
+    let stream = TcpStream::connect_timeout(&address, CALLOUT_TIMEOUT)?;
+    stream.set_read_timeout(Some(CALLOUT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CALLOUT_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    // a failure before RCPT TO says nothing about the recipient
+    let mut stage = "greeting";
+    let mut code = read_reply(&mut reader)?;
+    for (command, next_stage) in [
+        (format!("HELO {helo}\r\n"), "HELO"),
+        ("MAIL FROM:<>\r\n".to_string(), "MAIL FROM"),
+    ] {
+        if code / 100 != 2 {
+            break;
+        }
+        stage = next_stage;
+        writer.write_all(command.as_bytes())?;
+        code = read_reply(&mut reader)?;
+    }
+    if code / 100 != 2 {
+        let _ = writer.write_all(b"QUIT\r\n");
+        return Err(std::io::Error::other(format!("{code} reply to {stage}")));
+    }
+    writer.write_all(format!("RCPT TO:<{recipient}>\r\n").as_bytes())?;
+    code = read_reply(&mut reader)?;
+    let _ = writer.write_all(b"QUIT\r\n");
+    Ok(match code / 100 {
+        2 => RecipientStatus::Valid,
+        5 => RecipientStatus::Unknown,
+        _ => RecipientStatus::Unavailable,
+    })
+}
+
+/// Returns a validator which verifies recipients with an SMTP callout to `server`
+/// (`host:port`), typically the internal mailstore.
+///
+/// For every recipient a new connection is opened and the recipient is tried with a null
+/// sender. A 2xx reply to `RCPT TO` means valid, 5xx unknown. Temporary errors, errors
+/// before `RCPT TO` (e.g. a 554 greeting of an overloaded mailstore), timeouts and
+/// connection failures are logged and result in [`RecipientStatus::Unavailable`].
+pub fn smtp_callout(server: &str, helo: &str) -> impl Fn(&str) -> RecipientStatus + use<> {
+    let server = server.to_string();
+    let helo = helo.to_string();
+    move |recipient| match callout(&server, &helo, recipient) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("recipient callout to {server} failed: {e}");
+            RecipientStatus::Unavailable
+        }
+    }
+}
+
+#[test]
+fn test_recipient_validators() {
+    use std::net::TcpListener;
+
+    let validator = static_list(&["Alice@example.com".to_string(), "@example.org".to_string()]);
+    assert_eq!(validator("alice@EXAMPLE.com"), RecipientStatus::Valid);
+    assert_eq!(validator("bob@example.com"), RecipientStatus::Unknown);
+    assert_eq!(validator("bob@example.org"), RecipientStatus::Valid);
+    assert_eq!(validator("alice+tag@example.com"), RecipientStatus::Valid);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap().to_string();
+    let mailstore = std::thread::spawn(move || {
+        for reply in ["250 ok", "550 5.1.1 no such user", "451 try later"] {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut writer = &stream;
+            writer.write_all(b"220-mailstore\r\n220 ready\r\n").unwrap();
+            let mut line = String::new();
+            for _ in 0..2 {
+                reader.read_line(&mut line).unwrap();
+                writer.write_all(b"250 ok\r\n").unwrap();
+            }
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert!(line.starts_with("RCPT TO:<"));
+            writer.write_all(format!("{reply}\r\n").as_bytes()).unwrap();
+        }
+        // a mailstore refusing service must not make recipients unknown
+        let (stream, _) = listener.accept().unwrap();
+        (&stream).write_all(b"554 5.3.2 too busy\r\n").unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut writer = &stream;
+        writer.write_all(b"220 ready\r\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        writer.write_all(b"250 ok\r\n").unwrap();
+        reader.read_line(&mut line).unwrap();
+        writer.write_all(b"550 5.7.1 no null sender\r\n").unwrap();
+    });
+    let validator = smtp_callout(&server, "mx.example.com");
+    assert_eq!(validator("a@example.com"), RecipientStatus::Valid);
+    assert_eq!(validator("b@example.com"), RecipientStatus::Unknown);
+    assert_eq!(validator("c@example.com"), RecipientStatus::Unavailable);
+    assert_eq!(validator("d@example.com"), RecipientStatus::Unavailable);
+    assert_eq!(validator("e@example.com"), RecipientStatus::Unavailable);
+    mailstore.join().unwrap();
+    assert_eq!(validator("f@example.com"), RecipientStatus::Unavailable);
+}
@@ -0,0 +1,174 @@
+//! Spam scores from upstream filters.
+//!
+//! Filters running before the milter (SpamAssassin, rspamd, a gateway appliance) report
+//! their verdict in a header. [`ScoreHeaders`] knows which headers to read, how to parse
+//! them and which score thresholds lead to which verdict.
+//!
+//! The headers can be forged by the sender. With
+//! [`trusted_domain()`](ScoreHeaders::trusted_domain), only header instances added above
+//! the first `Received:` header of a host in that domain are used, see
+//! [`MailInfo::get_trusted_headers()`].
+//!
+//! # Example
+//!
+//! ```ignore
+//! let scores = ScoreHeaders::new()
+//!     .header("X-Rspamd-Score", ScoreFormat::Number)
+//!     .header("X-Spam-Status", ScoreFormat::Field("score".into()))
+//!     .trusted_domain("mx.example.org")
+//!     .above(15.0, ClassifyResult::Reject)
+//!     .above(5.0, ClassifyResult::Quarantine);
+//!
+//! // in the classifier
+//! if let Some(result) = ctx.scores.check(mail_info) {
+//!     return result;
+//! }
+//! ```
+
+use crate::{ClassifyResult, MailInfo};
+
+/// The format of a score header value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScoreFormat {
+    /// The value starts with the score, e.g. `X-Spam-Score: 5.3`.
+    Number,
+    /// The score is given as `name=score` within the value, e.g.
+    /// `X-Spam-Status: Yes, score=5.3 required=5.0` with name `score`.
+    Field(String),
+    /// The score is the first number in square brackets, e.g.
+    /// `X-Spamd-Result: default: False [5.30 / 15.00]`.
+    Bracketed,
+}
+
+impl ScoreFormat {
+    /// Parses the score from a header value.
+    pub fn parse(&self, value: &str) -> Option<f32> {
+        let value = value.trim();
+        let number = match self {
+            ScoreFormat::Number => value,
+            ScoreFormat::Field(name) => {
+                let lower = value.to_ascii_lowercase();
+                let key = format!("{}=", name.to_ascii_lowercase());
+                let pos = lower.match_indices(&key).map(|(pos, _)| pos).find(|&pos| {
+                    lower[..pos]
+                        .chars()
+                        .next_back()
+                        .is_none_or(|c| !c.is_ascii_alphanumeric() && c != '_')
+                })?;
+                &value[pos + key.len()..]
+            }
+            ScoreFormat::Bracketed => value[value.find('[')? + 1..].trim_start(),
+        };
+        let end = number
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+            .unwrap_or(number.len());
+        number[..end].parse().ok()
+    }
+}
+
+/// Score headers of upstream filters and the verdicts for score thresholds.
+#[derive(Debug, Clone, Default)]
+pub struct ScoreHeaders {
+    headers: Vec<(String, ScoreFormat)>,
+    trusted_domain: Option<String>,
+    thresholds: Vec<(f32, ClassifyResult)>,
+}
+
+impl ScoreHeaders {
+    /// Creates an empty set of score headers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Reads the score from header `name` in the given format.
+    ///
+    /// Headers are tried in the order they were added, the first valid score is used.
+    pub fn header(mut self, name: &str, format: ScoreFormat) -> Self {
+        self.headers.push((name.to_string(), format));
+        self
+    }
+    /// Only uses score headers added by hosts in `domain`.
+    ///
+    /// Header instances below the first `Received:` header with a `by` host ending with
+    /// `domain` were already present when the message reached that host and are ignored.
+    pub fn trusted_domain(mut self, domain: &str) -> Self {
+        self.trusted_domain = Some(domain.to_string());
+        self
+    }
+    /// Returns `verdict` for scores above `score`.
+    ///
+    /// Thresholds are checked in the order they were added, so higher thresholds should be
+    /// added first.
+    pub fn above(mut self, score: f32, verdict: ClassifyResult) -> Self {
+        self.thresholds.push((score, verdict));
+        self
+    }
+    /// Returns the score of the first configured header with a valid value.
+    pub fn score(&self, mail_info: &MailInfo) -> Option<f32> {
+        self.headers.iter().find_map(|(name, format)| {
+            let values = match &self.trusted_domain {
+                Some(domain) => mail_info.get_trusted_headers(name, domain),
+                None => mail_info.header_values(name),
+            };
+            values.into_iter().find_map(|value| format.parse(value))
+        })
+    }
+    /// Checks the score against the thresholds.
+    ///
+    /// For the first exceeded threshold, the decision is logged with the corresponding
+    /// decision method of `mail_info` and its verdict is returned. Returns `None` if no
+    /// threshold is exceeded or no score was found.
+    pub fn check(&self, mail_info: &MailInfo) -> Option<ClassifyResult> {
+        let score = self.score(mail_info)?;
+        let &(threshold, verdict) = self.thresholds.iter().find(|(t, _)| score > *t)?;
+        let reason = format!("upstream spam score {score} above {threshold}");
+        Some(match verdict {
+            ClassifyResult::Accept => mail_info.accept(&reason),
+            ClassifyResult::Quarantine => mail_info.quarantine(&reason),
+            ClassifyResult::Reject => mail_info.reject(&reason),
+            ClassifyResult::Tempfail => mail_info.tempfail(&reason),
+        })
+    }
+}
+
+#[test]
+fn test_score_headers() {
+    use mail_parser::MessageParser;
+
+    assert_eq!(ScoreFormat::Number.parse(" 5.3 "), Some(5.3));
+    assert_eq!(ScoreFormat::Number.parse("-1.5 (ham)"), Some(-1.5));
+    assert_eq!(ScoreFormat::Number.parse("high"), None);
+    let field = ScoreFormat::Field("score".into());
+    assert_eq!(
+        field.parse("Yes, hits=1.0 Score=7.1 required=5.0"),
+        Some(7.1)
+    );
+    assert_eq!(field.parse("No, myscore=3.0"), None);
+    assert_eq!(
+        ScoreFormat::Bracketed.parse("default: False [5.30 / 15.00]; R_SPF_ALLOW(-0.20)"),
+        Some(5.3)
+    );
+
+    let storage = crate::MailInfoStorage::default();
+    let msg = MessageParser::default()
+        .parse(
+            b"X-Spam-Status: Yes, score=9.0\r\n\
+              Received: from a.example.net by mx.example.org; Mon, 1 Jan 2024 00:00:00 +0000\r\n\
+              X-Spam-Score: -5\r\n\
+              X-Spam-Status: No, score=-5.0\r\n\
+              Subject: test\r\n\r\nbody\r\n",
+        )
+        .unwrap();
+    let mail_info = MailInfo::new(&storage, msg);
+    let scores = ScoreHeaders::new()
+        .header("X-Spam-Score", ScoreFormat::Number)
+        .header("X-Spam-Status", ScoreFormat::Field("score".into()))
+        .above(8.0, ClassifyResult::Reject)
+        .above(5.0, ClassifyResult::Quarantine);
+    assert_eq!(scores.score(&mail_info), Some(-5.0));
+    assert_eq!(scores.check(&mail_info), None);
+    let scores = scores.trusted_domain("example.org");
+    assert_eq!(scores.score(&mail_info), Some(9.0));
+    assert_eq!(scores.check(&mail_info), Some(ClassifyResult::Reject));
+    let scores = scores.trusted_domain("example.com");
+    assert_eq!(scores.score(&mail_info), None);
+}
@@ -0,0 +1,176 @@
+//! Writing system (script) statistics of header fields and text.
+//!
+//! [`MailInfo::script_report()`](crate::MailInfo::script_report) counts the letters of the
+//! subject, the `From:` display name and the text body per script. This allows rules like
+//! "subject mixes Latin and Cyrillic letters" (a common homoglyph trick) or "display name
+//! consists of Arabic presentation forms" without maintaining regular expressions per
+//! language.
+//!
+//! Script detection is based on Unicode blocks and covers the major scripts only. Digits,
+//! punctuation, symbols and combining marks are not counted.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let report = mail_info.script_report();
+//! if report.subject.is_mixed() {
+//!     let scripts = report.subject.scripts();
+//!     return mail_info.quarantine(&format!("mixed scripts in subject: {scripts:?}"));
+//! }
+//! ```
+
+use std::cmp::Reverse;
+
+/// A writing system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Armenian,
+    Hebrew,
+    Arabic,
+    Devanagari,
+    Thai,
+    Han,
+    Hiragana,
+    Katakana,
+    Hangul,
+    /// A letter of another script.
+    Other,
+}
+
+impl Script {
+    /// Returns the script of the letter `c`, or `None` if `c` is not a letter.
+    pub fn of(c: char) -> Option<Script> {
+        if !c.is_alphabetic() {
+            return None;
+        }
+        Some(match u32::from(c) {
+            0x0041..=0x024F | 0x1E00..=0x1EFF | 0x2C60..=0x2C7F | 0xA720..=0xA7FF => Script::Latin,
+            0xFF21..=0xFF3A | 0xFF41..=0xFF5A => Script::Latin, // fullwidth
+            0x0370..=0x03FF | 0x1F00..=0x1FFF => Script::Greek,
+            0x0400..=0x052F | 0x1C80..=0x1C8F | 0x2DE0..=0x2DFF | 0xA640..=0xA69F => {
+                Script::Cyrillic
+            }
+            0x0530..=0x058F | 0xFB13..=0xFB17 => Script::Armenian,
+            0x0590..=0x05FF | 0xFB1D..=0xFB4F => Script::Hebrew,
+            0x0600..=0x06FF | 0x0750..=0x077F | 0x08A0..=0x08FF => Script::Arabic,
+            0xFB50..=0xFDFF | 0xFE70..=0xFEFF => Script::Arabic,
+            0x0900..=0x097F => Script::Devanagari,
+            0x0E00..=0x0E7F => Script::Thai,
+            0x2E80..=0x2FDF | 0x3005..=0x3007 | 0x3400..=0x4DBF | 0x4E00..=0x9FFF => Script::Han,
+            0xF900..=0xFAFF | 0x20000..=0x3FFFF => Script::Han,
+            0x3040..=0x309F => Script::Hiragana,
+            0x30A0..=0x30FF | 0x31F0..=0x31FF | 0xFF66..=0xFF9F => Script::Katakana,
+            0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => Script::Hangul,
+            _ => Script::Other,
+        })
+    }
+    // Han, Hiragana and Katakana are used together in Japanese.
+    fn group(self) -> Script {
+        match self {
+            Script::Hiragana | Script::Katakana => Script::Han,
+            s => s,
+        }
+    }
+}
+
+fn is_presentation_form(c: char) -> bool {
+    matches!(u32::from(c), 0xFB50..=0xFDFF | 0xFE70..=0xFEFF)
+}
+
+/// Letter counts per script of a text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScriptStats {
+    counts: Vec<(Script, usize)>,
+    presentation_forms: usize,
+}
+
+impl ScriptStats {
+    /// Counts the letters of `text` per script.
+    pub fn of(text: &str) -> Self {
+        let mut stats = ScriptStats::default();
+        for c in text.chars() {
+            let Some(script) = Script::of(c) else {
+                continue;
+            };
+            match stats.counts.iter_mut().find(|(s, _)| *s == script) {
+                Some((_, n)) => *n += 1,
+                None => stats.counts.push((script, 1)),
+            }
+            if is_presentation_form(c) {
+                stats.presentation_forms += 1;
+            }
+        }
+        stats.counts.sort_by_key(|&(_, n)| Reverse(n));
+        stats
+    }
+    /// Returns the scripts found, the most frequent first.
+    pub fn scripts(&self) -> Vec<Script> {
+        self.counts.iter().map(|&(s, _)| s).collect()
+    }
+    /// Returns the number of letters of `script`.
+    pub fn count(&self, script: Script) -> usize {
+        self.counts
+            .iter()
+            .find(|(s, _)| *s == script)
+            .map_or(0, |&(_, n)| n)
+    }
+    /// Returns the total number of letters.
+    pub fn letters(&self) -> usize {
+        self.counts.iter().map(|&(_, n)| n).sum()
+    }
+    /// Returns the most frequent script, or `None` if there are no letters.
+    pub fn dominant(&self) -> Option<Script> {
+        self.counts.first().map(|&(s, _)| s)
+    }
+    /// Returns `true` if letters of more than one script are used.
+    ///
+    /// Han, Hiragana and Katakana count as one script, as they are used together in
+    /// Japanese.
+    pub fn is_mixed(&self) -> bool {
+        let mut scripts = self.counts.iter().map(|&(s, _)| s.group());
+        scripts
+            .next()
+            .is_some_and(|first| scripts.any(|s| s != first))
+    }
+    /// Returns the number of Arabic presentation forms (U+FB50..U+FDFF, U+FE70..U+FEFF).
+    ///
+    /// Regular text uses the base Arabic letters; text consisting of presentation forms
+    /// was usually produced to evade filters.
+    pub fn presentation_forms(&self) -> usize {
+        self.presentation_forms
+    }
+}
+
+/// Script statistics of the fields of a message, see
+/// [`MailInfo::script_report()`](crate::MailInfo::script_report).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScriptReport {
+    /// Statistics of the `Subject:`.
+    pub subject: ScriptStats,
+    /// Statistics of the display name of the `From:` header.
+    pub from_name: ScriptStats,
+    /// Statistics of the text body, see [`MailInfo::get_text()`](crate::MailInfo::get_text).
+    pub body: ScriptStats,
+}
+
+#[test]
+fn test_script_stats() {
+    let stats = ScriptStats::of("Оturum açma bilgileri"); // Cyrillic O
+    assert!(stats.is_mixed());
+    assert_eq!(stats.scripts(), [Script::Latin, Script::Cyrillic]);
+    assert_eq!(stats.count(Script::Cyrillic), 1);
+    assert_eq!(stats.letters(), 19);
+
+    let stats = ScriptStats::of("ﺖﻓﺎﺼﻴﻟ ﺖﺴﺠﻴﻟ ﺎﻟﺪﺧﻮﻟ");
+    assert!(!stats.is_mixed());
+    assert_eq!(stats.dominant(), Some(Script::Arabic));
+    assert_eq!(stats.presentation_forms(), stats.letters());
+    assert_eq!(ScriptStats::of("تفاصيل").presentation_forms(), 0);
+
+    assert!(!ScriptStats::of("ログイン情報です").is_mixed());
+    assert!(!ScriptStats::of("Login 123 - !").is_mixed());
+    assert_eq!(ScriptStats::of("123").dominant(), None);
+}
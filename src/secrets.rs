@@ -0,0 +1,71 @@
+//! Resolution of secret configuration values.
+//!
+//! API keys and passwords needed by a classifier (e.g. a DNSBL query key or database
+//! credentials) should not be compiled into the milter or stored in its main
+//! configuration. [`resolve()`] takes a reference to the secret and returns its value:
+//!
+//! - `file:/path/to/secret` reads the file,
+//! - `env:NAME` reads the environment variable `NAME`,
+//! - `credential:NAME` reads the systemd credential `NAME` (see `LoadCredential=` and
+//!   `LoadCredentialEncrypted=` in systemd.exec(5)) from `$CREDENTIALS_DIRECTORY`,
+//! - any other value is returned unchanged.
+//!
+//! A single trailing newline is removed from values read from files.
+//!
+//! # Example
+//!
+//! ```ignore
+//! // srmilter.service: LoadCredentialEncrypted=dqs-key:/etc/srmilter/dqs-key.cred
+//! let dqs_key = secrets::resolve("credential:dqs-key")?;
+//! ```
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+fn read_file(path: &Path) -> Result<String, Box<dyn Error>> {
+    let mut value = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    Ok(value)
+}
+
+/// Returns the value of the secret referenced by `reference`, see the [module
+/// documentation](self) for the supported forms.
+pub fn resolve(reference: &str) -> Result<String, Box<dyn Error>> {
+    if let Some(path) = reference.strip_prefix("file:") {
+        read_file(Path::new(path))
+    } else if let Some(name) = reference.strip_prefix("env:") {
+        env::var(name).map_err(|e| format!("environment variable {name}: {e}").into())
+    } else if let Some(name) = reference.strip_prefix("credential:") {
+        if name.is_empty() || name.contains('/') {
+            return Err(format!("invalid credential name {name:?}").into());
+        }
+        let dir = env::var_os("CREDENTIALS_DIRECTORY")
+            .ok_or_else(|| format!("credential {name}: CREDENTIALS_DIRECTORY not set"))?;
+        read_file(&Path::new(&dir).join(name))
+    } else {
+        Ok(reference.to_string())
+    }
+}
+
+#[test]
+fn test_resolve() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("key");
+    fs::write(&path, "s3cret\n").unwrap();
+    assert_eq!(
+        resolve(&format!("file:{}", path.display())).unwrap(),
+        "s3cret"
+    );
+    assert!(resolve("file:/nonexistent/key").is_err());
+    assert_eq!(resolve("plain").unwrap(), "plain");
+    assert!(resolve("env:SRMILTER_TEST_UNSET_VARIABLE").is_err());
+    assert_eq!(resolve("env:PATH").unwrap(), env::var("PATH").unwrap());
+    assert!(resolve("credential:../key").is_err());
+}
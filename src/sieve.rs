@@ -0,0 +1,733 @@
+//! Interpreter for a safe subset of Sieve (RFC 5228) for per-user rules.
+//!
+//! Scripts are loaded from a directory configured with
+//! [`ConfigBuilder::sieve_dir()`](crate::ConfigBuilder::sieve_dir). The script for a
+//! recipient is the file `<recipient>.sieve` in that directory, with the recipient address
+//! [normalized](crate::addresses) (e.g. `user+tag@Example.com` uses `user@example.com.sieve`).
+//! It is evaluated after the classifier has accepted a message with exactly one envelope
+//! recipient.
+//!
+//! # Supported language
+//!
+//! - Control: `require`, `if`/`elsif`/`else`, `stop`
+//! - Actions: `keep`, `reject "reason"` (rejects the message), `fileinto "folder"`
+//!   (quarantines the message)
+//! - Tests: `header`, `address`, `envelope`, `exists`, `size`, `allof`, `anyof`, `not`,
+//!   `true`, `false`
+//! - Match types `:is`, `:contains` and `:matches`, address parts `:all`, `:localpart` and
+//!   `:domain`, comparators `i;ascii-casemap` (default) and `i;octet`
+//!
+//! Everything else (e.g. `discard`, `redirect`, `vacation`, variables) is rejected when the
+//! script is parsed, as are blocks and tests nested deeper than 64 levels.
+//!
+//! # Example
+//!
+//! ```text
+//! require ["fileinto", "reject"];
+//! if header :contains "subject" ["lottery", "winner"] {
+//!     fileinto "Junk";
+//! } elsif address :domain "from" "spam.example" {
+//!     reject "not wanted";
+//! }
+//! ```
+
+use crate::addresses::normalize;
+use crate::{ClassifyResult, MailInfo};
+use mail_parser::HeaderValue;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Tag(String),
+    Str(String),
+    Num(usize),
+    Punct(char),
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' => {
+                chars.next();
+                if chars.next() != Some('*') {
+                    return Err("unexpected '/'".into());
+                }
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => last = c,
+                        None => return Err("unterminated comment".into()),
+                    }
+                }
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => s.push(c),
+                            None => return Err("unterminated string".into()),
+                        },
+                        Some(c) => s.push(c),
+                        None => return Err("unterminated string".into()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            ':' => {
+                chars.next();
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' {
+                        s.push(c.to_ascii_lowercase());
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if s.is_empty() {
+                    return Err("empty tag".into());
+                }
+                tokens.push(Token::Tag(s));
+            }
+            '0'..='9' => {
+                let mut n: usize = 0;
+                while let Some(&c) = chars.peek() {
+                    if let Some(d) = c.to_digit(10) {
+                        n = n
+                            .checked_mul(10)
+                            .and_then(|n| n.checked_add(d as usize))
+                            .ok_or("number too large")?;
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let factor = match chars.peek() {
+                    Some('K' | 'k') => 1 << 10,
+                    Some('M' | 'm') => 1 << 20,
+                    Some('G' | 'g') => 1 << 30,
+                    _ => 1,
+                };
+                if factor > 1 {
+                    chars.next();
+                }
+                tokens.push(Token::Num(n.checked_mul(factor).ok_or("number too large")?));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' {
+                        s.push(c.to_ascii_lowercase());
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Ident(s));
+            }
+            '[' | ']' | '(' | ')' | '{' | '}' | ',' | ';' => {
+                chars.next();
+                tokens.push(Token::Punct(c));
+            }
+            _ => return Err(format!("unexpected character '{c}'")),
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MatchType {
+    Is,
+    Contains,
+    Matches,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AddressPart {
+    All,
+    LocalPart,
+    Domain,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Comparison {
+    match_type: MatchType,
+    address_part: AddressPart,
+    case_sensitive: bool,
+}
+
+#[derive(Debug)]
+enum Test {
+    True,
+    False,
+    Not(Box<Test>),
+    AnyOf(Vec<Test>),
+    AllOf(Vec<Test>),
+    Exists(Vec<String>),
+    Header(Comparison, Vec<String>, Vec<String>),
+    Address(Comparison, Vec<String>, Vec<String>),
+    Envelope(Comparison, Vec<String>, Vec<String>),
+    SizeOver(usize),
+    SizeUnder(usize),
+}
+
+#[derive(Debug)]
+enum Command {
+    If(Vec<(Test, Vec<Command>)>, Vec<Command>),
+    Keep,
+    Stop,
+    Reject(String),
+    FileInto(String),
+}
+
+const EXTENSIONS: [&str; 3] = ["fileinto", "reject", "envelope"];
+
+// Maximum nesting of blocks and tests. Parsing and evaluation recurse, so deeper scripts
+// would overflow the stack.
+const MAX_NESTING: usize = 64;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.depth >= MAX_NESTING {
+            return Err(format!("nested deeper than {MAX_NESTING} levels"));
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("unexpected end of script")?;
+        self.pos += 1;
+        Ok(token)
+    }
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.next()? {
+            Token::Punct(p) if p == c => Ok(()),
+            t => Err(format!("expected '{c}', found {t:?}")),
+        }
+    }
+    fn string(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Str(s) => Ok(s),
+            t => Err(format!("expected string, found {t:?}")),
+        }
+    }
+    fn string_list(&mut self) -> Result<Vec<String>, String> {
+        if self.peek() != Some(&Token::Punct('[')) {
+            return Ok(vec![self.string()?]);
+        }
+        self.next()?;
+        let mut list = vec![self.string()?];
+        loop {
+            match self.next()? {
+                Token::Punct(']') => return Ok(list),
+                Token::Punct(',') => list.push(self.string()?),
+                t => return Err(format!("expected ',' or ']', found {t:?}")),
+            }
+        }
+    }
+    fn commands(&mut self) -> Result<Vec<Command>, String> {
+        let mut commands = Vec::new();
+        while let Some(token) = self.peek() {
+            if token == &Token::Punct('}') {
+                break;
+            }
+            if let Some(command) = self.command()? {
+                commands.push(command);
+            }
+        }
+        Ok(commands)
+    }
+    fn block(&mut self) -> Result<Vec<Command>, String> {
+        self.expect('{')?;
+        let commands = self.nested(Self::commands)?;
+        self.expect('}')?;
+        Ok(commands)
+    }
+    fn command(&mut self) -> Result<Option<Command>, String> {
+        let Token::Ident(name) = self.next()? else {
+            return Err(format!("expected command at token {}", self.pos));
+        };
+        let command = match name.as_str() {
+            "require" => {
+                for extension in self.string_list()? {
+                    if !EXTENSIONS.contains(&extension.as_str()) {
+                        return Err(format!("unsupported extension \"{extension}\""));
+                    }
+                }
+                self.expect(';')?;
+                return Ok(None);
+            }
+            "if" => {
+                let mut branches = vec![(self.test()?, self.block()?)];
+                let mut otherwise = Vec::new();
+                loop {
+                    match self.peek() {
+                        Some(Token::Ident(s)) if s == "elsif" => {
+                            self.next()?;
+                            branches.push((self.test()?, self.block()?));
+                        }
+                        Some(Token::Ident(s)) if s == "else" => {
+                            self.next()?;
+                            otherwise = self.block()?;
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+                return Ok(Some(Command::If(branches, otherwise)));
+            }
+            "keep" => Command::Keep,
+            "stop" => Command::Stop,
+            "reject" => Command::Reject(self.string()?),
+            "fileinto" => Command::FileInto(self.string()?),
+            _ => return Err(format!("unsupported command \"{name}\"")),
+        };
+        self.expect(';')?;
+        Ok(Some(command))
+    }
+    fn comparison(&mut self) -> Result<Comparison, String> {
+        let mut comparison = Comparison {
+            match_type: MatchType::Is,
+            address_part: AddressPart::All,
+            case_sensitive: false,
+        };
+        while let Some(Token::Tag(tag)) = self.peek() {
+            let tag = tag.clone();
+            self.next()?;
+            match tag.as_str() {
+                "is" => comparison.match_type = MatchType::Is,
+                "contains" => comparison.match_type = MatchType::Contains,
+                "matches" => comparison.match_type = MatchType::Matches,
+                "all" => comparison.address_part = AddressPart::All,
+                "localpart" => comparison.address_part = AddressPart::LocalPart,
+                "domain" => comparison.address_part = AddressPart::Domain,
+                "comparator" => match self.string()?.as_str() {
+                    "i;ascii-casemap" => comparison.case_sensitive = false,
+                    "i;octet" => comparison.case_sensitive = true,
+                    c => return Err(format!("unsupported comparator \"{c}\"")),
+                },
+                _ => return Err(format!("unsupported tag \":{tag}\"")),
+            }
+        }
+        Ok(comparison)
+    }
+    fn test_list(&mut self) -> Result<Vec<Test>, String> {
+        self.expect('(')?;
+        let mut tests = vec![self.test()?];
+        loop {
+            match self.next()? {
+                Token::Punct(')') => return Ok(tests),
+                Token::Punct(',') => tests.push(self.test()?),
+                t => return Err(format!("expected ',' or ')', found {t:?}")),
+            }
+        }
+    }
+    fn test(&mut self) -> Result<Test, String> {
+        let Token::Ident(name) = self.next()? else {
+            return Err(format!("expected test at token {}", self.pos));
+        };
+        Ok(match name.as_str() {
+            "true" => Test::True,
+            "false" => Test::False,
+            "not" => Test::Not(Box::new(self.nested(Self::test)?)),
+            "anyof" => Test::AnyOf(self.nested(Self::test_list)?),
+            "allof" => Test::AllOf(self.nested(Self::test_list)?),
+            "exists" => Test::Exists(self.string_list()?),
+            "header" => {
+                let comparison = self.comparison()?;
+                Test::Header(comparison, self.string_list()?, self.string_list()?)
+            }
+            "address" => {
+                let comparison = self.comparison()?;
+                Test::Address(comparison, self.string_list()?, self.string_list()?)
+            }
+            "envelope" => {
+                let comparison = self.comparison()?;
+                Test::Envelope(comparison, self.string_list()?, self.string_list()?)
+            }
+            "size" => {
+                let over = match self.next()? {
+                    Token::Tag(t) if t == "over" => true,
+                    Token::Tag(t) if t == "under" => false,
+                    t => return Err(format!("expected :over or :under, found {t:?}")),
+                };
+                let Token::Num(limit) = self.next()? else {
+                    return Err("expected number".into());
+                };
+                if over {
+                    Test::SizeOver(limit)
+                } else {
+                    Test::SizeUnder(limit)
+                }
+            }
+            _ => return Err(format!("unsupported test \"{name}\"")),
+        })
+    }
+}
+
+// Glob match with `*` (any sequence) and `?` (any single character).
+fn glob_match(pattern: &[char], value: &[char]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if let Some((bp, bv)) = backtrack {
+            p = bp + 1;
+            v = bv + 1;
+            backtrack = Some((bp, bv + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn compare(comparison: &Comparison, value: &str, key: &str) -> bool {
+    let (value, key) = if comparison.case_sensitive {
+        (value.to_string(), key.to_string())
+    } else {
+        (value.to_lowercase(), key.to_lowercase())
+    };
+    match comparison.match_type {
+        MatchType::Is => value == key,
+        MatchType::Contains => value.contains(&key),
+        MatchType::Matches => {
+            let pattern: Vec<char> = key.chars().collect();
+            let value: Vec<char> = value.chars().collect();
+            glob_match(&pattern, &value)
+        }
+    }
+}
+
+fn address_part(part: AddressPart, address: &str) -> &str {
+    match (part, address.rsplit_once('@')) {
+        (AddressPart::All, _) => address,
+        (AddressPart::LocalPart, Some((local, _))) => local,
+        (AddressPart::LocalPart, None) => address,
+        (AddressPart::Domain, Some((_, domain))) => domain,
+        (AddressPart::Domain, None) => "",
+    }
+}
+
+fn header_values(mail_info: &MailInfo, name: &str) -> Vec<String> {
+    let msg = mail_info.get_message();
+    let raw = msg.raw_message();
+    msg.headers()
+        .iter()
+        .filter(|h| h.name.as_str().eq_ignore_ascii_case(name))
+        .map(|h| match &h.value {
+            HeaderValue::Text(text) => text.to_string(),
+            HeaderValue::TextList(list) => list.join(", "),
+            _ => String::from_utf8_lossy(&raw[h.offset_start as usize..h.offset_end as usize])
+                .trim()
+                .to_string(),
+        })
+        .collect()
+}
+
+fn header_addresses(mail_info: &MailInfo, name: &str) -> Vec<String> {
+    mail_info
+        .get_message()
+        .headers()
+        .iter()
+        .filter(|h| h.name.as_str().eq_ignore_ascii_case(name))
+        .filter_map(|h| h.value.as_address())
+        .flat_map(|a| a.iter().filter_map(|a| a.address()).map(str::to_string))
+        .collect()
+}
+
+enum Outcome {
+    Continue,
+    Stop,
+}
+
+struct Evaluation<'a, 'b> {
+    mail_info: &'a MailInfo<'b>,
+    recipient: &'a str,
+    action: Option<SieveAction>,
+}
+
+impl Evaluation<'_, '_> {
+    fn test(&self, test: &Test) -> bool {
+        match test {
+            Test::True => true,
+            Test::False => false,
+            Test::Not(t) => !self.test(t),
+            Test::AnyOf(tests) => tests.iter().any(|t| self.test(t)),
+            Test::AllOf(tests) => tests.iter().all(|t| self.test(t)),
+            Test::Exists(names) => names
+                .iter()
+                .all(|n| !header_values(self.mail_info, n).is_empty()),
+            Test::Header(comparison, names, keys) => names.iter().any(|n| {
+                header_values(self.mail_info, n)
+                    .iter()
+                    .any(|v| keys.iter().any(|k| compare(comparison, v, k)))
+            }),
+            Test::Address(comparison, names, keys) => names.iter().any(|n| {
+                header_addresses(self.mail_info, n).iter().any(|a| {
+                    let part = address_part(comparison.address_part, a);
+                    keys.iter().any(|k| compare(comparison, part, k))
+                })
+            }),
+            Test::Envelope(comparison, names, keys) => names.iter().any(|n| {
+                let address = match n.to_ascii_lowercase().as_str() {
+                    "from" => self.mail_info.get_sender(),
+                    "to" => self.recipient,
+                    _ => return false,
+                };
+                let part = address_part(comparison.address_part, address);
+                keys.iter().any(|k| compare(comparison, part, k))
+            }),
+            Test::SizeOver(limit) => self.mail_info.storage.mail_buffer.len() > *limit,
+            Test::SizeUnder(limit) => self.mail_info.storage.mail_buffer.len() < *limit,
+        }
+    }
+    fn run(&mut self, commands: &[Command]) -> Outcome {
+        for command in commands {
+            match command {
+                Command::If(branches, otherwise) => {
+                    let block = branches
+                        .iter()
+                        .find(|(test, _)| self.test(test))
+                        .map(|(_, block)| block)
+                        .unwrap_or(otherwise);
+                    if let Outcome::Stop = self.run(block) {
+                        return Outcome::Stop;
+                    }
+                }
+                Command::Keep => (),
+                Command::Stop => return Outcome::Stop,
+                Command::Reject(reason) => {
+                    // reject cancels the implicit keep and any fileinto
+                    self.action = Some(SieveAction::Reject(reason.clone()));
+                }
+                Command::FileInto(folder) => {
+                    if !matches!(self.action, Some(SieveAction::Reject(_))) {
+                        self.action = Some(SieveAction::FileInto(folder.clone()));
+                    }
+                }
+            }
+        }
+        Outcome::Continue
+    }
+}
+
+/// Action resulting from the evaluation of a [`SieveScript`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SieveAction {
+    /// `reject "reason"`
+    Reject(String),
+    /// `fileinto "folder"`
+    FileInto(String),
+}
+
+/// A parsed Sieve script.
+#[derive(Debug)]
+pub struct SieveScript {
+    commands: Vec<Command>,
+}
+
+impl SieveScript {
+    /// Parses a script from source text.
+    pub fn parse(src: &str) -> Result<SieveScript, Box<dyn Error>> {
+        let mut parser = Parser {
+            tokens: tokenize(src)?,
+            pos: 0,
+            depth: 0,
+        };
+        let commands = parser.commands()?;
+        if parser.peek().is_some() {
+            return Err("unexpected '}'".into());
+        }
+        Ok(SieveScript { commands })
+    }
+    /// Reads and parses a script file.
+    pub fn load(filename: &Path) -> Result<SieveScript, Box<dyn Error>> {
+        let src = fs::read_to_string(filename)?;
+        SieveScript::parse(&src).map_err(|e| format!("{}: {e}", filename.display()).into())
+    }
+    /// Evaluates the script for the message delivered to `recipient`.
+    ///
+    /// Returns `None` if the message is kept (explicitly or implicitly).
+    pub fn evaluate(&self, mail_info: &MailInfo, recipient: &str) -> Option<SieveAction> {
+        let mut evaluation = Evaluation {
+            mail_info,
+            recipient,
+            action: None,
+        };
+        evaluation.run(&self.commands);
+        evaluation.action
+    }
+}
+
+/// Evaluates the user script of the only recipient, if any.
+///
+/// Returns `None` if no script applies or the script keeps the message.
+pub(crate) fn classify_user(dir: &Path, mail_info: &MailInfo) -> Option<ClassifyResult> {
+    let recipient = mail_info.get_only_recipient();
+    let user = normalize(recipient);
+    if user.is_empty() || user.starts_with('.') || user.contains('/') {
+        return None;
+    }
+    let filename = dir.join(format!("{user}.sieve"));
+    let src = match fs::read_to_string(&filename) {
+        Ok(src) => src,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => {
+            mail_info.log(&format!("sieve: {}: {e}", filename.display()));
+            return None;
+        }
+    };
+    let script = match SieveScript::parse(&src) {
+        Ok(script) => script,
+        Err(e) => {
+            mail_info.log(&format!("sieve: {}: {e}", filename.display()));
+            return None;
+        }
+    };
+    match script.evaluate(mail_info, recipient)? {
+        SieveAction::Reject(reason) => Some(mail_info.reject(&format!("sieve: {reason}"))),
+        SieveAction::FileInto(folder) => Some(mail_info.quarantine(&format!("sieve: {folder}"))),
+    }
+}
+
+#[test]
+fn test_sieve() {
+    use crate::MailInfoStorage;
+    use mail_parser::MessageParser;
+
+    let storage = MailInfoStorage {
+        mail_buffer: std::fs::read("tests/parse_001.eml").unwrap(),
+        sender: "donald.buczek@gmail.com".to_string(),
+        recipients: vec!["emil.erpel@entenhausen.org".to_string()],
+        ..Default::default()
+    };
+    let mail_info = MailInfo::new(
+        &storage,
+        MessageParser::default()
+            .parse(&storage.mail_buffer)
+            .unwrap(),
+    );
+    let eval = |src: &str| {
+        SieveScript::parse(src)
+            .unwrap()
+            .evaluate(&mail_info, "emil.erpel@entenhausen.org")
+    };
+
+    assert_eq!(eval(""), None);
+    assert_eq!(eval("keep;"), None);
+    assert_eq!(
+        eval("require \"reject\"; reject \"no\";"),
+        Some(SieveAction::Reject("no".into()))
+    );
+    assert_eq!(
+        eval(
+            r#"require ["fileinto"];
+            # comment
+            if header :contains "Subject" "LANGEN header" { fileinto "Junk"; }"#
+        ),
+        Some(SieveAction::FileInto("Junk".into()))
+    );
+    assert_eq!(
+        eval(r#"if header :comparator "i;octet" :contains "subject" "LANGEN" { reject "x"; }"#),
+        None
+    );
+    assert_eq!(
+        eval(
+            r#"if address :domain :is "from" "gmail.com" { reject "gmail"; } else { fileinto "x"; }"#
+        ),
+        Some(SieveAction::Reject("gmail".into()))
+    );
+    assert_eq!(
+        eval(
+            r#"if address :localpart :matches "to" "emil.*" { fileinto "Emil"; stop; } reject "x";"#
+        ),
+        Some(SieveAction::FileInto("Emil".into()))
+    );
+    assert_eq!(
+        eval(
+            r#"if allof (envelope :is "to" "emil.erpel@entenhausen.org",
+                         not exists "X-Spam-Score", size :under 100K) { reject "all"; }"#
+        ),
+        Some(SieveAction::Reject("all".into()))
+    );
+    assert_eq!(
+        eval(r#"if anyof (false, size :over 1M) { reject "x"; } elsif true { fileinto "y"; }"#),
+        Some(SieveAction::FileInto("y".into()))
+    );
+
+    assert!(SieveScript::parse("discard;").is_err());
+    assert!(SieveScript::parse("require \"vacation\";").is_err());
+    assert!(SieveScript::parse("if true { keep; ").is_err());
+    assert!(SieveScript::parse("keep; }").is_err());
+    assert!(SieveScript::parse("reject \"x\"").is_err());
+
+    let nested = |n: usize| {
+        format!(
+            "if {}true{} {{ keep; }}",
+            "anyof (not ".repeat(n),
+            ")".repeat(n)
+        )
+    };
+    assert!(SieveScript::parse(&nested(30)).is_ok());
+    assert!(SieveScript::parse(&nested(100_000)).is_err());
+    let blocks = |n: usize| format!("{}keep;{}", "if true {".repeat(n), "}".repeat(n));
+    assert!(SieveScript::parse(&blocks(64)).is_ok());
+    assert!(SieveScript::parse(&blocks(100_000)).is_err());
+}
+
+#[test]
+fn test_glob_match() {
+    let m = |p: &str, v: &str| {
+        glob_match(
+            &p.chars().collect::<Vec<_>>(),
+            &v.chars().collect::<Vec<_>>(),
+        )
+    };
+    assert!(m("*", ""));
+    assert!(m("a*c", "abbbc"));
+    assert!(m("a?c", "abc"));
+    assert!(!m("a?c", "ac"));
+    assert!(m("*b*", "abc"));
+    assert!(!m("*d", "abc"));
+}
@@ -0,0 +1,225 @@
+//! `simulate` subcommand: classifies messages from files with the concurrency model of the
+//! daemon and reports throughput and latency.
+
+use crate::cli::SimulateArgs;
+use crate::{Config, MailInfoStorage, classify_mail};
+use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
+use nix::unistd::{ForkResult, Pid, fork};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Reads the messages from a directory (one message per file) or an mbox file.
+pub(crate) fn read_messages(path: &Path) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    if path.is_dir() {
+        let mut files: Vec<PathBuf> = fs::read_dir(path)
+            .map_err(|e| format!("{}: {e}", path.display()))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        files.sort();
+        files
+            .iter()
+            .map(|f| fs::read(f).map_err(|e| format!("{}: {e}", f.display()).into()))
+            .collect()
+    } else {
+        let data = fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(split_mbox(&data))
+    }
+}
+
+/// Splits mbox data into messages, dropping the `From ` separator lines and unquoting
+/// `>From ` lines.
+fn split_mbox(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    let mut prev_blank = true;
+    for line in data.split_inclusive(|&c| c == b'\n') {
+        if prev_blank && line.starts_with(b"From ") {
+            messages.extend(current.take());
+            current = Some(Vec::new());
+            prev_blank = false;
+            continue;
+        }
+        prev_blank = line == b"\n" || line == b"\r\n";
+        if let Some(ref mut message) = current {
+            let quoted = line.iter().take_while(|&&c| c == b'>').count();
+            if quoted > 0 && line[quoted..].starts_with(b"From ") {
+                message.extend_from_slice(&line[1..]);
+            } else {
+                message.extend_from_slice(line);
+            }
+        }
+    }
+    messages.extend(current);
+    messages
+}
+
+fn classify_one(config: &Config, i: usize, message: &[u8]) {
+    let storage = MailInfoStorage {
+        mail_buffer: message.to_vec(),
+        id: format!("sim{i}"),
+        ..Default::default()
+    };
+    classify_mail(config, &storage);
+}
+
+// Returns the time message `i` is due at `rate` messages per second.
+fn due(start: Instant, i: usize, rate: Option<f64>) -> Instant {
+    match rate {
+        Some(rate) => start + Duration::from_secs_f64(i as f64 / rate),
+        None => start,
+    }
+}
+
+// Sleeps until message `i` is due.
+pub(crate) fn pace(start: Instant, i: usize, rate: Option<f64>) {
+    let due = due(start, i, rate);
+    let now = Instant::now();
+    if due > now {
+        thread::sleep(due - now);
+    }
+}
+
+fn percentile(sorted: &[Duration], q: f64) -> Duration {
+    let idx = ((sorted.len() as f64 * q).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[idx]
+}
+
+pub(crate) fn report(mut latencies: Vec<Duration>, elapsed: Duration) {
+    latencies.sort();
+    let n = latencies.len();
+    println!(
+        "{n} messages in {:.2}s, {:.1} messages/s",
+        elapsed.as_secs_f64(),
+        n as f64 / elapsed.as_secs_f64()
+    );
+    if n > 0 {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        println!(
+            "latency p50={:.1}ms p90={:.1}ms p99={:.1}ms max={:.1}ms",
+            ms(percentile(&latencies, 0.5)),
+            ms(percentile(&latencies, 0.9)),
+            ms(percentile(&latencies, 0.99)),
+            ms(latencies[n - 1]),
+        );
+    }
+}
+
+// Reaps terminated children without blocking and records their latency.
+fn reap_children(
+    running: &mut HashMap<Pid, Instant>,
+    latencies: &mut Vec<Duration>,
+) -> Result<(), Box<dyn Error>> {
+    while !running.is_empty() {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG))? {
+            WaitStatus::StillAlive => break,
+            status => {
+                if let Some(pid) = status.pid()
+                    && let Some(start) = running.remove(&pid)
+                {
+                    latencies.push(start.elapsed());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn simulate(config: &Config, args: &SimulateArgs) -> Result<(), Box<dyn Error>> {
+    let messages = read_messages(&args.input)?;
+    let start = Instant::now();
+    let mut latencies = Vec::with_capacity(messages.len());
+
+    if args.fork_max > 0 {
+        let mut running: HashMap<Pid, Instant> = HashMap::new();
+        for (i, message) in messages.iter().enumerate() {
+            // poll, so that latencies are not inflated by waiting for the next message
+            let due = due(start, i, args.rate);
+            loop {
+                reap_children(&mut running, &mut latencies)?;
+                if running.len() < args.fork_max as usize && Instant::now() >= due {
+                    break;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+            let forked = Instant::now();
+            match unsafe { fork() }? {
+                ForkResult::Parent { child } => {
+                    running.insert(child, forked);
+                }
+                ForkResult::Child => {
+                    classify_one(config, i, message);
+                    exit(0)
+                }
+            }
+        }
+        while !running.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+            reap_children(&mut running, &mut latencies)?;
+        }
+    } else if args.threads_max > 0 {
+        let state = Arc::new((Mutex::new(0u16), Condvar::new()));
+        let shared_latencies = Arc::new(Mutex::new(Vec::with_capacity(messages.len())));
+        for (i, message) in messages.into_iter().enumerate() {
+            {
+                let (lock, cvar) = state.as_ref();
+                let mut count = lock.lock().unwrap();
+                while *count >= args.threads_max {
+                    count = cvar.wait(count).unwrap();
+                }
+                *count += 1;
+            }
+            pace(start, i, args.rate);
+            let state_clone = state.clone();
+            let thread_latencies = shared_latencies.clone();
+            let thread_config = config.clone();
+            let spawned = Instant::now();
+            thread::spawn(move || {
+                classify_one(&thread_config, i, &message);
+                thread_latencies.lock().unwrap().push(spawned.elapsed());
+                let (lock, cvar) = &*state_clone;
+                *lock.lock().unwrap() -= 1;
+                cvar.notify_one();
+            });
+        }
+        let (lock, cvar) = state.as_ref();
+        let mut count = lock.lock().unwrap();
+        while *count > 0 {
+            count = cvar.wait(count).unwrap();
+        }
+        latencies = std::mem::take(&mut *shared_latencies.lock().unwrap());
+    } else {
+        for (i, message) in messages.iter().enumerate() {
+            pace(start, i, args.rate);
+            let t = Instant::now();
+            classify_one(config, i, message);
+            latencies.push(t.elapsed());
+        }
+    }
+
+    report(latencies, start.elapsed());
+    Ok(())
+}
+
+#[test]
+fn test_split_mbox() {
+    let mbox = b"From a@example.com Mon Oct  6 10:00:00 2025\n\
+        Subject: one\n\n>From here\n\n\
+        From b@example.com Mon Oct  6 10:00:01 2025\n\
+        Subject: two\n\nText\nFrom is not a separator here\n";
+    let messages = split_mbox(mbox);
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0], b"Subject: one\n\nFrom here\n\n");
+    assert_eq!(
+        messages[1],
+        b"Subject: two\n\nText\nFrom is not a separator here\n"
+    );
+}
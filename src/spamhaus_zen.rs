@@ -175,6 +175,11 @@ pub fn ip_in_spamhaus_zen<Iter: Iterator<Item = IpAddr>>(
     ret
 }
 
+/// Returns the number of IPs in `ips` which are listed in Spamhaus ZEN (for any reason).
+pub(crate) fn listed_count<Iter: Iterator<Item = IpAddr>>(ips: Iter) -> usize {
+    ips.filter(|&ip| !lookup_ip(ip).is_empty()).count()
+}
+
 #[test]
 fn test_format() {
     assert_eq!(
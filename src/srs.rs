@@ -0,0 +1,135 @@
+//! Sender Rewriting Scheme (SRS) for forwarding setups.
+//!
+//! When mail is forwarded, the envelope sender is rewritten to an address in the
+//! forwarder's domain, so that SPF checks at the next hop pass:
+//! `user@example.com` becomes `SRS0=HHHH=TT=example.com=user@forwarder.example`. `HHHH` is
+//! an HMAC-SHA1 over timestamp and original address, `TT` the day the address was created.
+//! Bounces to the rewritten address are sent back to the forwarder, which uses [`decode()`]
+//! to verify the address and recover the original sender.
+//!
+//! The format is compatible with libsrs2 (used by postsrsd). Addresses which are already
+//! SRS-rewritten are not rewritten again.
+//!
+//! [`MailInfo::srs_forward()`](crate::MailInfo::srs_forward) rewrites the envelope sender
+//! of a message the classifier forwards with the change-from milter action.
+//! [`MailInfo::srs_decode()`](crate::MailInfo::srs_decode) recovers the original sender
+//! of mail forwarded by others, without verification.
+//!
+//! # Example
+//!
+//! ```ignore
+//! if ctx.forwarded_recipients.contains(mail_info.get_only_recipient()) {
+//!     mail_info.srs_forward(&ctx.srs_secret, "fwd.example.org");
+//! }
+//! if mail_info.is_bounce()
+//!     && let Some(original) =
+//!         srs::decode(mail_info.get_only_recipient(), &ctx.srs_secret, batv::today(), 21)
+//! {
+//!     mail_info.log(&format!("bounce for forwarded mail from {original}"));
+//! }
+//! ```
+
+use hmac::{Hmac, Mac as _};
+use sha1::Sha1;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn timestamp(today: u32) -> String {
+    let t = today % 1024;
+    let chars = [BASE32[(t >> 5) as usize], BASE32[(t & 31) as usize]];
+    String::from_utf8_lossy(&chars).into_owned()
+}
+
+fn timestamp_value(tt: &str) -> Option<u32> {
+    let mut value = 0;
+    for c in tt.bytes() {
+        let digit = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())?;
+        value = (value << 5) | digit as u32;
+    }
+    Some(value)
+}
+
+// First four characters of the base64 encoded HMAC-SHA1 over the lowercased parts.
+fn hash(secret: &[u8], parts: &[&str]) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part.to_ascii_lowercase().as_bytes());
+    }
+    let d = mac.finalize().into_bytes();
+    let n = (u32::from(d[0]) << 16) | (u32::from(d[1]) << 8) | u32::from(d[2]);
+    (0..4)
+        .map(|i| BASE64[((n >> (18 - 6 * i)) & 63) as usize] as char)
+        .collect()
+}
+
+/// Rewrites `address` to an SRS0 address in `domain`.
+///
+/// `today` is the day number, see [`batv::today()`](crate::batv::today). Addresses which
+/// are already SRS-rewritten or have no domain are returned unchanged.
+pub fn encode(address: &str, secret: &[u8], domain: &str, today: u32) -> String {
+    if parse(address).is_some() {
+        return address.to_string();
+    }
+    let Some((local, orig_domain)) = address.rsplit_once('@') else {
+        return address.to_string();
+    };
+    let tt = timestamp(today);
+    let hhhh = hash(secret, &[&tt, orig_domain, local]);
+    format!("SRS0={hhhh}={tt}={orig_domain}={local}@{domain}")
+}
+
+// Splits an SRS0 address into hash, timestamp, original domain and local part.
+fn split(address: &str) -> Option<(&str, &str, &str, &str)> {
+    let (local, _) = address.rsplit_once('@')?;
+    let prefix = local.get(..5)?;
+    if !prefix.eq_ignore_ascii_case("SRS0=") && !prefix.eq_ignore_ascii_case("SRS0-") {
+        return None;
+    }
+    let mut fields = local[5..].splitn(4, '=');
+    let hhhh = fields.next()?;
+    let tt = fields.next()?;
+    let domain = fields.next()?;
+    let user = fields.next()?;
+    (tt.len() == 2 && !domain.is_empty()).then_some((hhhh, tt, domain, user))
+}
+
+/// Returns the original address of an SRS0 address, without verifying hash and timestamp.
+pub fn parse(address: &str) -> Option<String> {
+    let (_, _, domain, user) = split(address)?;
+    Some(format!("{user}@{domain}"))
+}
+
+/// Verifies an SRS0 address created with `secret` and returns the original address.
+///
+/// Returns `None` if the hash does not match or the address is older than `max_age_days`.
+pub fn decode(address: &str, secret: &[u8], today: u32, max_age_days: u32) -> Option<String> {
+    let (hhhh, tt, domain, user) = split(address)?;
+    let age = (today % 1024 + 1024 - timestamp_value(tt)?) % 1024;
+    if age > max_age_days || !hhhh.eq_ignore_ascii_case(&hash(secret, &[tt, domain, user])) {
+        return None;
+    }
+    Some(format!("{user}@{domain}"))
+}
+
+#[test]
+fn test_srs() {
+    let secret = b"secret";
+    let srs = encode("Alice@example.com", secret, "fwd.example", 20368);
+    assert!(srs.starts_with("SRS0="));
+    assert!(srs.ends_with("=example.com=Alice@fwd.example"));
+    assert_eq!(encode(&srs, secret, "other.example", 20368), srs);
+    assert_eq!(parse(&srs).as_deref(), Some("Alice@example.com"));
+    assert_eq!(
+        decode(&srs, secret, 20389, 21).as_deref(),
+        Some("Alice@example.com")
+    );
+    assert_eq!(decode(&srs, secret, 20390, 21), None); // too old
+    assert_eq!(decode(&srs, b"other", 20368, 21), None);
+    assert_eq!(
+        decode(&srs.to_lowercase(), secret, 20368, 21).as_deref(),
+        Some("alice@example.com")
+    );
+    assert_eq!(parse("alice@example.com"), None);
+    assert_eq!(timestamp_value(&timestamp(20368)), Some(20368 % 1024));
+}
@@ -0,0 +1,155 @@
+//! Helpers for testing classifiers with `cargo test`.
+//!
+//! A policy repository can keep sample messages next to its classifier and check the
+//! verdicts with the [`classify_test!`](crate::classify_test) macro:
+//!
+//! ```ignore
+//! use srmilter::{EmailClassifier, classify_test};
+//! use srmilter::testing::TestMail;
+//!
+//! #[test]
+//! fn test_policy() {
+//!     let classifier = EmailClassifier::builder(()).classify_fn(classify).build();
+//!     classify_test!(classifier, "tests/newsletter.eml", Accept);
+//!     classify_test!(classifier, "tests/phish.eml", Quarantine, "banned subject");
+//!     classify_test!(
+//!         classifier,
+//!         TestMail::from_file("tests/phish.eml").recipient("ceo@example.com"),
+//!         Reject,
+//!         "ceo fraud"
+//!     );
+//! }
+//! ```
+
+use crate::{ClassifyEmail, ClassifyResult, MailInfo, MailInfoStorage};
+use mail_parser::MessageParser;
+use std::path::Path;
+
+/// A message with envelope data, to be classified in a test.
+pub struct TestMail {
+    name: String,
+    storage: MailInfoStorage,
+}
+
+impl TestMail {
+    /// Creates a test message from an `.eml` file.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file can not be read.
+    pub fn from_file(filename: impl AsRef<Path>) -> Self {
+        let filename = filename.as_ref();
+        let data =
+            std::fs::read(filename).unwrap_or_else(|e| panic!("{}: {e}", filename.display()));
+        let mut mail = Self::from_bytes(data);
+        mail.name = filename.display().to_string();
+        mail
+    }
+    /// Creates a test message from raw message data.
+    pub fn from_bytes(data: impl Into<Vec<u8>>) -> Self {
+        TestMail {
+            name: "test".to_string(),
+            storage: MailInfoStorage {
+                mail_buffer: data.into(),
+                id: "test".to_string(),
+                ..Default::default()
+            },
+        }
+    }
+    /// Sets the envelope sender.
+    pub fn sender(mut self, sender: &str) -> Self {
+        self.storage.sender = sender.to_string();
+        self
+    }
+    /// Adds an envelope recipient.
+    pub fn recipient(mut self, recipient: &str) -> Self {
+        self.storage.recipients.push(recipient.to_string());
+        self.storage.recipient_parameters.push(Vec::new());
+        self
+    }
+    /// Sets a milter macro, e.g. `macro_value("{client_addr}", "192.0.2.1")`.
+    pub fn macro_value(mut self, name: &str, value: &str) -> Self {
+        self.storage
+            .macros
+            .insert(name.to_string(), value.to_string());
+        self
+    }
+    /// Returns the file name or `"test"`, used in assertion messages.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Runs `classifier` on the message and returns the result and the reason given to
+    /// [`accept`](MailInfo::accept), [`quarantine`](MailInfo::quarantine) or
+    /// [`reject`](MailInfo::reject).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the message can not be parsed.
+    pub fn classify(&self, classifier: &impl ClassifyEmail) -> (ClassifyResult, String) {
+        let msg = MessageParser::default()
+            .parse(&self.storage.mail_buffer)
+            .unwrap_or_else(|| panic!("{}: failed to parse message", self.name));
+        let mail_info = MailInfo::new(&self.storage, msg);
+        let result = classifier.classify(&mail_info);
+        (result, mail_info.into_verdict(result).reason)
+    }
+}
+
+impl From<&str> for TestMail {
+    fn from(filename: &str) -> Self {
+        TestMail::from_file(filename)
+    }
+}
+
+/// Classifies a message and asserts the result and, optionally, a substring of the reason.
+///
+/// The message is a [`TestMail`] or the file name of an `.eml` file. See
+/// [`testing`](crate::testing) for an example.
+#[macro_export]
+macro_rules! classify_test {
+    ($classifier:expr, $mail:expr, $expected:ident) => {
+        $crate::classify_test!($classifier, $mail, $expected, "")
+    };
+    ($classifier:expr, $mail:expr, $expected:ident, $reason:expr) => {{
+        let mail: $crate::testing::TestMail = $mail.into();
+        let (result, reason) = mail.classify(&$classifier);
+        assert_eq!(
+            result,
+            $crate::ClassifyResult::$expected,
+            "{}: unexpected result ({})",
+            mail.name(),
+            reason
+        );
+        assert!(
+            reason.contains($reason),
+            "{}: reason {:?} does not contain {:?}",
+            mail.name(),
+            reason,
+            $reason
+        );
+    }};
+}
+
+#[test]
+fn test_classify_test() {
+    use crate::EmailClassifier;
+
+    fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+        if mail_info.get_only_recipient() == "ceo@example.com" {
+            return mail_info.reject("ceo fraud");
+        }
+        if mail_info.get_spam_score() > 3.0 {
+            return mail_info.quarantine("spam score");
+        }
+        mail_info.accept("default")
+    }
+    let classifier = EmailClassifier::builder(()).classify_fn(classify).build();
+    classify_test!(classifier, "tests/parse_001.eml", Accept, "default");
+    classify_test!(classifier, "tests/parse_003.eml", Quarantine);
+    classify_test!(
+        classifier,
+        TestMail::from_file("tests/parse_001.eml").recipient("ceo@example.com"),
+        Reject,
+        "fraud"
+    );
+}
@@ -0,0 +1,249 @@
+//! Session traces of failed milter connections (`daemon --trace-dir`).
+//!
+//! The bytes received on a connection are recorded (up to [`TRACE_MAX`]). If the
+//! connection fails with a protocol error or the classifier panics, the recorded packets
+//! are decoded and written to a file in the trace directory, so that errors like "received
+//! line to long" can be diagnosed after the fact. Body chunks and header values are
+//! redacted, envelope and macros are kept.
+//!
+//! The oldest traces are deleted when the traces in the directory exceed
+//! [`TRACE_DIR_MAX_BYTES`] or [`TRACE_DIR_MAX_FILES`], so that a client sending garbage
+//! can not fill the file system.
+
+use std::io::{self, BufRead, Read, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of received bytes recorded per connection.
+pub(crate) const TRACE_MAX: usize = 1 << 20;
+/// Total size of the traces kept in the trace directory.
+pub(crate) const TRACE_DIR_MAX_BYTES: u64 = 64 << 20;
+/// Number of traces kept in the trace directory.
+pub(crate) const TRACE_DIR_MAX_FILES: usize = 1000;
+
+static TRACE_SEQ: AtomicU32 = AtomicU32::new(0);
+
+/// A reader which records the bytes read through it.
+pub(crate) struct TraceReader<R> {
+    inner: R,
+    recorded: Vec<u8>,
+    total: usize,
+}
+
+fn record(recorded: &mut Vec<u8>, total: &mut usize, data: &[u8]) {
+    let room = TRACE_MAX.saturating_sub(recorded.len());
+    recorded.extend_from_slice(&data[..data.len().min(room)]);
+    *total += data.len();
+}
+
+impl<R: BufRead> TraceReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        TraceReader {
+            inner,
+            recorded: Vec::new(),
+            total: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Read for TraceReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        record(&mut self.recorded, &mut self.total, &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for TraceReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        // the buffer is still filled, so this does not read
+        if let Ok(buf) = self.inner.fill_buf() {
+            record(
+                &mut self.recorded,
+                &mut self.total,
+                &buf[..amt.min(buf.len())],
+            );
+        }
+        self.inner.consume(amt);
+    }
+}
+
+// Decodes the recorded packets, one line per packet.
+fn decode(recorded: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    let mut pos = 0;
+    let mut last_cmd = None;
+    while pos < recorded.len() {
+        let Some(len) = recorded.get(pos..pos + 4) else {
+            writeln!(out, "incomplete packet length")?;
+            break;
+        };
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        pos += 4;
+        let available = recorded.len() - pos;
+        if len == 0 || len > available {
+            let cmd = recorded.get(pos).map_or('-', |&c| c as char);
+            writeln!(
+                out,
+                "incomplete packet: command '{cmd}', {len} bytes announced, {available} received"
+            )?;
+            break;
+        }
+        let (cmd, payload) = (recorded[pos], &recorded[pos + 1..pos + len]);
+        pos += len;
+        last_cmd = Some(cmd as char);
+        let shown = match cmd {
+            b'B' => format!("[{} bytes redacted]", payload.len()),
+            b'L' => {
+                let name = payload.split(|&c| c == 0).next().unwrap_or_default();
+                format!("{}: [redacted]", name.escape_ascii())
+            }
+            _ => payload.escape_ascii().to_string(),
+        };
+        writeln!(out, "'{}' {len} {shown}", cmd as char)?;
+    }
+    if let Some(cmd) = last_cmd {
+        writeln!(out, "last complete command: '{cmd}'")?;
+    }
+    Ok(())
+}
+
+// Deletes the oldest traces in `dir` until at most `max_files` traces with at most
+// `max_bytes` in total are left. Other files are not touched.
+fn prune(dir: &Path, max_bytes: u64, max_files: usize) -> io::Result<()> {
+    let mut traces = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !(name.starts_with("srmilter-") && name.ends_with(".trace")) {
+            continue;
+        }
+        // another process may have deleted it in the meantime
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        traces.push((modified, metadata.len(), entry.path()));
+    }
+    // newest first
+    traces.sort_by_key(|(modified, _, _)| std::cmp::Reverse(*modified));
+    let mut total = 0;
+    for (i, (_, len, path)) in traces.iter().enumerate() {
+        total += len;
+        if i >= max_files || total > max_bytes {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the trace of a failed connection to a new file in `dir` and returns its path.
+///
+/// Older traces are deleted to keep the directory within [`TRACE_DIR_MAX_BYTES`] and
+/// [`TRACE_DIR_MAX_FILES`].
+pub(crate) fn write_trace<R>(
+    dir: &Path,
+    error: &str,
+    reader: &TraceReader<R>,
+) -> io::Result<PathBuf> {
+    let mut out = Vec::new();
+    writeln!(out, "error: {error}")?;
+    writeln!(out, "received: {} bytes", reader.total)?;
+    if reader.total > reader.recorded.len() {
+        writeln!(out, "recorded: first {} bytes", reader.recorded.len())?;
+    }
+    decode(&reader.recorded, &mut out)?;
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let path = dir.join(format!(
+        "srmilter-{secs}-{}-{}.trace",
+        std::process::id(),
+        TRACE_SEQ.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, out)?;
+    if let Err(e) = prune(dir, TRACE_DIR_MAX_BYTES, TRACE_DIR_MAX_FILES) {
+        eprintln!("{}: pruning traces: {e}", dir.display());
+    }
+    Ok(path)
+}
+
+#[test]
+fn test_trace() {
+    use crate::milter::PacketBuffer;
+
+    let mut packets = PacketBuffer::default();
+    packets.push(b'M', b"<a@example.com>\0");
+    packets.push(b'L', b"Subject\0secret\0");
+    packets.push(b'B', b"secret body");
+    let mut input = Vec::new();
+    packets.send(&mut input).unwrap();
+    input.extend_from_slice(&100000u32.to_be_bytes());
+    input.push(b'B');
+    let mut reader = TraceReader::new(&input[..]);
+    let mut buffer = Vec::new();
+    assert!(reader.fill_buf().unwrap().len() > 4);
+    reader.consume(4);
+    reader.read_to_end(&mut buffer).unwrap();
+    assert_eq!(reader.recorded, input);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_trace(dir.path(), "received line to long", &reader).unwrap();
+    let trace = std::fs::read_to_string(path).unwrap();
+    assert_eq!(
+        trace,
+        "error: received line to long\n\
+         received: 62 bytes\n\
+         'M' 17 <a@example.com>\\x00\n\
+         'L' 16 Subject: [redacted]\n\
+         'B' 12 [11 bytes redacted]\n\
+         incomplete packet: command 'B', 100000 bytes announced, 1 received\n\
+         last complete command: 'B'\n"
+    );
+}
+
+#[test]
+fn test_prune() {
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let now = SystemTime::now();
+    for (i, name) in [
+        "srmilter-1.trace",
+        "srmilter-2.trace",
+        "srmilter-3.trace",
+        "other",
+    ]
+    .iter()
+    .enumerate()
+    {
+        let path = dir.path().join(name);
+        std::fs::write(&path, [b'x'; 10]).unwrap();
+        let modified = now - Duration::from_secs(100 - i as u64);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+    let exists = |name: &str| dir.path().join(name).exists();
+    prune(dir.path(), 100, 10).unwrap();
+    assert!(exists("srmilter-1.trace"));
+    // by size, oldest first
+    prune(dir.path(), 25, 10).unwrap();
+    assert!(!exists("srmilter-1.trace"));
+    assert!(exists("srmilter-2.trace"));
+    // by number
+    prune(dir.path(), 100, 1).unwrap();
+    assert!(!exists("srmilter-2.trace"));
+    assert!(exists("srmilter-3.trace"));
+    assert!(exists("other"));
+}
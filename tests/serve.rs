@@ -0,0 +1,68 @@
+#![cfg(feature = "daemon")]
+
+use srmilter::cli::{Concurrency, DaemonArgs};
+use srmilter::{ClassifyResult, Config, EmailClassifier, MailInfo, serve};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+fn classify(_ctx: &(), mail_info: &MailInfo) -> ClassifyResult {
+    mail_info.reject("test")
+}
+
+fn send(stream: &mut TcpStream, cmd: u8, data: &[u8]) {
+    stream
+        .write_all(&(data.len() as u32 + 1).to_be_bytes())
+        .unwrap();
+    stream.write_all(&[cmd]).unwrap();
+    stream.write_all(data).unwrap();
+}
+
+fn receive(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut packet = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut packet).unwrap();
+    (packet[0], packet[1..].to_vec())
+}
+
+#[test]
+fn test_serve() {
+    let config = Config::builder()
+        .email_classifier(EmailClassifier::builder(()).classify_fn(classify).build())
+        .build();
+    // fork mode is not enabled
+    let args = DaemonArgs::builder().fork(2).build();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    assert!(serve(listener, &config, &args, Arc::default()).is_err());
+    // the service can be started and stopped repeatedly
+    for concurrency in [Concurrency::Single, Concurrency::Threads(2)] {
+        let args = DaemonArgs::builder().concurrency(concurrency).build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = thread::spawn({
+            let config = config.clone();
+            let shutdown = shutdown.clone();
+            move || serve(listener, &config, &args, shutdown).map_err(|e| e.to_string())
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        send(&mut stream, b'O', &[0u8; 12]);
+        assert_eq!(receive(&mut stream).0, b'O');
+        send(&mut stream, b'M', b"<sender@example.net>\0");
+        send(&mut stream, b'R', b"<user@example.org>\0");
+        send(&mut stream, b'L', b"Subject\0test\0");
+        send(&mut stream, b'N', b"");
+        send(&mut stream, b'B', b"body\r\n");
+        send(&mut stream, b'E', b"");
+        assert_eq!(receive(&mut stream).0, b'r');
+        send(&mut stream, b'Q', b"");
+        drop(stream);
+
+        shutdown.store(true, Ordering::Relaxed);
+        server.join().unwrap().unwrap();
+    }
+}